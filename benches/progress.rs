@@ -0,0 +1,214 @@
+// Cost of reporting the progress of parts: a channel message per read, as parts used
+// to do, against a counter per part sampled by the observer. Each is measured with a
+// bare observer and with one that updates a bar per part, as ProgressManager does.
+//
+// cargo bench --bench progress
+
+use std::{
+    hint::black_box,
+    io,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use fget::progress::Counter;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle, TermLike};
+
+const PARTS: usize = 4;
+const PART_LEN: u64 = 1 << 30;
+const READ_SIZE: u64 = 8 * 1024;
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(50);
+
+/// cpu time (user + system) of the process so far
+#[cfg(unix)]
+fn cpu_time() -> Duration {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) };
+    let tv = |tv: libc::timeval| {
+        Duration::from_secs(tv.tv_sec as u64) + Duration::from_micros(tv.tv_usec as u64)
+    };
+    tv(usage.ru_utime) + tv(usage.ru_stime)
+}
+
+#[cfg(not(unix))]
+fn cpu_time() -> Duration {
+    Duration::ZERO
+}
+
+/// terminal that renders nothing, so the bars cost what they cost without a tty
+#[derive(Debug)]
+struct Sink;
+
+impl TermLike for Sink {
+    fn width(&self) -> u16 {
+        120
+    }
+
+    fn move_cursor_up(&self, _: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_down(&self, _: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_right(&self, _: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn move_cursor_left(&self, _: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_line(&self, s: &str) -> io::Result<()> {
+        black_box(s);
+        Ok(())
+    }
+
+    fn write_str(&self, s: &str) -> io::Result<()> {
+        black_box(s);
+        Ok(())
+    }
+
+    fn clear_line(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// a bar per part with the style of ProgressManager, drawn to a Sink
+struct Bars {
+    _m: MultiProgress,
+    pbs: Vec<ProgressBar>,
+}
+
+impl Bars {
+    fn new() -> Self {
+        let m = MultiProgress::with_draw_target(ProgressDrawTarget::term_like(Box::new(Sink)));
+        let style = ProgressStyle::with_template(concat!(
+            "{spinner:.green} ",
+            "[{elapsed_precise}] ",
+            "[{wide_bar:.cyan/blue}] ",
+            "{bytes}/{total_bytes} ",
+            "({binary_bytes_per_sec:^12} ",
+            "{eta:>3}) ",
+            "{msg}"
+        ))
+        .unwrap()
+        .progress_chars("#>-");
+        let pbs = (0..PARTS)
+            .map(|idx| {
+                let pb = m.insert(idx, ProgressBar::new(PART_LEN));
+                pb.set_style(style.clone());
+                pb.set_message(format!("connection {}", idx));
+                pb
+            })
+            .collect();
+        Self { _m: m, pbs }
+    }
+
+    /// what ProgressManager::on_progress does
+    fn on_progress(&self, idx: usize, pos: u64) {
+        self.pbs[idx].set_position(pos);
+    }
+}
+
+/// every read of every part sends its position to the observer thread
+fn channel_per_read(mut on_progress: impl FnMut(usize, u64)) -> u64 {
+    let (sender, recv) = mpsc::channel();
+    let parts: Vec<_> = (0..PARTS)
+        .map(|idx| {
+            let sender = sender.clone();
+            thread::spawn(move || {
+                let mut pos = 0;
+                while pos < PART_LEN {
+                    pos += READ_SIZE;
+                    sender.send((idx, pos)).unwrap();
+                }
+            })
+        })
+        .collect();
+    drop(sender);
+
+    let mut progress = [0u64; PARTS];
+    for (idx, pos) in recv {
+        progress[idx] = pos;
+        on_progress(idx, pos);
+    }
+    parts.into_iter().for_each(|part| part.join().unwrap());
+    progress.iter().sum()
+}
+
+/// every read of every part updates its counter, the observer samples them
+fn sampled_counters(mut on_progress: impl FnMut(usize, u64)) -> u64 {
+    let counters: Vec<Counter> = (0..PARTS).map(|_| Counter::new()).collect();
+    let parts: Vec<_> = counters
+        .iter()
+        .map(|counter| {
+            let counter = counter.clone();
+            thread::spawn(move || {
+                let mut pos = 0;
+                while pos < PART_LEN {
+                    pos += READ_SIZE;
+                    counter.set(pos);
+                }
+            })
+        })
+        .collect();
+
+    let mut progress = [0u64; PARTS];
+    while parts.iter().any(|part| !part.is_finished()) {
+        thread::sleep(SAMPLE_INTERVAL);
+        for (idx, counter) in counters.iter().enumerate() {
+            let pos = counter.get();
+            if pos != progress[idx] {
+                progress[idx] = pos;
+                on_progress(idx, pos);
+            }
+        }
+    }
+    parts.into_iter().for_each(|part| part.join().unwrap());
+    counters.iter().map(Counter::get).sum()
+}
+
+fn bench(name: &str, f: impl FnOnce() -> u64) {
+    let (wall, cpu) = (Instant::now(), cpu_time());
+    assert_eq!(PARTS as u64 * PART_LEN, f());
+    let (wall, cpu) = (wall.elapsed(), cpu_time() - cpu);
+
+    let reads = PARTS as u64 * PART_LEN / READ_SIZE;
+    println!(
+        "{:<18} {} reads: {:>8.1?} wall, {:>8.1?} cpu, {:>6.1} ns cpu per read",
+        name,
+        reads,
+        wall,
+        cpu,
+        cpu.as_nanos() as f64 / reads as f64
+    );
+}
+
+fn main() {
+    bench("channel per read", || {
+        channel_per_read(|_, pos| {
+            black_box(pos);
+        })
+    });
+    bench("sampled counters", || {
+        sampled_counters(|_, pos| {
+            black_box(pos);
+        })
+    });
+
+    let bars = Bars::new();
+    bench("bars per read", || {
+        channel_per_read(|idx, pos| bars.on_progress(idx, pos))
+    });
+    let bars = Bars::new();
+    bench("bars sampled", || {
+        sampled_counters(|idx, pos| bars.on_progress(idx, pos))
+    });
+}
@@ -0,0 +1,11 @@
+#![no_main]
+
+use fget::httpparse::{parse_response_head, try_parse_response_head};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = parse_response_head(data);
+    if let Ok(Some((_, len))) = try_parse_response_head(data) {
+        assert!(len <= data.len());
+    }
+});
@@ -0,0 +1,172 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Mutex, OnceLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::urlinfo::UrlInfo;
+
+/// freshness of an alternative whose Alt-Svc entry has no ma parameter (RFC 7838)
+const DEFAULT_MAX_AGE: u64 = 24 * 3600;
+
+static STORE: OnceLock<Mutex<AltSvcStore>> = OnceLock::new();
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Alternative {
+    authority: String, // host:port
+    expires: u64,      // unix timestamp in seconds
+}
+
+/// HTTP/3 alternatives of https origins (RFC 7838) learnt from Alt-Svc headers of earlier
+/// responses, kept in memory for the duration of the run
+#[derive(Debug, Default)]
+struct AltSvcStore {
+    origins: HashMap<String, Alternative>, // keyed by host:port of origin
+    broken: HashSet<String>,               // authorities HTTP/3 failed for, not tried again
+}
+
+/// Record Alt-Svc header received from given origin (host:port) over https
+pub fn record(origin: &str, header: &str) {
+    if let Ok(mut store) = STORE.get_or_init(Default::default).lock() {
+        store.update(origin, header, now());
+    }
+}
+
+/// Authority (host:port) to reach given https origin over HTTP/3: its advertised
+/// alternative if any, otherwise the origin itself. None if HTTP/3 failed there before.
+pub fn h3_authority(origin: &UrlInfo) -> Option<String> {
+    match STORE.get_or_init(Default::default).lock() {
+        Ok(store) => store.lookup(origin, now()),
+        Err(_) => Some(origin.host_addr()),
+    }
+}
+
+/// HTTP/3 failed at given authority (host:port), origins it serves are reached over tcp
+/// from now on, unless they advertise another alternative
+pub fn mark_broken(authority: &str) {
+    if let Ok(mut store) = STORE.get_or_init(Default::default).lock() {
+        store.broken.insert(authority.to_string());
+    }
+}
+
+impl AltSvcStore {
+    fn update(&mut self, origin: &str, header: &str, now: u64) {
+        // a new header replaces all alternatives of the origin, `clear` included
+        match parse_alt_svc_header(header) {
+            Some((authority, max_age)) => {
+                let authority = match authority.strip_prefix(':') {
+                    Some(port) => {
+                        let host = origin.rsplit_once(':').map(|(host, _)| host);
+                        format!("{}:{}", host.unwrap_or(origin), port)
+                    }
+                    None => authority,
+                };
+                let expires = now.saturating_add(max_age);
+                self.origins
+                    .insert(origin.to_string(), Alternative { authority, expires });
+            }
+            None => {
+                self.origins.remove(origin);
+            }
+        }
+    }
+
+    fn lookup(&self, origin: &UrlInfo, now: u64) -> Option<String> {
+        let key = origin.host_addr();
+        let authority = match self.origins.get(&key) {
+            Some(alt) if alt.expires > now => alt.authority.clone(),
+            _ => key,
+        };
+
+        Some(authority).filter(|authority| !self.broken.contains(authority))
+    }
+}
+
+/// parse Alt-Svc header value into (alt-authority, ma) of its first h3 alternative,
+/// returns None if there is none (e.g. `clear`)
+fn parse_alt_svc_header(header: &str) -> Option<(String, u64)> {
+    for entry in header.split(',') {
+        let mut params = entry.split(';').map(str::trim);
+        let (proto, authority) = match params.next()?.split_once('=') {
+            Some((proto, authority)) => (proto.trim(), authority.trim().trim_matches('"')),
+            None => continue,
+        };
+        if proto != "h3" || authority.is_empty() {
+            continue;
+        }
+
+        let mut max_age = DEFAULT_MAX_AGE;
+        for param in params {
+            if let Some((name, val)) = param.split_once('=') {
+                if name.trim().eq_ignore_ascii_case("ma") {
+                    max_age = val.trim().trim_matches('"').parse().ok()?;
+                }
+            }
+        }
+
+        return Some((authority.to_string(), max_age));
+    }
+
+    None
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_alt_svc_header() {
+        assert_eq!(
+            Some((":443".to_string(), 3600)),
+            parse_alt_svc_header("h3=\":443\"; ma=3600, h3-29=\":443\"; ma=3600")
+        );
+        assert_eq!(
+            Some(("alt.example.com:8443".to_string(), DEFAULT_MAX_AGE)),
+            parse_alt_svc_header("h2=\":443\", h3=\"alt.example.com:8443\"; persist=1")
+        );
+        assert_eq!(None, parse_alt_svc_header("h3-29=\":443\""));
+        assert_eq!(None, parse_alt_svc_header("clear"));
+    }
+
+    #[test]
+    fn test_store() {
+        let origin = UrlInfo::parse("https://example.com/file").unwrap();
+        let mut store = AltSvcStore::default();
+        assert_eq!(
+            Some("example.com:443".to_string()),
+            store.lookup(&origin, 1000)
+        );
+
+        store.update("example.com:443", "h3=\":8443\"; ma=100", 1000);
+        assert_eq!(
+            Some("example.com:8443".to_string()),
+            store.lookup(&origin, 1050)
+        );
+        assert_eq!(
+            Some("example.com:443".to_string()),
+            store.lookup(&origin, 1100)
+        ); // expired
+
+        store.update("example.com:443", "h3=\"alt.example.com:443\"", 1000);
+        assert_eq!(
+            Some("alt.example.com:443".to_string()),
+            store.lookup(&origin, 1050)
+        );
+
+        store.broken.insert("alt.example.com:443".to_string());
+        assert_eq!(None, store.lookup(&origin, 1050));
+
+        store.update("example.com:443", "clear", 1000);
+        assert_eq!(
+            Some("example.com:443".to_string()),
+            store.lookup(&origin, 1050)
+        );
+    }
+}
@@ -1,38 +1,498 @@
 use crate::{
-    httpx::{resolve_addr, HttpClient, HttpResponse, RedirectPolicy},
+    engine::{Engine, Handler, Job},
+    hsts,
+    httpx::{
+        resolve_addr, ConnAddr, HttpClient, HttpClientBuilder, HttpError, HttpHeaders,
+        HttpResponse, RedirectPolicy, TcpTransport, Timings, Transport, UnixTransport,
+    },
+    ratelimit::{self, RateLimit},
+    reorder::ReorderBuffer,
+    timing::{self, ConnStats},
     urlinfo::UrlInfo,
     Config,
 };
-use fget::{make_error, map, PError};
-use http::header;
+use fget::{
+    httpparse::{self, ChunkedReader, ResponseHead},
+    make_error, map,
+    progress::Counter,
+    status, statusln, NumThreads, PError,
+};
+use http::{header, StatusCode};
 
 use std::{
     cmp,
-    fs::{self, File},
-    io::{BufWriter, Read, Write},
-    sync::mpsc::{self, Sender},
-    thread,
+    error::Error,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Read, Write},
+    path::Path,
+    process,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
+/// interval between two samples of the progress of parts reported to the observer
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(50);
+/// maximum number of times a part is retried because server throttles requests
+const MAX_THROTTLED_RETRIES: u8 = 5;
+/// longest delay asked by server (Retry-After) to wait for, a part asked to wait longer
+/// fails
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+/// delay before retrying a throttled part if server does not tell, doubled on each retry
+const THROTTLE_BACKOFF: Duration = Duration::from_secs(1);
+/// number of times a download starts over because the file changed on server
+const MAX_RESTARTS: u8 = 2;
+/// window over which the throughput of the last part is measured for --hedge-speed
+const HEDGE_WINDOW: Duration = Duration::from_secs(1);
+/// window over which the aggregate throughput is measured for --num-threads auto
+const TUNE_WINDOW: Duration = Duration::from_secs(2);
+/// end of the only part of a file of unknown length, downloaded until the end of the body
+const UNKNOWN_END: u64 = u64::MAX - 1;
+/// data of parts received ahead of the one written to stdout kept in memory, the rest is
+/// spilled to disk
+const REORDER_MEMORY: usize = 64 * 1024 * 1024;
+
+/// Observer of the progress of a download, parts are reported by the index of the worker
+/// (connection slot) downloading them, a worker downloads a part after another
 pub trait DownloadObserver {
     fn on_init(&mut self, len: usize);
-    fn on_download_start(&mut self, idx: u8, len: u64);
-    fn on_progress(&mut self, idx: u8, pos: u64);
-    fn on_download_end(&mut self, idx: u8);
+    /// a part of len bytes starts, None if the length of the file is unknown
+    fn on_download_start(&mut self, idx: usize, len: Option<u64>, src: &ConnAddr);
+    fn on_progress(&mut self, idx: usize, pos: u64);
+    fn on_throttled(&mut self, idx: usize, wait: Duration);
+    /// part is shortened to len bytes, its end is taken over by a new part
+    fn on_split(&mut self, idx: usize, len: u64);
+    fn on_download_end(&mut self, idx: usize);
 }
 
 struct DownloadInfo {
     range_supported: bool,
     content_type: String,
-    len: u64,
+    len: Option<u64>, // None if unknown (no Content-Length): the body is read until its end
+    validators: Validators,
+}
+
+/// Validators (RFC 9110) of the version of the file being downloaded, every part must
+/// come from this version
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Validators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl Validators {
+    fn new(etag: Option<&str>, last_modified: Option<&str>) -> Self {
+        Self {
+            etag: etag.map(|etag| etag.trim().to_string()),
+            last_modified: last_modified.map(|date| date.trim().to_string()),
+        }
+    }
+
+    /// If-Range value asking for the range only if the file is still this version: the
+    /// ETag unless weak (which If-Range does not allow), otherwise the modification date
+    fn if_range(&self) -> Option<&str> {
+        match &self.etag {
+            Some(etag) if !etag.starts_with("W/") => Some(etag),
+            _ => self.last_modified.as_deref(),
+        }
+    }
+
+    /// whether a response with given validators is another version of the file
+    fn changed(&self, other: &Validators) -> bool {
+        // weak comparison, e.g. compression may turn a strong ETag into a weak one
+        let weak = |etag: &String| etag.trim_start_matches("W/").to_string();
+        match (&self.etag, &other.etag) {
+            (Some(etag), Some(other)) => weak(etag) != weak(other),
+            _ => matches!(
+                (&self.last_modified, &other.last_modified),
+                (Some(date), Some(other)) if date != other
+            ),
+        }
+    }
+}
+
+/// A part of the file, with its inclusive byte range
+#[derive(Debug, Clone)]
+struct Part {
+    idx: usize,
+    start: u64,
+    remaining: Arc<Mutex<Remaining>>,
+    downloaded: Counter, // bytes written by the current request of the part
+}
+
+/// Range of a part not written yet, shared by the handler downloading the part, which
+/// advances pos, and the download loop, which may move end back to split the part
+#[derive(Debug)]
+struct Remaining {
+    pos: u64,
+    end: u64, // inclusive
+}
+
+impl Part {
+    fn new(idx: usize, start: u64, end: u64) -> Self {
+        let remaining = Remaining { pos: start, end };
+        Self {
+            idx,
+            start,
+            remaining: Arc::new(Mutex::new(remaining)),
+            downloaded: Counter::new(),
+        }
+    }
+
+    /// number of bytes of the part not written yet
+    fn remaining(&self) -> u64 {
+        let remaining = self.remaining.lock().unwrap();
+        (remaining.end + 1).saturating_sub(remaining.pos)
+    }
+
+    /// new part with given index for the remaining range, None if there is none
+    fn rest(&self, idx: usize) -> Option<Part> {
+        let remaining = self.remaining.lock().unwrap();
+        (remaining.pos <= remaining.end).then(|| Part::new(idx, remaining.pos, remaining.end))
+    }
+
+    /// hand the second half of the remaining range over to a new part with given index,
+    /// if both halves are at least min_len long (a shorter range is not worth a request)
+    fn split(&self, idx: usize, min_len: u64) -> Option<Part> {
+        let mut remaining = self.remaining.lock().unwrap();
+        let len = (remaining.end + 1).saturating_sub(remaining.pos);
+        if len < 2 * min_len {
+            return None;
+        }
+
+        let start = remaining.pos + len / 2;
+        let part = Part::new(idx, start, remaining.end);
+        remaining.end = start - 1;
+        Some(part)
+    }
 }
 
+/// Status of a part, identified by its index
 #[derive(Debug)]
 enum DownloadStatus {
-    Started(u8, u64),
-    Progress(u8, u64),
-    Failed(u8, String),
-    Done(u8, String),
+    Started(usize, Option<u64>, ConnAddr),
+    Failed(usize, String, Option<HttpError>), // with the error response, if any
+    Throttled(usize, HttpError, Option<Duration>), // server asked to retry later
+    Rejected(usize, PartError),               // response can not be written as part
+    Done(usize, String, ConnStats),
+}
+
+impl DownloadStatus {
+    fn idx(&self) -> usize {
+        match self {
+            DownloadStatus::Started(idx, ..)
+            | DownloadStatus::Failed(idx, ..)
+            | DownloadStatus::Throttled(idx, ..)
+            | DownloadStatus::Rejected(idx, _)
+            | DownloadStatus::Done(idx, ..) => *idx,
+        }
+    }
+}
+
+/// A part response which can not be written as the requested part of the file
+#[derive(Debug)]
+enum PartError {
+    /// not the requested range: server ignored the Range header or sent another range
+    RangeMismatch(String),
+    /// another version of the file, which changed on server since the download started
+    FileChanged(String),
+}
+
+impl fmt::Display for PartError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PartError::RangeMismatch(msg) | PartError::FileChanged(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl Error for PartError {}
+
+/// A part which failed for good, with the error response of the server if any
+#[derive(Debug)]
+struct PartFailed {
+    idx: usize,
+    msg: String,
+    http_err: Option<HttpError>,
+}
+
+impl fmt::Display for PartFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "download failed at part {}: {}", self.idx, self.msg)
+    }
+}
+
+impl Error for PartFailed {}
+
+/// Schedules the requests of parts over a few workers (connection slots): parts are
+/// pulled from a queue by free workers, no more workers than the concurrency limit are
+/// busy at once, and parts throttled by server wait before being retried
+struct Scheduler {
+    limit: usize,                   // maximum number of parts downloaded concurrently
+    active: usize,                  // parts submitted to engine and not ended yet
+    workers: Vec<Option<usize>>,    // part downloaded by every worker
+    pending: Vec<(Instant, usize)>, // (not before, index) of parts to submit
+}
+
+impl Scheduler {
+    fn new(num_parts: usize, num_workers: usize, now: Instant) -> Self {
+        Self {
+            limit: num_workers,
+            active: 0,
+            workers: vec![None; num_workers],
+            pending: (0..num_parts).map(|idx| (now, idx)).collect(),
+        }
+    }
+
+    /// next part to submit now and the worker downloading it, if any, the part is
+    /// counted as active
+    fn next(&mut self, now: Instant) -> Option<(usize, usize)> {
+        if self.active >= self.limit {
+            return None;
+        }
+
+        let (i, _) = self
+            .pending
+            .iter()
+            .enumerate()
+            .filter(|(_, (at, _))| *at <= now)
+            .min_by_key(|(_, entry)| **entry)?;
+        let worker = self.workers.iter().position(Option::is_none)?;
+        let idx = self.pending.remove(i).1;
+        self.workers[worker] = Some(idx);
+        self.active += 1;
+        Some((idx, worker))
+    }
+
+    /// worker downloading given part, if it is active
+    fn worker(&self, idx: usize) -> Option<usize> {
+        self.workers.iter().position(|part| *part == Some(idx))
+    }
+
+    /// part is not active anymore, its worker is free
+    fn release(&mut self, idx: usize) {
+        if let Some(worker) = self.worker(idx) {
+            self.workers[worker] = None;
+            self.active -= 1;
+        }
+    }
+
+    /// add a part to submit as soon as possible
+    fn add(&mut self, idx: usize, now: Instant) {
+        self.pending.push((now, idx));
+    }
+
+    /// whether a part could be submitted now but none is pending
+    fn idle(&self) -> bool {
+        self.active < self.limit && self.pending.is_empty()
+    }
+
+    /// when a pending part may be submitted, if it is waiting for a delay only
+    fn wakeup(&self) -> Option<Instant> {
+        if self.active >= self.limit {
+            return None; // woken up by the end of an active part
+        }
+        self.pending.iter().map(|(at, _)| *at).min()
+    }
+
+    /// change the number of parts downloaded concurrently, active parts beyond it are not
+    /// interrupted but their workers are not reused
+    fn set_limit(&mut self, limit: usize) {
+        self.limit = limit.clamp(1, self.workers.len());
+    }
+
+    /// part is done
+    fn ended(&mut self, idx: usize) {
+        self.release(idx);
+    }
+
+    /// part failed and is retried as soon as possible
+    fn retry(&mut self, idx: usize, now: Instant) {
+        self.release(idx);
+        self.pending.push((now, idx));
+    }
+
+    /// server throttled part, it is retried after given delay and the number of parts
+    /// downloaded concurrently is halved for the rest of the download
+    fn throttled(&mut self, idx: usize, wait: Duration, now: Instant) {
+        self.limit = cmp::max(1, cmp::min(self.limit, self.active) / 2);
+        self.release(idx);
+        self.pending.push((now + wait, idx));
+    }
+}
+
+/// Watches the throughput of the last part left, to hedge it if it is too slow
+struct TailWatch {
+    idx: usize,
+    window_start: Instant,
+    window_pos: u64,
+}
+
+impl TailWatch {
+    fn new(idx: usize, pos: u64, now: Instant) -> Self {
+        Self {
+            idx,
+            window_start: now,
+            window_pos: pos,
+        }
+    }
+
+    /// whether the part was slower than limit (bytes per second) over the last window,
+    /// once the window is over, another one starts then
+    fn slow(&mut self, limit: u64, pos: u64, now: Instant) -> bool {
+        if pos < self.window_pos {
+            // part was retried from its start, it gets a new window
+            *self = Self::new(self.idx, pos, now);
+            return false;
+        }
+
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed < HEDGE_WINDOW {
+            return false;
+        }
+
+        let speed = (pos - self.window_pos) * 1000 / cmp::max(elapsed.as_millis() as u64, 1);
+        self.window_start = now;
+        self.window_pos = pos;
+        speed < limit
+    }
+
+    fn wakeup(&self) -> Instant {
+        self.window_start + HEDGE_WINDOW
+    }
+}
+
+/// Tunes the number of connections of --num-threads auto from the aggregate throughput:
+/// a connection is added every window as long as the throughput improves by a tenth, the
+/// last one added is given up as soon as it does not or a part fails
+struct Tuner {
+    min: usize,
+    max: usize,
+    window_start: Instant,
+    window_pos: u64,
+    best: u64,     // best throughput so far, bytes per second
+    settled: bool, // no connection is added anymore
+}
+
+impl Tuner {
+    fn new(min: usize, max: usize, now: Instant) -> Self {
+        Self {
+            min,
+            max,
+            window_start: now,
+            window_pos: 0,
+            best: 0,
+            settled: false,
+        }
+    }
+
+    /// when the current window is over, if still tuning
+    fn wakeup(&self) -> Option<Instant> {
+        (!self.settled).then_some(self.window_start + TUNE_WINDOW)
+    }
+
+    /// number of connections once given number of bytes is downloaded overall, another
+    /// window starts when the current one is over
+    fn update(&mut self, limit: usize, pos: u64, now: Instant) -> usize {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if self.settled || elapsed < TUNE_WINDOW {
+            return limit;
+        }
+
+        let speed =
+            pos.saturating_sub(self.window_pos) * 1000 / cmp::max(elapsed.as_millis() as u64, 1);
+        self.window_start = now;
+        self.window_pos = pos;
+        if speed == 0 {
+            return limit; // still connecting
+        }
+        if speed > self.best + self.best / 10 {
+            self.best = speed;
+            if limit < self.max {
+                return limit + 1;
+            }
+            self.settled = true;
+            return limit;
+        }
+        self.failed(limit)
+    }
+
+    /// number of connections after a part failed or did not improve the throughput
+    fn failed(&mut self, limit: usize) -> usize {
+        self.settled = true;
+        cmp::max(self.min, limit.saturating_sub(1))
+    }
+}
+
+/// Duplicate request for the rest of the last part, from its position at that time,
+/// racing the slow connection downloading the part: the first one done is kept
+struct Hedge {
+    part: Part,                            // rest of the range, with an index of its own
+    victim: usize,                         // index of the part hedged
+    victim_failed: Option<DownloadStatus>, // handled only if the hedge fails too
+    fpath: Option<String>,                 // file of the hedge, once done
+}
+
+/// detect a stalled transfer, that is a transfer whose throughput stays below
+/// the speed limit during a whole time window
+struct StallDetector {
+    limit: u64, // bytes per second
+    window: Duration,
+    window_start: Instant,
+    window_bytes: u64,
+}
+
+impl StallDetector {
+    fn new(limit: u64, window: Duration, now: Instant) -> Self {
+        Self {
+            limit,
+            window,
+            window_start: now,
+            window_bytes: 0,
+        }
+    }
+
+    fn update(&mut self, n: usize, now: Instant) -> Result<(), PError> {
+        self.window_bytes += n as u64;
+
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed < self.window {
+            return Ok(());
+        }
+
+        let speed = self.window_bytes * 1000 / cmp::max(elapsed.as_millis() as u64, 1);
+        if speed < self.limit {
+            return Err(make_error(
+                format!(
+                    "transfer stalled, speed {} B/s below limit {} B/s for {} seconds",
+                    speed,
+                    self.limit,
+                    elapsed.as_secs()
+                )
+                .as_str(),
+            ));
+        }
+
+        // start a new window
+        self.window_start = now;
+        self.window_bytes = 0;
+
+        Ok(())
+    }
+}
+
+fn check_deadline(deadline: Option<Instant>) -> Result<(), PError> {
+    match deadline {
+        Some(deadline) if Instant::now() >= deadline => {
+            Err(make_error("operation timed out (max time reached)"))
+        }
+        _ => Ok(()),
+    }
 }
 
 /// format byte length in bytes to human readable
@@ -50,257 +510,2342 @@ fn format_byte_length(len: u64) -> String {
     format!("{:.1} {}", value, units[unit_index])
 }
 
-fn build_client(cfg: &Config, urlinfo: &UrlInfo) -> Result<HttpClient, PError> {
-    let mut builder = HttpClient::builder().from_url_info(&urlinfo);
+/// transport to open connections with, a unix socket one if --unix-socket
+fn make_transport(cfg: &Config) -> Arc<dyn Transport> {
+    match &cfg.unix_socket {
+        Some(path) => Arc::new(UnixTransport::new(Path::new(path))),
+        None => Arc::new(TcpTransport),
+    }
+}
+
+/// builder of clients for the nth part, parts are spread evenly (round-robin) over
+/// the local addresses and interfaces given in config
+fn client_builder(
+    cfg: &Config,
+    transport: &Arc<dyn Transport>,
+    urlinfo: &UrlInfo,
+    idx: usize,
+    label: &str,
+) -> HttpClientBuilder {
+    let mut builder = HttpClient::builder()
+        .from_url_info(urlinfo)
+        .with_transport(transport.clone())
+        .with_http2(!cfg.http1_1)
+        .with_label(label)
+        .with_http3(cfg.http3 && cfg.unix_socket.is_none());
+    if let Some(rate) = cfg.limit_conn_rate {
+        builder = builder.with_conn_rate_limit(rate);
+    }
+    if !cfg.bind_address.is_empty() {
+        builder = builder.with_bind_addr(cfg.bind_address[idx % cfg.bind_address.len()]);
+    }
+    if !cfg.interface.is_empty() {
+        builder = builder.with_interface(&cfg.interface[idx % cfg.interface.len()]);
+    }
     if cfg.no_redirect {
         builder = builder.with_redirect_policy(RedirectPolicy::None);
     }
     if cfg.timeout > 0 {
         builder = builder.with_timeout_ms(cfg.timeout as u64 * 1000);
     }
+    if let Some(secs) = cfg.connect_timeout {
+        builder = builder.with_connect_timeout_ms(secs.saturating_mul(1000));
+    }
+    if let Some(secs) = cfg.read_timeout {
+        builder = builder.with_read_timeout_ms(secs.saturating_mul(1000));
+    }
     if let Some(ua) = &cfg.user_agent {
         builder = builder.with_user_agent(ua);
     }
 
-    builder.build()
+    builder
+}
+
+fn build_client(
+    cfg: &Config,
+    transport: &Arc<dyn Transport>,
+    urlinfo: &UrlInfo,
+    idx: usize,
+    label: &str,
+) -> Result<HttpClient, PError> {
+    client_builder(cfg, transport, urlinfo, idx, label).build()
+}
+
+/// --range: fetch the ranges in one request and write them at their offsets into the
+/// output file, which is kept as it is elsewhere, returns the ranges written
+fn download_ranges(
+    cfg: &Config,
+    transport: &Arc<dyn Transport>,
+    urlinfo: &UrlInfo,
+) -> Result<Vec<(u64, u64)>, PError> {
+    let output = cfg.output.as_ref().unwrap_or(&urlinfo.fname);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(output)?;
+    let client = build_client(cfg, transport, urlinfo, 0, "ranges")?;
+
+    client.get_ranges(&urlinfo.path, &cfg.range, &mut file)
 }
 
-fn get_download_info(resp: HttpResponse) -> Result<DownloadInfo, PError> {
-    let mut len = 0u64;
+fn get_download_info(resp: &HttpResponse) -> Result<DownloadInfo, PError> {
+    let mut len = None;
     let mut range_supported = false;
     let mut content_type = String::new();
+    let (mut etag, mut last_modified) = (None, None);
 
     for (key, val) in resp.headers().iter() {
         let val = val.to_str()?;
         match *key {
-            header::CONTENT_LENGTH => len = val.parse::<u64>()?,
+            header::CONTENT_LENGTH => len = Some(val.parse::<u64>()?),
             header::ACCEPT_RANGES => range_supported = val == "bytes",
             header::CONTENT_TYPE => content_type = val.to_string(),
+            header::ETAG => etag = Some(val),
+            header::LAST_MODIFIED => last_modified = Some(val),
             _ => {}
         }
     }
 
+    // answer to a ranged GET of the first byte: the file length is in Content-Range
+    if resp.status() == StatusCode::PARTIAL_CONTENT {
+        let content_range = resp
+            .headers()
+            .get(header::CONTENT_RANGE)
+            .ok_or_else(|| make_error("partial content without content-range"))?;
+        (_, _, len) = httpparse::parse_content_range(content_range.to_str()?)
+            .ok_or_else(|| make_error("invalid content-range"))?;
+        range_supported = true;
+    }
+
     Ok(DownloadInfo {
-        range_supported,
+        range_supported: range_supported && len.is_some(), // no part of an unknown length
         len,
         content_type,
+        validators: Validators::new(etag, last_modified),
     })
 }
 
-fn download_part(
-    cfg: &Config,
-    urlinfo: &UrlInfo,
-    start: u64,
-    end: u64,
-    idx: u8,
-    sender: &Sender<DownloadStatus>,
-) -> Result<(), PError> {
-    let headers = map!(
-        header::RANGE.to_string() => format!("bytes={}-{}", start, end)
-    );
-    let client = build_client(cfg, urlinfo)?;
-    let resp = client.get_with_headers(&urlinfo.path, &headers)?;
+/// parse `bytes <first>-<last>/<complete length>` content range, the length must be known
+fn parse_content_range(val: &str) -> Option<(u64, u64, u64)> {
+    match httpparse::parse_content_range(val)? {
+        (first, last, Some(len)) => Some((first, last, len)),
+        _ => None,
+    }
+}
 
-    let mut r = resp.into_body();
-    let mut buf = [0u8; 8192];
-    let mut pos = start;
+/// Result of probing the file, with a HEAD request or, if it failed or was inconclusive,
+/// with a GET of its first byte
+struct Probe {
+    resp: HttpResponse,
+    dlinfo: DownloadInfo,
+    stats: ConnStats,
+    reuse: Reuse,
+}
 
-    let dir = std::env::temp_dir();
-    let fpath = format!(
-        "{}{}.{}",
-        dir.to_str().unwrap_or("/tmp/"),
-        urlinfo.fname,
-        idx
-    );
-    let mut file = File::create(&fpath)?;
+/// What of the probe the first part is downloaded from, instead of a new connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reuse {
+    Nothing,
+    Body,       // resp is a GET whose body is the whole file, the only part
+    Connection, // body of resp was read, its connection is kept alive for another request
+}
+
+/// Probe response, or its connection, the first part is downloaded from
+enum Reused {
+    Body(HttpResponse, ConnStats),
+    Connection(HttpResponse, ConnStats),
+}
 
-    // start fetching data file from server
-    sender.send(DownloadStatus::Started(idx, end - start))?;
+/// why a successful HEAD response does not tell how to download the file, if it does not
+fn inconclusive_head(resp: &HttpResponse, dlinfo: &DownloadInfo) -> Option<&'static str> {
+    if !resp.headers().contains_key(header::CONTENT_LENGTH) {
+        Some("no content length")
+    } else if dlinfo.len == Some(0) {
+        Some("zero content length")
+    } else if !dlinfo.range_supported {
+        Some("no range support advertised")
+    } else {
+        None
+    }
+}
 
-    while pos < end {
-        let n = r.read(&mut buf)?;
-        if n == 0 {
-            break;
+/// learn the length of the file and whether ranges are supported, trying HEAD first
+fn probe_file(
+    cfg: &Config,
+    transport: &Arc<dyn Transport>,
+    urlinfo: &UrlInfo,
+    client: HttpClient,
+) -> Result<Probe, PError> {
+    let (src, dst) = (client.local_addr(), client.remote_addr());
+    let reason = match client.head(&urlinfo.path) {
+        Ok(resp) => {
+            let dlinfo = get_download_info(&resp)?;
+            match inconclusive_head(&resp, &dlinfo) {
+                Some(reason) => reason.to_string(),
+                None => {
+                    let stats = new_conn_stats("probe", &resp, src, dst);
+                    return Ok(Probe {
+                        resp,
+                        dlinfo,
+                        stats,
+                        reuse: Reuse::Nothing,
+                    });
+                }
+            }
         }
+        // e.g. HEAD not allowed, or the connection reset or timed out
+        Err(err) => err.to_string(),
+    };
 
-        // take a slice of buffer from 0 to nth-offset to ensure we only write newly bytes to file
-        file.write_all(&buf[..n])?;
-        pos += n as u64;
-        sender.send(DownloadStatus::Progress(idx, pos - start))?;
+    statusln!(
+        "HEAD not usable ({}), probing with a ranged GET... ",
+        reason
+    );
+    let client = build_client(cfg, transport, urlinfo, 0, "probe")?;
+    let (src, dst) = (client.local_addr(), client.remote_addr());
+    let headers = map!(header::RANGE.to_string() => "bytes=0-0".to_string());
+    let mut resp = match client.get_with_headers(&urlinfo.path, &headers) {
+        // the first byte of an empty file does not exist, the file is asked for whole
+        Err(err)
+            if err.downcast_ref::<HttpError>().map(|err| err.status)
+                == Some(StatusCode::RANGE_NOT_SATISFIABLE) =>
+        {
+            build_client(cfg, transport, urlinfo, 0, "probe")?.get(&urlinfo.path)?
+        }
+        res => res?,
+    };
+    let mut dlinfo = get_download_info(&resp)?;
+    if resp.status() == StatusCode::OK {
+        dlinfo.range_supported = false; // whatever Accept-Ranges says
     }
+    let stats = new_conn_stats("probe", &resp, src, dst);
 
-    sender.send(DownloadStatus::Done(idx, fpath))?;
+    // a server ignoring the range sends the whole file, which is not requested again,
+    // otherwise the connection goes on with the first part once the first byte is read
+    let reuse = match resp.status() {
+        StatusCode::OK => Reuse::Body,
+        _ if read_first_byte(&mut resp) => Reuse::Connection,
+        _ => Reuse::Nothing,
+    };
+    Ok(Probe {
+        resp,
+        dlinfo,
+        stats,
+        reuse,
+    })
+}
 
-    Ok(())
+/// read the body of a response to a request of the first byte, returns whether it was
+fn read_first_byte(resp: &mut HttpResponse) -> bool {
+    let len = resp
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok());
+    len == Some("1") && resp.body_mut().read_exact(&mut [0u8]).is_ok()
 }
 
-fn merge_parts(fpath: &String, parts: &Vec<String>) -> Result<(), PError> {
-    // if there is only one part, just rename downloaded file
-    if parts.len() == 1 {
-        fs::rename(parts.get(0).unwrap(), &fpath)?;
-        return Ok(());
+/// Downloads one part (an inclusive byte range) of the file into a temporary file,
+/// the request is driven by the engine and the progress is reported through sender
+struct PartHandler {
+    idx: usize,
+    label: String,
+    start: u64,
+    end: u64,           // inclusive, as requested: the part may be shortened meanwhile
+    total: Option<u64>, // length of the whole file, None if unknown
+    validators: Validators,
+    pos: u64,
+    remaining: Arc<Mutex<Remaining>>,
+    fpath: String,
+    stdout: Option<Arc<ReorderBuffer>>, // -o -: written in file order, not to fpath
+    sink: Option<PartSink>,
+    deadline: Option<Instant>,
+    stall_detector: Option<StallDetector>,
+    stats: Option<ConnStats>,
+    transfer_start: Instant,
+    downloaded: Counter,
+    sender: Sender<DownloadStatus>,
+}
+
+/// Where the data of a part is written once its response is received
+enum PartSink {
+    File(File),                 // part file, merged with the others once all are done
+    Stdout(Arc<ReorderBuffer>), // straight to stdout, in file order
+}
+
+impl PartHandler {
+    fn send(&self, status: DownloadStatus) {
+        // receiver is gone only if the download is being aborted
+        let _ = self.sender.send(status);
     }
 
-    let tmp_path = format!("{}.tmp", fpath);
-    let mut w = BufWriter::new(File::create(&tmp_path)?);
-    let mut buf = [0u8; 8192];
+    /// check that response carries the requested range of the expected version of the
+    /// file: a 206 whose Content-Range matches it, or a 200 if the whole file was requested
+    fn check_response(&self, head: &ResponseHead) -> Result<(), PError> {
+        let validators = Validators::new(head.header("etag"), head.header("last-modified"));
+        if self.validators.changed(&validators) {
+            return Err(Box::new(PartError::FileChanged(format!(
+                "file changed on server while downloading part {}",
+                self.idx
+            ))));
+        }
 
-    for part in parts {
-        let mut r = File::open(part)?;
-        loop {
-            let n = r.read(&mut buf)?;
-            if n > 0 {
-                w.write_all(&buf[..n])?;
-            } else {
-                break;
+        let err = match (head.status, self.total) {
+            (StatusCode::PARTIAL_CONTENT, Some(total)) => {
+                let content_range = head.header("content-range").unwrap_or_default();
+                if parse_content_range(content_range) == Some((self.start, self.end, total)) {
+                    return Ok(());
+                }
+                format!(
+                    "expected content-range 'bytes {}-{}/{}', got '{}'",
+                    self.start, self.end, total, content_range
+                )
             }
-        }
-    }
+            (StatusCode::OK, Some(total)) if self.start == 0 && self.end + 1 == total => {
+                return Ok(())
+            }
+            // a file of unknown length is not requested by range
+            (StatusCode::OK, None) => return Ok(()),
+            (status, Some(_)) => format!("expected status 206, got {}", status),
+            (status, None) => format!("expected status 200, got {}", status),
+        };
 
-    w.flush()?;
-    drop(w); // drop the file to close it before renaming
+        Err(Box::new(PartError::RangeMismatch(format!(
+            "server did not honor range of part {}: {}",
+            self.idx, err
+        ))))
+    }
 
-    fs::rename(&tmp_path, &fpath)?;
+    /// check that the whole part was received, the end of the body is the end of a file
+    /// of unknown length
+    fn check_complete(&self) -> Result<(), PError> {
+        let end = self.remaining.lock().unwrap().end;
+        if self.total.is_some() && self.pos <= end {
+            return Err(make_error(
+                format!(
+                    "connection closed after {} of {} bytes",
+                    self.pos - self.start,
+                    end - self.start + 1
+                )
+                .as_str(),
+            ));
+        }
 
-    Ok(())
+        Ok(())
+    }
 }
 
-fn download<T: DownloadObserver>(
-    cfg: &Config,
-    urlinfo: &UrlInfo,
-    dlinfo: &DownloadInfo,
-    ob: &mut T,
-) -> Result<(), PError> {
-    let num_threads = if dlinfo.range_supported {
-        cfg.num_threads as u64
-    } else {
-        1
-    };
-    let chunk_size = (dlinfo.len + num_threads - 1) / num_threads;
+impl Handler for PartHandler {
+    fn on_connect(&mut self, local_addr: &ConnAddr, remote_addr: &ConnAddr) {
+        self.stats = Some(ConnStats {
+            label: self.label.clone(),
+            http_code: 0,
+            local_addr: local_addr.clone(),
+            remote_addr: remote_addr.clone(),
+            timings: Timings::default(),
+            transfer: Duration::ZERO,
+            size: 0,
+        });
+    }
 
-    // update UI (progress bar) before starting downloads
-    ob.on_init(num_threads as usize);
+    fn on_response(&mut self, head: &ResponseHead, timings: &Timings) -> Result<(), PError> {
+        check_deadline(self.deadline)?;
+        self.check_response(head)?;
 
-    let (sender, recv) = mpsc::channel();
-    let mut handles = vec![];
-
-    for i in 0..num_threads {
-        let start = i * chunk_size;
-        let end = cmp::min((i + 1) * chunk_size - 1, dlinfo.len - 1);
-
-        // below seems stupid but with my current knowledge about Rust, using clone is the
-        // easiest way to share object between multi-thread, even though I know that
-        // url_info and cfg are read-only objects and can be safe to read by multiple threads
-        let _sender = sender.clone();
-        let _urlinfo = urlinfo.clone();
-        let _idx = i as u8;
-        let _cfg = cfg.clone();
-
-        let handle = thread::spawn(move || {
-            if let Err(err) = download_part(&_cfg, &_urlinfo, start, end, _idx, &_sender) {
-                _sender
-                    .send(DownloadStatus::Failed(_idx, err.to_string()))
-                    .unwrap(); // TODO: find a safe way to handle this
-            }
+        let stats = self
+            .stats
+            .as_mut()
+            .ok_or_else(|| make_error("response received before connection"))?;
+        stats.http_code = head.status.as_u16();
+        stats.timings = *timings;
+        let src = stats.local_addr.clone();
+
+        self.sink = Some(match &self.stdout {
+            Some(stdout) => PartSink::Stdout(stdout.clone()),
+            None => PartSink::File(File::create(&self.fpath)?),
         });
+        self.transfer_start = Instant::now();
 
-        handles.push(handle);
+        // start fetching data file from server
+        let end = self.remaining.lock().unwrap().end;
+        let len = self.total.map(|_| end - self.start + 1);
+        self.send(DownloadStatus::Started(self.idx, len, src));
+
+        Ok(())
     }
 
-    let mut cnt = num_threads; // number of remaining downloads
-    let mut dlparts = vec![String::default(); num_threads as usize];
+    fn on_data(&mut self, data: &[u8]) -> Result<bool, PError> {
+        check_deadline(self.deadline)?;
+        if let Some(detector) = self.stall_detector.as_mut() {
+            detector.update(data.len(), Instant::now())?;
+        }
 
-    // block until all download threads are done or an error is encountered
-    for msg in recv {
-        match msg {
-            DownloadStatus::Started(idx, len) => ob.on_download_start(idx, len),
-            DownloadStatus::Progress(idx, pos) => ob.on_progress(idx, pos),
-            DownloadStatus::Failed(idx, err) => {
-                ob.on_download_end(idx);
-                return Err(make_error(
-                    format!("download failed at part {}: {}", idx, err).as_str(),
-                ));
-            }
-            DownloadStatus::Done(idx, fpath) => {
-                dlparts[idx as usize] = fpath;
-                ob.on_download_end(idx);
+        let sink = self
+            .sink
+            .as_mut()
+            .ok_or_else(|| make_error("data received before response"))?;
 
-                cnt -= 1;
-                if cnt == 0 {
-                    break;
-                }
-            }
+        // data past the end of a part split meanwhile is downloaded by another part
+        let (offset, n, more) = {
+            let mut remaining = self.remaining.lock().unwrap();
+            let offset = remaining.pos;
+            let n = cmp::min(data.len() as u64, remaining.end + 1 - offset);
+            remaining.pos += n;
+            (offset, n as usize, remaining.pos <= remaining.end)
+        };
+        match sink {
+            PartSink::File(file) => file.write_all(&data[..n])?,
+            PartSink::Stdout(stdout) => stdout.write(offset, &data[..n])?,
         }
+        self.pos += n as u64;
+        self.downloaded.set(self.pos - self.start);
+
+        Ok(more)
     }
 
-    for handle in handles {
-        // TODO: is there a better way ?
-        handle.join().unwrap();
+    fn on_tick(&mut self) -> Result<(), PError> {
+        check_deadline(self.deadline)?;
+        if let Some(detector) = self.stall_detector.as_mut() {
+            detector.update(0, Instant::now())?;
+        }
+
+        Ok(())
     }
 
-    // merge all download parts into one file
-    let output = cfg.output.as_ref().unwrap_or(&urlinfo.fname);
-    merge_parts(&output, &dlparts)?;
-    println!(
-        "File downloaded to '{}': {} ({})",
-        output,
-        dlinfo.len,
-        format_byte_length(dlinfo.len)
-    );
+    fn on_end(&mut self, res: Result<(), PError>) {
+        if let Err(err) = res.and_then(|_| self.check_complete()) {
+            let status = match err.downcast::<PartError>() {
+                Ok(err) => DownloadStatus::Rejected(self.idx, *err),
+                Err(err) => match err.downcast::<HttpError>() {
+                    Ok(http_err) if http_err.is_throttling() => {
+                        let wait = http_err.retry_after();
+                        DownloadStatus::Throttled(self.idx, *http_err, wait)
+                    }
+                    Ok(http_err) => {
+                        DownloadStatus::Failed(self.idx, http_err.to_string(), Some(*http_err))
+                    }
+                    Err(err) => DownloadStatus::Failed(self.idx, err.to_string(), None),
+                },
+            };
+            self.send(status);
+            return;
+        }
 
-    Ok(())
+        self.sink = None; // close file
+        if let Some(mut stats) = self.stats.take() {
+            stats.transfer = self.transfer_start.elapsed();
+            stats.size = self.pos - self.start;
+            self.send(DownloadStatus::Done(self.idx, self.fpath.clone(), stats));
+        }
+    }
 }
 
-pub fn run<T: DownloadObserver>(cfg: &Config, ob: &mut T) -> Result<(), PError> {
-    println!("Downloading file at {}", cfg.url);
-    let urlinfo = UrlInfo::parse(&cfg.url)?;
-
-    print!("Resolving {}... ", urlinfo.domain);
-    let sock_addr = resolve_addr(&urlinfo.host_addr())?;
-    println!("{}", sock_addr.ip());
-
-    print!(
-        "Connecting to ({})|{}:{}... ",
-        urlinfo.domain,
-        sock_addr.ip(),
-        urlinfo.port
-    );
-
-    let client = build_client(cfg, &urlinfo)?;
-    println!("connected.");
-    println!("HTTP request sent, awaiting response... ");
+/// Thread feeding the first part from the probe response or its connection, outside the
+/// engine: like the engine, it is stopped and waited for when dropped, before the parts
+/// are cut, merged or downloaded again
+struct Feeder {
+    cancel: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
 
-    // our http client is one-time client, so we must move it
-    // to let get_download_info use it instead of borrow
-    let resp = client.head(&urlinfo.path)?;
-    println!(
-        "{} {}",
-        resp.status().as_u16(),
-        resp.status().canonical_reason().unwrap_or_default()
-    );
+impl Feeder {
+    fn spawn<F>(f: F) -> Feeder
+    where
+        F: FnOnce(&AtomicBool) + Send + 'static,
+    {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let flag = cancel.clone();
+        let handle = thread::spawn(move || f(&flag));
+
+        Feeder {
+            cancel,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Feeder {
+    /// a read in progress is not interrupted, it ends within the read timeout
+    fn drop(&mut self) {
+        self.cancel.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// drive handler of a part with a response received already (the whole file sent to the
+/// probe) instead of requesting it again, on the calling thread, within given rate limits,
+/// until the response ends or cancel is set
+fn feed_response(
+    mut handler: Box<dyn Handler>,
+    limits: Vec<Arc<RateLimit>>,
+    resp: HttpResponse,
+    local_addr: &ConnAddr,
+    remote_addr: &ConnAddr,
+    cancel: &AtomicBool,
+) {
+    handler.on_connect(local_addr, remote_addr);
+    let timings = resp
+        .extensions()
+        .get::<Timings>()
+        .copied()
+        .unwrap_or_default();
+    let head = ResponseHead {
+        version: resp.version(),
+        status: resp.status(),
+        reason: resp
+            .status()
+            .canonical_reason()
+            .unwrap_or_default()
+            .to_string(),
+        headers: resp
+            .headers()
+            .iter()
+            .map(|(key, val)| {
+                (
+                    key.to_string(),
+                    val.to_str().unwrap_or_default().to_string(),
+                )
+            })
+            .collect(),
+    };
+
+    let is_chunked = head
+        .header("transfer-encoding")
+        .is_some_and(|te| te.to_ascii_lowercase().contains("chunked"));
+    let res = handler.on_response(&head, &timings).and_then(|_| {
+        let mut body: Box<dyn Read> = match is_chunked {
+            true => Box::new(ChunkedReader::new(resp.into_body())),
+            false => Box::new(resp.into_body()),
+        };
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            if cancel.load(Ordering::Relaxed) {
+                return Err(make_error("cancelled"));
+            }
+            let granted = match ratelimit::take(&limits, buf.len(), Instant::now()) {
+                Ok(granted) => granted,
+                Err(at) => {
+                    thread::sleep(at.saturating_duration_since(Instant::now()));
+                    continue;
+                }
+            };
+            let n = body.read(&mut buf[..granted])?;
+            ratelimit::give_back(&limits, granted - n);
+            if n == 0 || !handler.on_data(&buf[..n])? {
+                return Ok(());
+            }
+            handler.on_tick()?;
+        }
+    });
+    handler.on_end(res);
+}
+
+/// engine job downloading the nth part of the file, to its part file or to stdout
+#[allow(clippy::too_many_arguments)]
+fn part_job(
+    cfg: &Config,
+    transport: &Arc<dyn Transport>,
+    urlinfo: &UrlInfo,
+    dlinfo: &DownloadInfo,
+    part: &Part,
+    deadline: Option<Instant>,
+    sender: &Sender<DownloadStatus>,
+    stdout: Option<&Arc<ReorderBuffer>>,
+) -> Job {
+    let (idx, start) = (part.idx, part.start);
+    // a retried part starts over, up to its current end
+    let end = {
+        let mut remaining = part.remaining.lock().unwrap();
+        remaining.pos = start;
+        remaining.end
+    };
+    part.downloaded.set(0);
+    let label = format!("part {}", idx);
+    let mut headers = HttpHeaders::new();
+    if dlinfo.len.is_some() {
+        headers.insert(
+            header::RANGE.to_string(),
+            format!("bytes={}-{}", start, end),
+        );
+        // the range is sent only if the file is still the same version, the whole file
+        // otherwise, then found out to have changed
+        if let Some(if_range) = dlinfo.validators.if_range() {
+            headers.insert(header::IF_RANGE.to_string(), if_range.to_string());
+        }
+    }
+    let fpath = part_path(urlinfo, idx);
+
+    let handler = PartHandler {
+        idx,
+        label: label.clone(),
+        start,
+        end,
+        total: dlinfo.len,
+        validators: dlinfo.validators.clone(),
+        pos: start,
+        remaining: part.remaining.clone(),
+        fpath,
+        stdout: stdout.cloned(),
+        sink: None,
+        deadline,
+        stall_detector: cfg.speed_limit.map(|limit| {
+            StallDetector::new(limit, Duration::from_secs(cfg.speed_time), Instant::now())
+        }),
+        stats: None,
+        transfer_start: Instant::now(),
+        downloaded: part.downloaded.clone(),
+        sender: sender.clone(),
+    };
+
+    Job {
+        urlinfo: urlinfo.clone(),
+        headers,
+        cfg: client_builder(cfg, transport, urlinfo, idx, &label).config(),
+        handler: Box::new(handler),
+    }
+}
+
+/// job whose connections also read from the bucket of --limit-rate, if any
+fn rate_limited(job: Job, limit: &Option<Arc<RateLimit>>) -> Job {
+    match limit {
+        Some(limit) => Job {
+            cfg: HttpClient::builder()
+                .with_config(&job.cfg)
+                .with_rate_limit(limit.clone())
+                .config(),
+            ..job
+        },
+        None => job,
+    }
+}
+
+/// reorder buffer writing the file to stdout
+fn stdout_buffer(urlinfo: &UrlInfo) -> Arc<ReorderBuffer> {
+    let stdout = Box::new(BufWriter::new(io::stdout()));
+    Arc::new(ReorderBuffer::new(
+        stdout,
+        REORDER_MEMORY,
+        &spill_path(urlinfo),
+    ))
+}
+
+/// temporary file of data written ahead to stdout, unique to the process and buffer
+fn spill_path(urlinfo: &UrlInfo) -> String {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let name = format!(
+        "{}.{}.{}.spill",
+        urlinfo.fname,
+        process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    );
+    std::env::temp_dir()
+        .join(name)
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// temporary file of the nth part
+fn part_path(urlinfo: &UrlInfo, idx: usize) -> String {
+    let dir = std::env::temp_dir();
+    format!(
+        "{}{}.{}",
+        dir.to_str().unwrap_or("/tmp/"),
+        urlinfo.fname,
+        idx
+    )
+}
+
+fn new_conn_stats(
+    label: &str,
+    resp: &HttpResponse,
+    local_addr: ConnAddr,
+    remote_addr: ConnAddr,
+) -> ConnStats {
+    ConnStats {
+        label: label.to_string(),
+        http_code: resp.status().as_u16(),
+        local_addr,
+        remote_addr,
+        timings: resp
+            .extensions()
+            .get::<Timings>()
+            .copied()
+            .unwrap_or_default(),
+        transfer: Duration::ZERO,
+        size: 0,
+    }
+}
+
+/// print --write-out output of every connection and timing summary (if requested)
+fn report_stats(cfg: &Config, stats: &[ConnStats]) {
+    if let Some(template) = &cfg.write_out {
+        for s in stats {
+            status!("{}", timing::write_out(template, s));
+        }
+    }
+    if cfg.timing {
+        timing::print_summary(stats);
+    }
+}
+
+fn merge_parts(fpath: &String, parts: &Vec<String>) -> Result<(), PError> {
+    // if there is only one part, just rename downloaded file
+    if parts.len() == 1 {
+        fs::rename(parts.first().unwrap(), fpath)?;
+        return Ok(());
+    }
+
+    let tmp_path = format!("{}.tmp", fpath);
+    let mut w = BufWriter::new(File::create(&tmp_path)?);
+    let mut buf = [0u8; 8192];
+
+    for part in parts {
+        let mut r = File::open(part)?;
+        loop {
+            let n = r.read(&mut buf)?;
+            if n > 0 {
+                w.write_all(&buf[..n])?;
+            } else {
+                break;
+            }
+        }
+    }
+
+    w.flush()?;
+    drop(w); // drop the file to close it before renaming
+
+    fs::rename(&tmp_path, fpath)?;
+
+    Ok(())
+}
+
+/// download the version of the file found by probe, starting over from a new probe if
+/// it changes on server meanwhile, then merge the parts into the output file, or write
+/// them to stdout in file order while they download
+fn download<T: DownloadObserver>(
+    cfg: &Config,
+    transport: &Arc<dyn Transport>,
+    urlinfo: &UrlInfo,
+    mut probe: Probe,
+    deadline: Option<Instant>,
+    ob: &mut T,
+) -> Result<Vec<ConnStats>, PError> {
+    let mut restarts = 0;
+    let mut stdout = cfg.to_stdout().then(|| stdout_buffer(urlinfo));
+    let (dlparts, stats) = loop {
+        let res = download_version(
+            cfg,
+            transport,
+            urlinfo,
+            probe,
+            deadline,
+            stdout.as_ref(),
+            ob,
+        );
+        match res {
+            // what was written to stdout already can not be taken back
+            Err(err)
+                if restarts < MAX_RESTARTS
+                    && matches!(err.downcast_ref(), Some(PartError::FileChanged(_)))
+                    && stdout.as_ref().is_none_or(|stdout| stdout.written() == 0) =>
+            {
+                statusln!("{}, restarting download", err);
+                restarts += 1;
+                stdout = cfg.to_stdout().then(|| stdout_buffer(urlinfo));
+                let client = build_client(cfg, transport, urlinfo, 0, "probe")?;
+                probe = probe_file(cfg, transport, urlinfo, client)?;
+            }
+            res => break res?,
+        }
+    };
+
+    let (output, len) = match stdout {
+        Some(stdout) => ("STDOUT", stdout.finish()?),
+        None => {
+            // merge all download parts into one file, an empty one if there are none
+            let output = cfg.output.as_ref().unwrap_or(&urlinfo.fname);
+            merge_parts(output, &dlparts)?;
+            (output.as_str(), fs::metadata(output)?.len())
+        }
+    };
+    statusln!(
+        "File downloaded to '{}': {} ({})",
+        output,
+        len,
+        format_byte_length(len)
+    );
+
+    Ok(stats)
+}
+
+/// download all parts of a version of the file, falling back to a single connection if
+/// the server does not honor ranges after all, returns its part files and stats
+fn download_version<T: DownloadObserver>(
+    cfg: &Config,
+    transport: &Arc<dyn Transport>,
+    urlinfo: &UrlInfo,
+    probe: Probe,
+    deadline: Option<Instant>,
+    stdout: Option<&Arc<ReorderBuffer>>,
+    ob: &mut T,
+) -> Result<(Vec<String>, Vec<ConnStats>), PError> {
+    let mut dlinfo = probe.dlinfo;
+    let reused = match probe.reuse {
+        Reuse::Body => Some(Reused::Body(probe.resp, probe.stats)),
+        Reuse::Connection => Some(Reused::Connection(probe.resp, probe.stats)),
+        Reuse::Nothing => None,
+    };
+
+    let res = download_parts(
+        cfg, transport, urlinfo, &dlinfo, reused, deadline, stdout, ob,
+    );
+    let (dlparts, stats) = match res {
+        Err(err)
+            if dlinfo.range_supported
+                && matches!(err.downcast_ref(), Some(PartError::RangeMismatch(_))) =>
+        {
+            statusln!("{}, falling back to a single connection", err);
+            dlinfo.range_supported = false;
+            download_parts(cfg, transport, urlinfo, &dlinfo, None, deadline, stdout, ob)?
+        }
+        res => res?,
+    };
+
+    Ok((dlparts, stats))
+}
+
+/// number of parts to cut a file of given length into: one per connection (the first
+/// ones with --num-threads auto), or parts of --split-size, but none shorter than
+/// --min-split-size
+fn num_parts(cfg: &Config, len: u64) -> u64 {
+    let num_parts = match (cfg.split_size, cfg.num_threads) {
+        (Some(size), _) => len.div_ceil(cmp::max(size, 1)),
+        (None, NumThreads::Fixed(n)) => n as u64,
+        (None, NumThreads::Auto) => cfg.min_threads as u64,
+    };
+
+    cmp::max(
+        1,
+        cmp::min(num_parts, len / cmp::max(cfg.min_split_size, 1)),
+    )
+}
+
+/// download file in parts (a single one if ranges are not supported or the length is
+/// unknown) pulled from a queue by --num-threads workers, the first one is fed from given
+/// probe response if any, returns the part files (in file order, none if the file is
+/// empty or written to given stdout buffer) and their stats
+#[allow(clippy::too_many_arguments)]
+fn download_parts<T: DownloadObserver>(
+    cfg: &Config,
+    transport: &Arc<dyn Transport>,
+    urlinfo: &UrlInfo,
+    dlinfo: &DownloadInfo,
+    mut reused: Option<Reused>,
+    deadline: Option<Instant>,
+    stdout: Option<&Arc<ReorderBuffer>>,
+    ob: &mut T,
+) -> Result<(Vec<String>, Vec<ConnStats>), PError> {
+    let (num_parts, chunk_size) = match dlinfo.len {
+        Some(0) => return Ok((vec![], vec![])),
+        Some(len) if dlinfo.range_supported => {
+            let chunk_size = len.div_ceil(num_parts(cfg, len));
+            (len.div_ceil(chunk_size), chunk_size) // no empty part at the end
+        }
+        Some(len) => (1, len),
+        None => (1, UNKNOWN_END + 1),
+    };
+    let last = dlinfo.len.map_or(UNKNOWN_END, |len| len - 1);
+    // with --num-threads auto, workers are added up to the maximum while tuning
+    let mut tuner = None;
+    let (num_workers, limit) = match cfg.num_threads {
+        NumThreads::Auto if dlinfo.range_supported => {
+            let min = cmp::min(cfg.min_threads as u64, num_parts) as usize;
+            let max = cmp::max(cfg.max_threads as usize, min);
+            tuner = Some(Tuner::new(min, max, Instant::now()));
+            (max, min)
+        }
+        NumThreads::Auto => (1, 1),
+        NumThreads::Fixed(n) => {
+            let n = cmp::min(n as u64, num_parts) as usize;
+            (n, n)
+        }
+    };
+
+    // update UI (progress bar of every worker) before starting downloads
+    ob.on_init(limit);
+
+    // all parts are driven by a few engine threads, no more than there are cpus
+    let threads = thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(num_workers);
+    let mut engine = Engine::new(threads)?;
+    let mut feeder = None;
+    let (sender, recv) = mpsc::channel();
+    // all connections share the bucket of --limit-rate
+    let rate_limit = cfg
+        .limit_rate
+        .map(|rate| Arc::new(RateLimit::adjustable(rate)));
+    let mut parts: Vec<Part> = (0..num_parts)
+        .map(|i| {
+            let end = cmp::min((i + 1) * chunk_size - 1, last);
+            Part::new(i as usize, i * chunk_size, end)
+        })
+        .collect();
+
+    let mut scheduler = Scheduler::new(num_parts as usize, num_workers, Instant::now());
+    scheduler.set_limit(limit);
+    let mut cnt = num_parts; // number of remaining downloads
+    let mut dlparts = vec![String::default(); num_parts as usize];
+    let mut retries = vec![0u8; num_parts as usize];
+    let mut throttled = vec![0u8; num_parts as usize];
+    let mut stats = vec![None; num_parts as usize];
+    let mut tail: Option<TailWatch> = None;
+    let mut hedge: Option<Hedge> = None;
+    let mut hedged = false; // the last part is hedged once at most
+    let mut steal = false; // whether a free worker should split a remaining part
+    let mut sampled = Instant::now(); // last time the progress of parts was reported
+
+    // block until all parts are done or an error is encountered
+    loop {
+        check_deadline(deadline)?;
+
+        // parts only count the bytes they write, the observer is told at a steady pace
+        if sampled.elapsed() >= PROGRESS_INTERVAL {
+            sampled = Instant::now();
+            for part in parts.iter() {
+                if let Some(worker) = scheduler.worker(part.idx) {
+                    ob.on_progress(worker, part.downloaded.get());
+                }
+            }
+        }
+
+        // add a connection while the aggregate throughput improves
+        let now = Instant::now();
+        if let Some(t) = tuner.as_mut().filter(|t| t.wakeup() <= Some(now)) {
+            let pos = parts.iter().map(|part| part.downloaded.get()).sum();
+            let limit = t.update(scheduler.limit, pos, now);
+            steal |= limit > scheduler.limit;
+            scheduler.set_limit(limit);
+        }
+
+        // a worker is free and no part is queued: take over the end of the largest
+        // remaining part so that a slow connection does not hold the download back, unless
+        // the last part is already raced by a hedge
+        let can_steal = dlinfo.range_supported && scheduler.idle() && hedge.is_none();
+        if std::mem::take(&mut steal) && can_steal {
+            let victim = parts.iter().max_by_key(|part| part.remaining()).cloned();
+            let split = victim.and_then(|victim| {
+                let part = victim.split(parts.len(), cfg.min_split_size)?;
+                Some((victim, part))
+            });
+            if let Some((victim, part)) = split {
+                if let Some(worker) = scheduler.worker(victim.idx) {
+                    ob.on_split(worker, part.start - victim.start);
+                }
+                scheduler.add(part.idx, Instant::now());
+                parts.push(part);
+                dlparts.push(String::default());
+                retries.push(0);
+                throttled.push(0);
+                stats.push(None);
+                cnt += 1;
+            }
+        }
+
+        while let Some((idx, _)) = scheduler.next(Instant::now()) {
+            let job = part_job(
+                cfg,
+                transport,
+                urlinfo,
+                dlinfo,
+                &parts[idx],
+                deadline,
+                &sender,
+                stdout,
+            );
+            let job = rate_limited(job, &rate_limit);
+            match reused.take() {
+                Some(Reused::Body(resp, stats)) => {
+                    let limits = job.cfg.rate_limits();
+                    feeder = Some(Feeder::spawn(move |cancel| {
+                        let (local_addr, remote_addr) = (&stats.local_addr, &stats.remote_addr);
+                        feed_response(job.handler, limits, resp, local_addr, remote_addr, cancel)
+                    }));
+                }
+                Some(Reused::Connection(resp, stats)) => {
+                    let client = HttpClient::builder()
+                        .from_url_info(urlinfo)
+                        .with_config(&job.cfg)
+                        .build_on(resp);
+                    match client {
+                        Ok(client) => {
+                            let limits = job.cfg.rate_limits();
+                            let path = urlinfo.path.clone();
+                            feeder = Some(Feeder::spawn(move |cancel| {
+                                let Job {
+                                    headers,
+                                    mut handler,
+                                    ..
+                                } = job;
+                                let (local_addr, remote_addr) =
+                                    (&stats.local_addr, &stats.remote_addr);
+                                match client.get_with_headers(&path, &headers) {
+                                    Ok(resp) => feed_response(
+                                        handler,
+                                        limits,
+                                        resp,
+                                        local_addr,
+                                        remote_addr,
+                                        cancel,
+                                    ),
+                                    Err(err) => handler.on_end(Err(err)),
+                                }
+                            }));
+                        }
+                        Err(_) => engine.submit(job)?,
+                    }
+                }
+                None => engine.submit(job)?,
+            }
+        }
+
+        // race the last part left with a hedge if it is too slow
+        let mut tail_wakeup = None;
+        let hedge_speed = cfg
+            .hedge_speed
+            .filter(|_| dlinfo.range_supported && cnt == 1 && !hedged);
+        if let Some(limit) = hedge_speed {
+            let now = Instant::now();
+            let last = dlparts
+                .iter()
+                .position(String::is_empty)
+                .filter(|idx| scheduler.worker(*idx).is_some());
+            match last {
+                Some(idx) => {
+                    let pos = parts[idx].downloaded.get();
+                    let watch = tail
+                        .take()
+                        .filter(|watch| watch.idx == idx)
+                        .unwrap_or_else(|| TailWatch::new(idx, pos, now));
+                    let watch = tail.insert(watch);
+                    if watch.slow(limit, pos, now) {
+                        hedged = true;
+                        if let Some(part) = parts[idx].rest(parts.len()) {
+                            let mut job = part_job(
+                                cfg, transport, urlinfo, dlinfo, &part, deadline, &sender, stdout,
+                            );
+                            // a connection of its own, not a stream of an HTTP/2 or HTTP/3
+                            // connection, which may be the slow one
+                            let label = format!("part {}", part.idx);
+                            job.cfg = client_builder(cfg, transport, urlinfo, part.idx, &label)
+                                .with_http2(false)
+                                .with_http3(false)
+                                .config();
+                            engine.submit(rate_limited(job, &rate_limit))?;
+                            hedge = Some(Hedge {
+                                part,
+                                victim: idx,
+                                victim_failed: None,
+                                fpath: None,
+                            });
+                        }
+                    } else {
+                        tail_wakeup = Some(watch.wakeup());
+                    }
+                }
+                None => tail = None,
+            }
+        }
+
+        let wakeup = deadline
+            .into_iter()
+            .chain(scheduler.wakeup())
+            .chain(tail_wakeup)
+            .chain(tuner.as_ref().and_then(Tuner::wakeup))
+            .fold(sampled + PROGRESS_INTERVAL, cmp::min);
+        let timeout = wakeup.saturating_duration_since(Instant::now());
+        let msg = match recv.recv_timeout(timeout) {
+            Ok(msg) => msg,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(err) => return Err(Box::new(err)),
+        };
+
+        // the first one of the hedge and the part it races to be done wins, a failure of
+        // one of them is handled only if the other one fails too
+        if let Some(h) = hedge.as_mut() {
+            if msg.idx() == h.part.idx {
+                match msg {
+                    DownloadStatus::Done(_, fpath, part_stats) => {
+                        if let Some(worker) = scheduler.worker(h.victim) {
+                            ob.on_download_end(worker);
+                        }
+                        h.fpath = Some(fpath);
+                        stats.push(Some(part_stats));
+                        break;
+                    }
+                    DownloadStatus::Started(..) => {}
+                    _ => {
+                        let _ = fs::remove_file(part_path(urlinfo, h.part.idx));
+                        if let Some(failed) = h.victim_failed.take() {
+                            sender.send(failed)?;
+                        }
+                        hedge = None;
+                    }
+                }
+                continue;
+            }
+            if msg.idx() == h.victim
+                && matches!(
+                    msg,
+                    DownloadStatus::Failed(..) | DownloadStatus::Throttled(..)
+                )
+            {
+                h.victim_failed = Some(msg);
+                continue;
+            }
+        }
+
+        // parts are identified by their index, the observer is told about workers
+        let worker = scheduler.worker(msg.idx());
+        match msg {
+            DownloadStatus::Started(_, len, src) => {
+                if let Some(worker) = worker {
+                    ob.on_download_start(worker, len, &src);
+                }
+            }
+            DownloadStatus::Failed(idx, ..) if retries[idx] < cfg.retries => {
+                // restart the failed (or stalled) part from scratch
+                retries[idx] += 1;
+                scheduler.retry(idx, Instant::now());
+                if let Some(t) = tuner.as_mut() {
+                    scheduler.set_limit(t.failed(scheduler.limit));
+                }
+            }
+            DownloadStatus::Failed(idx, msg, http_err) => {
+                return Err(part_failed(ob, worker, idx, msg, http_err))
+            }
+            DownloadStatus::Rejected(_, err) => {
+                if let Some(worker) = worker {
+                    ob.on_download_end(worker);
+                }
+                return Err(Box::new(err));
+            }
+            DownloadStatus::Throttled(idx, err, wait) => {
+                // honor the delay asked by server, or back off exponentially
+                let wait = wait.unwrap_or(THROTTLE_BACKOFF * (1 << throttled[idx]));
+                if throttled[idx] >= MAX_THROTTLED_RETRIES || wait > MAX_RETRY_AFTER {
+                    return Err(part_failed(ob, worker, idx, err.to_string(), Some(err)));
+                }
+                throttled[idx] += 1;
+                if let Some(worker) = worker {
+                    ob.on_throttled(worker, wait);
+                }
+                scheduler.throttled(idx, wait, Instant::now());
+                if let Some(t) = tuner.as_mut() {
+                    t.settled = true;
+                }
+            }
+            DownloadStatus::Done(idx, fpath, part_stats) => {
+                dlparts[idx] = fpath;
+                stats[idx] = Some(part_stats);
+                if let Some(worker) = worker {
+                    ob.on_progress(worker, parts[idx].downloaded.get());
+                    ob.on_download_end(worker);
+                }
+                scheduler.ended(idx);
+                cnt -= 1;
+                steal = true;
+
+                if cnt == 0 {
+                    break;
+                }
+            }
+        }
+    }
+
+    // requests still in flight, the loser of a hedge race, are cancelled; parts written to
+    // stdout have no file to cut
+    drop(engine);
+    drop(feeder);
+    if let Some(hedge) = hedge.filter(|_| stdout.is_none()) {
+        match hedge.fpath {
+            // the hedge won: the part it raced ends where the hedge started
+            Some(fpath) => {
+                let len = hedge.part.start - parts[hedge.victim].start;
+                let victim_path = part_path(urlinfo, hedge.victim);
+                if len > 0 {
+                    let file = OpenOptions::new().write(true).open(&victim_path)?;
+                    if file.metadata()?.len() < len {
+                        return Err(make_error("part file is shorter than downloaded"));
+                    }
+                    file.set_len(len)?;
+                    dlparts[hedge.victim] = victim_path;
+                } else {
+                    let _ = fs::remove_file(victim_path);
+                }
+                parts.push(hedge.part);
+                dlparts.push(fpath);
+            }
+            None => {
+                let _ = fs::remove_file(part_path(urlinfo, hedge.part.idx));
+            }
+        }
+    }
+
+    // parts split meanwhile are not in file order anymore
+    let mut dlparts: Vec<(u64, String)> = parts
+        .iter()
+        .map(|part| part.start)
+        .zip(dlparts)
+        .filter(|(_, fpath)| !fpath.is_empty() && stdout.is_none())
+        .collect();
+    dlparts.sort();
+    Ok((
+        dlparts.into_iter().map(|(_, fpath)| fpath).collect(),
+        stats.into_iter().flatten().collect(),
+    ))
+}
+
+fn part_failed<T: DownloadObserver>(
+    ob: &mut T,
+    worker: Option<usize>,
+    idx: usize,
+    msg: String,
+    http_err: Option<HttpError>,
+) -> PError {
+    if let Some(worker) = worker {
+        ob.on_download_end(worker);
+    }
+    Box::new(PartFailed { idx, msg, http_err })
+}
+
+/// Error response to the probe (which falls back to GET when HEAD fails so the error
+/// details, e.g. an API's JSON error, are in the body) or to a part: print its headers
+/// if --info and save its body if --content-on-error
+fn handle_http_error(cfg: &Config, urlinfo: &UrlInfo, err: PError) -> PError {
+    let http_err = match err.downcast_ref::<HttpError>() {
+        Some(http_err) => http_err,
+        None => match err.downcast_ref::<PartFailed>() {
+            Some(PartFailed {
+                http_err: Some(http_err),
+                ..
+            }) => http_err,
+            _ => return err,
+        },
+    };
 
     if cfg.info {
-        println!("Response headers:");
-        for (key, value) in resp.headers().iter() {
-            println!("=> {}: {}", key, value.to_str().unwrap_or_default());
+        statusln!("Response headers:");
+        for (key, value) in http_err.headers.iter() {
+            statusln!("=> {}: {}", key, value);
         }
+    }
 
+    if cfg.content_on_error {
+        let output = cfg.output.as_ref().unwrap_or(&urlinfo.fname);
+        let res = match cfg.to_stdout() {
+            true => io::stdout().write_all(&http_err.body),
+            false => fs::write(output, &http_err.body),
+        };
+        if let Err(e) = res {
+            return e.into();
+        }
+        match http_err.body_cut() {
+            true => statusln!(
+                "Error response body saved to '{}', cut to its first {} bytes",
+                output,
+                http_err.body.len()
+            ),
+            false => statusln!("Error response body saved to '{}'", output),
+        }
+    }
+
+    err
+}
+
+pub fn run<T: DownloadObserver>(cfg: &Config, ob: &mut T) -> Result<(), PError> {
+    let deadline = cfg
+        .max_time
+        .and_then(|secs| Instant::now().checked_add(Duration::from_secs(secs)));
+
+    // stdout carries the file with -o -
+    fget::set_status_to_stderr(cfg.to_stdout());
+    statusln!("Downloading file at {}", cfg.url);
+    let mut urlinfo = UrlInfo::parse(&cfg.url)?;
+    if hsts::upgrade(&mut urlinfo) {
+        statusln!("HSTS: upgrading to https");
+    }
+
+    if let Some(path) = &cfg.unix_socket {
+        status!(
+            "Connecting to ({}) via unix socket {}... ",
+            urlinfo.domain,
+            path
+        );
+    } else {
+        status!("Resolving {}... ", urlinfo.domain);
+        let sock_addr = resolve_addr(&urlinfo.host_addr())?;
+        statusln!("{}", sock_addr.ip());
+
+        status!(
+            "Connecting to ({})|{}:{}... ",
+            urlinfo.domain,
+            sock_addr.ip(),
+            urlinfo.port
+        );
+    }
+
+    let transport = make_transport(cfg);
+    let client = build_client(cfg, &transport, &urlinfo, 0, "probe")?;
+    statusln!("connected from {}.", client.local_addr().host());
+    statusln!("HTTP request sent, awaiting response... ");
+
+    let probe = match probe_file(cfg, &transport, &urlinfo, client) {
+        Ok(probe) => probe,
+        Err(err) => return Err(handle_http_error(cfg, &urlinfo, err)),
+    };
+    let status = probe.resp.status();
+    statusln!(
+        "{} {}",
+        status.as_u16(),
+        status.canonical_reason().unwrap_or_default()
+    );
+
+    if cfg.info {
+        statusln!("Response headers:");
+        for (key, value) in probe.resp.headers().iter() {
+            statusln!("=> {}: {}", key, value.to_str().unwrap_or_default());
+        }
+
+        report_stats(cfg, &[probe.stats]);
         return Ok(());
     }
 
-    let dlinfo = get_download_info(resp)?;
-    println!(
-        "Length: {} ({}), accept-ranges: {} [{}]",
-        dlinfo.len,
-        format_byte_length(dlinfo.len),
+    let dlinfo = &probe.dlinfo;
+    let length = match dlinfo.len {
+        Some(len) => format!("{} ({})", len, format_byte_length(len)),
+        None => "unspecified".to_string(),
+    };
+    statusln!(
+        "Length: {}, accept-ranges: {} [{}]",
+        length,
         dlinfo.range_supported,
         dlinfo.content_type
     );
 
-    if dlinfo.len == 0 {
-        return Err(make_error("content length is zero"));
+    let target = match cfg.to_stdout() {
+        true => "STDOUT",
+        false => &urlinfo.fname,
+    };
+    statusln!("Saving to: '{}'\r\n", target);
+    let probe_stats = probe.stats.clone();
+    if !cfg.range.is_empty() {
+        drop(probe);
+        let written = download_ranges(cfg, &transport, &urlinfo)
+            .map_err(|err| handle_http_error(cfg, &urlinfo, err))?;
+        let written: Vec<String> = written
+            .iter()
+            .map(|(first, last)| format!("{}-{}", first, last))
+            .collect();
+        statusln!(
+            "Written {} of {} ranges: {}",
+            written.len(),
+            cfg.range.len(),
+            written.join(",")
+        );
+        report_stats(cfg, &[probe_stats]);
+        return Ok(());
+    }
+
+    let mut stats = download(cfg, &transport, &urlinfo, probe, deadline, ob)
+        .map_err(|err| handle_http_error(cfg, &urlinfo, err))?;
+    stats.insert(0, probe_stats);
+    report_stats(cfg, &stats);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memtransport::{Fault, MemTransport};
+    use clap::Parser;
+
+    struct NoopObserver;
+
+    impl DownloadObserver for NoopObserver {
+        fn on_init(&mut self, _len: usize) {}
+        fn on_download_start(&mut self, _idx: usize, _len: Option<u64>, _src: &ConnAddr) {}
+        fn on_progress(&mut self, _idx: usize, _pos: u64) {}
+        fn on_throttled(&mut self, _idx: usize, _wait: Duration) {}
+        fn on_split(&mut self, _idx: usize, _len: u64) {}
+        fn on_download_end(&mut self, _idx: usize) {}
+    }
+
+    /// download url served by transport with given extra arguments, returns file content
+    fn mem_download(transport: MemTransport, url: &str, args: &[&str]) -> Result<Vec<u8>, PError> {
+        let urlinfo = UrlInfo::parse(url)?;
+        let output = std::env::temp_dir().join(format!("{}.out", urlinfo.fname));
+        let output = output.to_str().unwrap();
+        let cfg = Config::parse_from(["fget", url, "-o", output].iter().chain(args));
+
+        let transport: Arc<dyn Transport> = Arc::new(transport);
+        let client = build_client(&cfg, &transport, &urlinfo, 0, "probe")?;
+        let probe = probe_file(&cfg, &transport, &urlinfo, client)?;
+        download(&cfg, &transport, &urlinfo, probe, None, &mut NoopObserver)?;
+
+        let content = fs::read(output)?;
+        fs::remove_file(output)?;
+        Ok(content)
+    }
+
+    fn content(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn test_download_parts() {
+        let data = content(100_000);
+        let transport = MemTransport::new().serve("http://a.test/parts.bin", &data);
+
+        let downloaded = mem_download(
+            transport.clone(),
+            "http://a.test/parts.bin",
+            &["-t", "4", "--min-split-size", "25000"],
+        )
+        .unwrap();
+        assert_eq!(data, downloaded);
+
+        let mut requests = transport.requests();
+        requests.sort();
+        assert_eq!(
+            vec![
+                "GET a.test:80/parts.bin 0-24999",
+                "GET a.test:80/parts.bin 25000-49999",
+                "GET a.test:80/parts.bin 50000-74999",
+                "GET a.test:80/parts.bin 75000-99999",
+                "HEAD a.test:80/parts.bin -",
+            ],
+            requests
+        );
+    }
+
+    #[test]
+    fn test_download_probe_fallback() {
+        let data = content(100_000);
+        let url = "http://a.test/probe.bin";
+
+        // HEAD is not allowed, the first byte tells the length
+        let transport = MemTransport::new()
+            .serve(url, &data)
+            .fault(None, Fault::Status(405));
+        let downloaded = mem_download(
+            transport.clone(),
+            url,
+            &["-t", "2", "--min-split-size", "50000"],
+        );
+        assert_eq!(data, downloaded.unwrap());
+
+        let mut requests = transport.requests();
+        requests.sort();
+        assert_eq!(
+            vec![
+                "GET a.test:80/probe.bin 0-0",
+                "GET a.test:80/probe.bin 0-49999",
+                "GET a.test:80/probe.bin 50000-99999",
+                "HEAD a.test:80/probe.bin -",
+            ],
+            requests
+        );
+        // the first part is requested on the connection of the probe
+        assert_eq!(3, transport.connections());
+
+        // HEAD fails before any response, the first byte is asked for all the same
+        let transport = MemTransport::new()
+            .serve(url, &data)
+            .fault(None, Fault::Reset);
+        let downloaded = mem_download(transport.clone(), url, &["-t", "1"]);
+        assert_eq!(data, downloaded.unwrap());
+        assert_eq!(
+            vec![
+                "HEAD a.test:80/probe.bin -",
+                "GET a.test:80/probe.bin 0-0",
+                "GET a.test:80/probe.bin 0-99999"
+            ],
+            transport.requests()
+        );
+        assert_eq!(2, transport.connections());
+
+        // HEAD has no length and the range is ignored: the probe gets the whole file
+        let transport = MemTransport::new()
+            .serve(url, &data)
+            .fault(None, Fault::NoLength)
+            .fault(Some(0), Fault::IgnoreRange);
+        let downloaded = mem_download(
+            transport.clone(),
+            url,
+            &["-t", "2", "--min-split-size", "50000"],
+        );
+        assert_eq!(data, downloaded.unwrap());
+        assert_eq!(
+            vec!["HEAD a.test:80/probe.bin -", "GET a.test:80/probe.bin 0-0"],
+            transport.requests()
+        );
+
+        // the ranged probe is redirected to a mirror, which is asked for the first byte
+        let mirror = "http://b.test/probe.bin";
+        let transport = MemTransport::new()
+            .serve(mirror, &data)
+            .redirect(url, mirror)
+            .fault(None, Fault::Status(405));
+        let downloaded = mem_download(
+            transport.clone(),
+            url,
+            &["-t", "2", "--min-split-size", "50000"],
+        );
+        assert_eq!(data, downloaded.unwrap());
+        let requests = transport.requests();
+        assert_eq!(
+            vec![
+                "HEAD a.test:80/probe.bin -",
+                "GET a.test:80/probe.bin 0-0",
+                "GET b.test:80/probe.bin 0-0",
+            ],
+            requests[..3]
+        );
+        // the file is downloaded in parts, not as the whole response to the probe
+        assert!(requests.iter().any(|req| req.ends_with(" 50000-99999")));
+    }
+
+    #[test]
+    fn test_download_ranges() {
+        let data = content(100_000);
+        let url = "http://a.test/holes.bin";
+        let output = std::env::temp_dir().join(format!("holes.{}.out", process::id()));
+        let fetch = |transport: MemTransport| {
+            fs::write(&output, vec![0u8; data.len()]).unwrap();
+            let args = [
+                "-o",
+                output.to_str().unwrap(),
+                "--range",
+                "10-19,50000-50009",
+            ];
+            let cfg = Config::parse_from(["fget", url].iter().chain(&args));
+            let transport: Arc<dyn Transport> = Arc::new(transport);
+            let urlinfo = UrlInfo::parse(url).unwrap();
+            let written = download_ranges(&cfg, &transport, &urlinfo).unwrap();
+            (written, fs::read(&output).unwrap())
+        };
+        // the holes are filled in one request, the rest of the file is left as it is
+        let mut expected = vec![0u8; data.len()];
+        expected[10..20].copy_from_slice(&data[10..20]);
+        expected[50000..50010].copy_from_slice(&data[50000..50010]);
+
+        let transport = MemTransport::new().serve(url, &data);
+        let (written, downloaded) = fetch(transport.clone());
+        assert_eq!(vec![(10, 19), (50000, 50009)], written);
+        assert_eq!(expected, downloaded);
+        assert_eq!(
+            vec!["GET a.test:80/holes.bin 10-19,50000-50009"],
+            transport.requests()
+        );
+
+        // the whole file sent instead, only the ranges are written
+        let transport = MemTransport::new()
+            .serve(url, &data)
+            .fault(None, Fault::IgnoreRange);
+        let (written, downloaded) = fetch(transport);
+        assert_eq!(vec![(10, 19), (50000, 50009)], written);
+        assert_eq!(expected, downloaded);
+        fs::remove_file(&output).unwrap();
+    }
+
+    #[test]
+    fn test_download_unknown_length() {
+        let data = content(100_000);
+        let url = "http://a.test/export.csv";
+
+        // generated on the fly: a single connection reads the body until its end
+        for fault in [Fault::Chunked(7000), Fault::Streamed] {
+            let transport = MemTransport::new()
+                .serve(url, &data)
+                .fault(None, Fault::NoLength)
+                .fault(Some(0), fault);
+            let downloaded = mem_download(transport.clone(), url, &["-t", "4"]);
+            assert_eq!(data, downloaded.unwrap());
+            assert_eq!(
+                vec![
+                    "HEAD a.test:80/export.csv -",
+                    "GET a.test:80/export.csv 0-0"
+                ],
+                transport.requests()
+            );
+        }
+
+        // the first byte of an empty file is not satisfiable, the output file is created
+        let url = "http://a.test/empty.bin";
+        let transport = MemTransport::new().serve(url, b"");
+        assert_eq!(
+            Vec::<u8>::new(),
+            mem_download(transport.clone(), url, &[]).unwrap()
+        );
+        assert_eq!(
+            vec![
+                "HEAD a.test:80/empty.bin -",
+                "GET a.test:80/empty.bin 0-0",
+                "GET a.test:80/empty.bin -",
+            ],
+            transport.requests()
+        );
+    }
+
+    #[test]
+    fn test_download_range_mismatch() {
+        let data = content(100_000);
+        let url = "http://a.test/mismatch.bin";
+
+        // part 1 gets the whole file: download starts over on a single connection
+        let transport = MemTransport::new()
+            .serve(url, &data)
+            .fault(Some(50_000), Fault::IgnoreRange);
+        let downloaded = mem_download(
+            transport.clone(),
+            url,
+            &["-t", "2", "--min-split-size", "50000"],
+        );
+        assert_eq!(data, downloaded.unwrap());
+
+        let requests = transport.requests();
+        assert_eq!(
+            Some(&"GET a.test:80/mismatch.bin 0-99999".to_string()),
+            requests.last()
+        );
+
+        // single connection gets another range too: give up
+        let transport = MemTransport::new()
+            .serve(url, &data)
+            .fault(Some(0), Fault::ShiftRange(10))
+            .fault(Some(0), Fault::ShiftRange(10));
+        let err =
+            mem_download(transport, url, &["-t", "2", "--min-split-size", "50000"]).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("server did not honor range of part 0"));
+    }
+
+    #[test]
+    fn test_download_file_changed() {
+        let data = content(100_000);
+        let url = "http://a.test/changed.bin";
+
+        // If-Range of part 1 does not match: it gets the new version, download restarts
+        let transport = MemTransport::new()
+            .serve(url, &data)
+            .fault(Some(50_000), Fault::Modified);
+        let downloaded = mem_download(
+            transport.clone(),
+            url,
+            &["-t", "2", "--min-split-size", "50000"],
+        );
+        assert_eq!(data, downloaded.unwrap());
+
+        let requests = transport.requests();
+        let heads = requests
+            .iter()
+            .filter(|req| req.starts_with("HEAD"))
+            .count();
+        assert_eq!(2, heads);
+        let if_ranges = transport.request_header("if-range");
+        for (req, if_range) in requests.iter().zip(if_ranges) {
+            assert_eq!(req.starts_with("GET"), if_range.is_some(), "{}", req);
+        }
+
+        // file keeps changing
+        let transport = MemTransport::new()
+            .serve(url, &data)
+            .fault(Some(0), Fault::Modified)
+            .fault(Some(0), Fault::Modified)
+            .fault(Some(0), Fault::Modified);
+        let err =
+            mem_download(transport, url, &["-t", "2", "--min-split-size", "50000"]).unwrap_err();
+        assert_eq!(
+            "file changed on server while downloading part 0",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn test_validators() {
+        let date = "Wed, 21 Oct 2015 07:28:00 GMT";
+        let strong = Validators::new(Some("\"v1\""), Some(date));
+        assert_eq!(Some("\"v1\""), strong.if_range());
+        let weak = Validators::new(Some("W/\"v1\""), Some(date));
+        assert_eq!(Some(date), weak.if_range());
+        assert_eq!(None, Validators::new(Some("W/\"v1\""), None).if_range());
+        assert_eq!(None, Validators::default().if_range());
+
+        assert!(!strong.changed(&weak));
+        assert!(strong.changed(&Validators::new(Some("\"v2\""), Some(date))));
+        assert!(!strong.changed(&Validators::default()));
+
+        let dated = Validators::new(None, Some(date));
+        assert!(!dated.changed(&strong));
+        assert!(dated.changed(&Validators::new(
+            None,
+            Some("Thu, 22 Oct 2015 07:28:00 GMT")
+        )));
+    }
+
+    #[test]
+    fn test_parse_content_range() {
+        assert_eq!(Some((0, 0, 1000)), parse_content_range("bytes 0-0/1000"));
+        assert_eq!(
+            Some((10, 999, 1000)),
+            parse_content_range(" bytes 10-999/1000")
+        );
+        assert_eq!(None, parse_content_range("bytes 0-0/*"));
+        assert_eq!(None, parse_content_range("bytes 0-1000/1000"));
+        assert_eq!(None, parse_content_range("bytes 5-4/1000"));
+        assert_eq!(None, parse_content_range("bytes */1000"));
+    }
+
+    #[test]
+    fn test_download_retry() {
+        let data = content(100_000);
+        let url = "http://a.test/retry.bin";
+
+        let transport = MemTransport::new()
+            .serve(url, &data)
+            .fault(Some(50_000), Fault::ResetAfter(1000));
+        let err =
+            mem_download(transport, url, &["-t", "4", "--min-split-size", "25000"]).unwrap_err();
+        assert!(err.to_string().starts_with("download failed at part 2"));
+
+        let transport = MemTransport::new()
+            .serve(url, &data)
+            .fault(Some(50_000), Fault::ResetAfter(1000))
+            .fault(Some(0), Fault::Latency(Duration::from_millis(50)));
+        let downloaded = mem_download(
+            transport.clone(),
+            url,
+            &["-t", "4", "--min-split-size", "25000", "--retries", "1"],
+        );
+        assert_eq!(data, downloaded.unwrap());
+
+        let retried = transport
+            .requests()
+            .iter()
+            .filter(|req| req.ends_with(" 50000-74999"))
+            .count();
+        assert_eq!(2, retried);
+    }
+
+    #[test]
+    fn test_content_on_error() {
+        let data = content(100_000);
+        let url = "http://a.test/error.bin";
+        let urlinfo = UrlInfo::parse(url).unwrap();
+        let output = std::env::temp_dir().join("error.bin.out");
+        let args = ["fget", url, "-o", output.to_str().unwrap()];
+        let cfg = Config::parse_from(args.iter().chain(&["--content-on-error"]));
+
+        // the error body of a part is saved, as the one of the probe
+        let transport = MemTransport::new()
+            .serve(url, &data)
+            .fault(Some(50_000), Fault::Status(404));
+        let err = mem_download(transport, url, &["-t", "2", "--min-split-size", "50000"])
+            .err()
+            .unwrap();
+        let err = handle_http_error(&cfg, &urlinfo, err);
+        assert!(err
+            .to_string()
+            .starts_with("download failed at part 1: server response error: 404"));
+        assert_eq!(b"fault", &fs::read(&output).unwrap()[..]);
+        fs::remove_file(&output).unwrap();
+
+        // other errors are left as they are
+        let err = handle_http_error(&cfg, &urlinfo, make_error("connection reset"));
+        assert_eq!("connection reset", err.to_string());
+        assert!(!output.exists());
+    }
+
+    #[test]
+    fn test_download_throttled() {
+        let data = content(100_000);
+        let url = "http://a.test/throttled.bin";
+
+        let transport = MemTransport::new()
+            .serve(url, &data)
+            .fault(Some(50_000), Fault::Throttle(429, 0))
+            .fault(Some(50_000), Fault::Throttle(503, 0));
+        let downloaded = mem_download(
+            transport.clone(),
+            url,
+            &["-t", "4", "--min-split-size", "25000"],
+        );
+        assert_eq!(data, downloaded.unwrap());
+
+        let retried = transport
+            .requests()
+            .iter()
+            .filter(|req| req.ends_with(" 50000-74999"))
+            .count();
+        assert_eq!(3, retried);
+
+        let transport = MemTransport::new()
+            .serve(url, &data)
+            .fault(Some(0), Fault::Throttle(503, 3600));
+        let err =
+            mem_download(transport, url, &["-t", "4", "--min-split-size", "25000"]).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("download failed at part 0: server response error: 503"));
+    }
+
+    #[test]
+    fn test_scheduler() {
+        let now = Instant::now();
+        let mut scheduler = Scheduler::new(4, 4, now);
+        let submitted: Vec<(usize, usize)> = std::iter::from_fn(|| scheduler.next(now)).collect();
+        assert_eq!(vec![(0, 0), (1, 1), (2, 2), (3, 3)], submitted);
+        assert_eq!(None, scheduler.wakeup());
+
+        // concurrency is halved, part 2 waits for its delay
+        scheduler.throttled(2, Duration::from_secs(1), now);
+        assert_eq!(2, scheduler.limit);
+        assert_eq!(None, scheduler.wakeup());
+
+        scheduler.ended(0);
+        scheduler.retry(1, now);
+        assert_eq!(Some(now), scheduler.wakeup());
+        assert_eq!(Some((1, 0)), scheduler.next(now));
+        assert_eq!(None, scheduler.next(now)); // limit reached
+        assert_eq!(None, scheduler.wakeup());
+
+        scheduler.ended(3);
+        assert_eq!(Some(now + Duration::from_secs(1)), scheduler.wakeup());
+        assert_eq!(None, scheduler.next(now));
+        assert_eq!(Some((2, 1)), scheduler.next(now + Duration::from_secs(1)));
+
+        // never below one part at a time
+        scheduler.throttled(2, Duration::ZERO, now);
+        scheduler.throttled(1, Duration::ZERO, now);
+        assert_eq!(1, scheduler.limit);
+        assert_eq!(Some((1, 0)), scheduler.next(now));
+        assert_eq!(None, scheduler.next(now));
+
+        // more parts than workers: a free worker pulls the next part
+        let mut scheduler = Scheduler::new(3, 2, now);
+        assert_eq!(Some((0, 0)), scheduler.next(now));
+        assert_eq!(Some((1, 1)), scheduler.next(now));
+        assert_eq!(None, scheduler.next(now));
+        scheduler.ended(0);
+        assert_eq!(Some((2, 0)), scheduler.next(now));
+        assert_eq!(Some(0), scheduler.worker(2));
+        assert!(!scheduler.idle());
+        scheduler.ended(1);
+        assert!(scheduler.idle());
+        assert_eq!(None, scheduler.worker(1));
+
+        // the limit is bounded by the number of workers
+        scheduler.set_limit(8);
+        assert_eq!(2, scheduler.limit);
+        scheduler.set_limit(0);
+        assert_eq!(1, scheduler.limit);
+    }
+
+    #[test]
+    fn test_tuner() {
+        let now = Instant::now();
+        let mut tuner = Tuner::new(1, 3, now);
+        assert_eq!(Some(now + TUNE_WINDOW), tuner.wakeup());
+
+        // not over yet, then still connecting
+        assert_eq!(1, tuner.update(1, 1000, now + Duration::from_secs(1)));
+        assert_eq!(1, tuner.update(1, 0, now + TUNE_WINDOW));
+
+        // a connection is added while the throughput improves, up to the maximum
+        let mut at = now + TUNE_WINDOW;
+        let mut update = |limit, pos| {
+            at += TUNE_WINDOW;
+            tuner.update(limit, pos, at)
+        };
+        assert_eq!(2, update(1, 2000));
+        assert_eq!(3, update(2, 6000));
+        assert_eq!(3, update(3, 16000));
+        assert_eq!(None, tuner.wakeup());
+
+        // the last connection is given up when it does not help
+        let mut tuner = Tuner::new(1, 4, now);
+        assert_eq!(2, tuner.update(1, 2000, now + TUNE_WINDOW));
+        assert_eq!(1, tuner.update(2, 4100, now + TUNE_WINDOW * 2));
+        assert_eq!(None, tuner.wakeup());
+
+        // or when a part fails, not below the minimum
+        let mut tuner = Tuner::new(2, 4, now);
+        assert_eq!(3, tuner.failed(4));
+        assert_eq!(2, tuner.failed(2));
+    }
+
+    #[test]
+    fn test_stall_detector() {
+        let now = Instant::now();
+        let window = Duration::from_secs(2);
+        let mut detector = StallDetector::new(1000, window, now);
+
+        // the speed is only checked once the window is over
+        assert!(detector.update(100, now + Duration::from_secs(1)).is_ok());
+        assert!(detector.update(2000, now + window).is_ok());
+
+        // a new window started, the bytes of the previous one do not count
+        let err = detector.update(1000, now + window * 2).err().unwrap();
+        assert_eq!(
+            "transfer stalled, speed 500 B/s below limit 1000 B/s for 2 seconds",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn test_check_deadline() {
+        let now = Instant::now();
+        assert!(check_deadline(None).is_ok());
+        assert!(check_deadline(Some(now + Duration::from_secs(60))).is_ok());
+        let err = check_deadline(Some(now)).err().unwrap();
+        assert_eq!("operation timed out (max time reached)", err.to_string());
+    }
+
+    #[test]
+    fn test_client_timeouts() {
+        // timeouts too large to be counted in milliseconds are capped, not overflowing
+        let secs = u64::MAX.to_string();
+        let url = "http://a.test/file";
+        let args = [
+            "fget",
+            url,
+            "--connect-timeout",
+            &secs,
+            "--read-timeout",
+            "5",
+        ];
+        let cfg = Config::parse_from(args);
+        let transport: Arc<dyn Transport> = Arc::new(MemTransport::new());
+        let urlinfo = UrlInfo::parse(url).unwrap();
+        let http_cfg = client_builder(&cfg, &transport, &urlinfo, 0, "probe").config();
+        assert_eq!(Duration::from_millis(u64::MAX), http_cfg.connect_timeout());
+        assert_eq!(Duration::from_secs(5), http_cfg.read_timeout());
+    }
+
+    #[test]
+    fn test_client_bind() {
+        // parts take the local addresses and the interfaces in turn, each list on its own
+        let url = "http://a.test/file";
+        let transport: Arc<dyn Transport> = Arc::new(MemTransport::new());
+        let urlinfo = UrlInfo::parse(url).unwrap();
+        let args = [
+            "fget",
+            url,
+            "--bind-address",
+            "10.0.0.1",
+            "--bind-address",
+            "10.0.0.2",
+            "--interface",
+            "eth0",
+            "--interface",
+            "eth1",
+            "--interface",
+            "wlan0",
+        ];
+        let cfg = Config::parse_from(args);
+        let expected = [
+            ("10.0.0.1", "eth0"),
+            ("10.0.0.2", "eth1"),
+            ("10.0.0.1", "wlan0"),
+            ("10.0.0.2", "eth0"),
+        ];
+        for (idx, (addr, interface)) in expected.into_iter().enumerate() {
+            let http_cfg = client_builder(&cfg, &transport, &urlinfo, idx, "part").config();
+            assert_eq!(Some(addr.parse().unwrap()), http_cfg.bind_addr());
+            assert_eq!(Some(interface), http_cfg.interface());
+        }
+
+        let cfg = Config::parse_from(["fget", url]);
+        let http_cfg = client_builder(&cfg, &transport, &urlinfo, 3, "part").config();
+        assert_eq!(None, http_cfg.bind_addr());
+        assert_eq!(None, http_cfg.interface());
+    }
+
+    #[test]
+    fn test_feeder() {
+        // a feeder is stopped and done when dropped, it writes nothing afterwards
+        let fed = Arc::new(AtomicUsize::new(0));
+        let counter = fed.clone();
+        let feeder = Feeder::spawn(move |cancel| {
+            while !cancel.load(Ordering::Relaxed) {
+                counter.fetch_add(1, Ordering::Relaxed);
+                thread::sleep(Duration::from_millis(1));
+            }
+        });
+        thread::sleep(Duration::from_millis(10));
+        drop(feeder);
+        let n = fed.load(Ordering::Relaxed);
+        assert!(n > 0);
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(n, fed.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_tail_watch() {
+        let now = Instant::now();
+        let mut watch = TailWatch::new(0, 1000, now);
+        assert!(!watch.slow(1000, 1100, now + HEDGE_WINDOW / 2));
+        assert!(!watch.slow(
+            1000,
+            1000 + 2000 * HEDGE_WINDOW.as_secs(),
+            now + HEDGE_WINDOW
+        ));
+
+        // a retried part starts over, the time it was failing does not count
+        let at = now + HEDGE_WINDOW * 3;
+        assert!(!watch.slow(1000, 0, at));
+        assert_eq!(at + HEDGE_WINDOW, watch.wakeup());
+        assert!(watch.slow(1000, 100, at + HEDGE_WINDOW));
     }
 
-    println!("Saving to: '{}'\r\n", urlinfo.fname);
-    download(&cfg, &urlinfo, &dlinfo, ob)
+    #[test]
+    fn test_part_split() {
+        let part = Part::new(0, 1000, 100_999);
+        part.remaining.lock().unwrap().pos += 1000;
+
+        let second = part.split(1, 40_000).unwrap();
+        assert_eq!(1, second.idx);
+        assert_eq!(51_500, second.start);
+        assert_eq!(100_999, second.remaining.lock().unwrap().end);
+        assert_eq!(51_499, part.remaining.lock().unwrap().end);
+        assert_eq!(49_500, part.remaining());
+
+        // halves would be too short
+        assert!(part.split(2, 40_000).is_none());
+    }
+
+    #[test]
+    fn test_download_hedged() {
+        let data = content(200_000);
+        let url = "http://a.test/hedged.bin";
+        let args = [
+            "-t",
+            "2",
+            "--min-split-size",
+            "100000",
+            "--hedge-speed",
+            "10K",
+        ];
+
+        // part 1 stalls after 30000 bytes, the rest of it is requested again and the
+        // hedge wins
+        let transport = MemTransport::new()
+            .serve(url, &data)
+            .fault(Some(100_000), Fault::Stall(30_000, Duration::from_secs(5)));
+        let started = Instant::now();
+        let downloaded = mem_download(transport.clone(), url, &args);
+        assert_eq!(data, downloaded.unwrap());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(
+            Some(&"GET a.test:80/hedged.bin 130000-199999".to_string()),
+            transport.requests().last()
+        );
+
+        // stalled before any data: the hedge requests the whole part
+        let transport = MemTransport::new()
+            .serve(url, &data)
+            .fault(Some(100_000), Fault::Stall(0, Duration::from_secs(5)));
+        let downloaded = mem_download(transport.clone(), url, &args);
+        assert_eq!(data, downloaded.unwrap());
+        let requests = transport.requests();
+        let part = "GET a.test:80/hedged.bin 100000-199999".to_string();
+        assert_eq!(2, requests.iter().filter(|req| **req == part).count());
+
+        // not hedged if fast enough
+        let transport = MemTransport::new().serve(url, &data);
+        let downloaded = mem_download(transport.clone(), url, &args);
+        assert_eq!(data, downloaded.unwrap());
+        assert_eq!(3, transport.requests().len());
+    }
+
+    #[test]
+    fn test_num_parts() {
+        let cfg = |args: &[&str]| Config::parse_from(["fget", "http://a.test/"].iter().chain(args));
+        let mib = 1024 * 1024;
+
+        assert_eq!(4, num_parts(&cfg(&[]), 100 * mib));
+        assert_eq!(1, num_parts(&cfg(&[]), 40_000));
+        assert_eq!(2, num_parts(&cfg(&[]), 2 * mib + 1));
+        assert_eq!(13, num_parts(&cfg(&["--split-size", "8M"]), 100 * mib));
+        assert_eq!(2, num_parts(&cfg(&["-t", "auto"]), 100 * mib));
+        assert_eq!(
+            3,
+            num_parts(&cfg(&["-t", "auto", "--min-threads", "3"]), 100 * mib)
+        );
+        assert_eq!(
+            10,
+            num_parts(
+                &cfg(&["--split-size", "4K", "--min-split-size", "10K"]),
+                100 * 1024
+            )
+        );
+    }
+
+    #[test]
+    fn test_download_auto_threads() {
+        let data = content(400_000);
+        let url = "http://a.test/auto.bin";
+        let args = [
+            "-t",
+            "auto",
+            "--min-threads",
+            "1",
+            "--max-threads",
+            "2",
+            "--min-split-size",
+            "50000",
+        ];
+
+        // a single slow connection, a second one takes over the second half of the
+        // rest after the first window
+        let transport = MemTransport::new().serve(url, &data).fault(
+            Some(0),
+            Fault::Stall(10_000, TUNE_WINDOW + Duration::from_millis(500)),
+        );
+        let downloaded = mem_download(transport.clone(), url, &args);
+        assert_eq!(data, downloaded.unwrap());
+        let requests = transport.requests();
+        assert!(requests.contains(&"GET a.test:80/auto.bin 0-399999".to_string()));
+        assert!(requests.contains(&"GET a.test:80/auto.bin 205000-399999".to_string()));
+
+        // done before the first window is over
+        let transport = MemTransport::new().serve(url, &data);
+        let downloaded = mem_download(transport.clone(), url, &args);
+        assert_eq!(data, downloaded.unwrap());
+        assert_eq!(2, transport.requests().len());
+    }
+
+    #[test]
+    fn test_download_rate_limit() {
+        let data = content(100_000);
+        let url = "http://a.test/limited.bin";
+        let parts = ["-t", "2", "--min-split-size", "50000"];
+
+        // both parts share 200KB/s: 80KB past the initial burst take 400ms
+        let transport = MemTransport::new().serve(url, &data);
+        let args = [&parts[..], &["--limit-rate", "200000"]].concat();
+        let started = Instant::now();
+        let downloaded = mem_download(transport.clone(), url, &args);
+        assert_eq!(data, downloaded.unwrap());
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(350), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+        assert_eq!(3, transport.requests().len());
+
+        // 100KB/s for every connection: 45KB past the burst of a part take 450ms
+        let transport = MemTransport::new().serve(url, &data);
+        let args = [&parts[..], &["--limit-conn-rate", "100000"]].concat();
+        let started = Instant::now();
+        let downloaded = mem_download(transport, url, &args);
+        assert_eq!(data, downloaded.unwrap());
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(400), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn test_download_progress() {
+        /// last progress and length of the part of every worker
+        #[derive(Default)]
+        struct Recorder(Vec<(u64, u64)>);
+
+        impl DownloadObserver for Recorder {
+            fn on_init(&mut self, len: usize) {
+                self.0 = vec![(0, 0); len];
+            }
+            fn on_download_start(&mut self, idx: usize, len: Option<u64>, _src: &ConnAddr) {
+                self.0[idx] = (0, len.unwrap_or_default());
+            }
+            fn on_progress(&mut self, idx: usize, pos: u64) {
+                self.0[idx].0 = pos;
+            }
+            fn on_throttled(&mut self, _idx: usize, _wait: Duration) {}
+            fn on_split(&mut self, _idx: usize, _len: u64) {}
+            fn on_download_end(&mut self, _idx: usize) {}
+        }
+
+        let data = content(100_000);
+        let url = "http://a.test/progress.bin";
+        let urlinfo = UrlInfo::parse(url).unwrap();
+        let transport: Arc<dyn Transport> = Arc::new(MemTransport::new().serve(url, &data));
+        let args = ["fget", url, "-t", "2", "--min-split-size", "50000"];
+        let cfg = Config::parse_from(args);
+        let client = build_client(&cfg, &transport, &urlinfo, 0, "probe").unwrap();
+        let probe = probe_file(&cfg, &transport, &urlinfo, client).unwrap();
+
+        // progress is sampled from the counters of parts, the last sample is complete
+        let mut recorder = Recorder::default();
+        let (fpaths, _) = download_parts(
+            &cfg,
+            &transport,
+            &urlinfo,
+            &probe.dlinfo,
+            None,
+            None,
+            None,
+            &mut recorder,
+        )
+        .unwrap();
+        fpaths
+            .iter()
+            .for_each(|fpath| fs::remove_file(fpath).unwrap());
+        assert_eq!(vec![(50_000, 50_000), (50_000, 50_000)], recorder.0);
+    }
+
+    #[test]
+    fn test_download_to_stdout() {
+        let data = content(100_000);
+        let url = "http://a.test/stdout.bin";
+        let urlinfo = UrlInfo::parse(url).unwrap();
+        let dir = std::env::temp_dir();
+        let output = dir.join("stdout.bin.out");
+
+        // spill files are in the temporary directory, one per buffer
+        let spill = spill_path(&urlinfo);
+        assert_ne!(spill, spill_path(&urlinfo));
+        let spill = Path::new(&spill);
+        assert_eq!(Some(dir.as_path()), spill.parent());
+        assert!(spill
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with("stdout.bin."));
+
+        // the first part is late: the next ones are kept meanwhile, in memory then on disk
+        let transport = MemTransport::new()
+            .serve(url, &data)
+            .fault(Some(0), Fault::Stall(0, Duration::from_millis(300)));
+        let transport: Arc<dyn Transport> = Arc::new(transport);
+        let args = [
+            "fget",
+            url,
+            "-o",
+            "-",
+            "-t",
+            "4",
+            "--min-split-size",
+            "25000",
+        ];
+        let cfg = Config::parse_from(args);
+        let client = build_client(&cfg, &transport, &urlinfo, 0, "probe").unwrap();
+        let probe = probe_file(&cfg, &transport, &urlinfo, client).unwrap();
+        let stdout = ReorderBuffer::new(
+            Box::new(File::create(&output).unwrap()),
+            30_000,
+            spill.to_str().unwrap(),
+        );
+        let stdout = Arc::new(stdout);
+
+        let (fpaths, _) = download_parts(
+            &cfg,
+            &transport,
+            &urlinfo,
+            &probe.dlinfo,
+            None,
+            None,
+            Some(&stdout),
+            &mut NoopObserver,
+        )
+        .unwrap();
+        assert!(fpaths.is_empty());
+        assert!(spill.exists());
+        assert_eq!(100_000, stdout.finish().unwrap());
+        drop(stdout);
+        assert!(!spill.exists());
+        assert_eq!(data, fs::read(&output).unwrap());
+        fs::remove_file(output).unwrap();
+    }
+
+    #[test]
+    fn test_download_split_size() {
+        let data = content(100_000);
+        let url = "http://a.test/split.bin";
+
+        // eight parts pulled by two workers
+        let transport = MemTransport::new().serve(url, &data);
+        let args = [
+            "-t",
+            "2",
+            "--split-size",
+            "12500",
+            "--min-split-size",
+            "10000",
+        ];
+        let downloaded = mem_download(transport.clone(), url, &args);
+        assert_eq!(data, downloaded.unwrap());
+
+        let requests = transport.requests();
+        assert_eq!(9, requests.len());
+        for i in 0..8 {
+            let range = format!(
+                "GET a.test:80/split.bin {}-{}",
+                i * 12500,
+                i * 12500 + 12499
+            );
+            assert!(requests.contains(&range), "{}", range);
+        }
+    }
+
+    #[test]
+    fn test_download_work_stealing() {
+        let data = content(2 * 1024 * 1024);
+        let url = "http://a.test/steal.bin";
+
+        // part 1 is slow: part 0, once done, takes over its second half
+        let transport = MemTransport::new().serve(url, &data).fault(
+            Some(1024 * 1024),
+            Fault::Stall(0, Duration::from_millis(500)),
+        );
+        let downloaded = mem_download(
+            transport.clone(),
+            url,
+            &["-t", "2", "--min-split-size", "512K"],
+        );
+        assert_eq!(data, downloaded.unwrap());
+
+        let mut requests = transport.requests();
+        requests[1..3].sort();
+        assert_eq!(
+            vec![
+                "HEAD a.test:80/steal.bin -",
+                "GET a.test:80/steal.bin 0-1048575",
+                "GET a.test:80/steal.bin 1048576-2097151",
+                "GET a.test:80/steal.bin 1572864-2097151",
+            ],
+            requests
+        );
+    }
+
+    #[test]
+    fn test_download_truncated() {
+        let data = content(100_000);
+        let url = "http://a.test/truncated.bin";
+
+        let transport = MemTransport::new()
+            .serve(url, &data)
+            .fault(Some(25_000), Fault::Truncate(1000));
+        let err =
+            mem_download(transport, url, &["-t", "4", "--min-split-size", "25000"]).unwrap_err();
+        assert_eq!(
+            "download failed at part 1: connection closed after 1000 of 25000 bytes",
+            err.to_string()
+        );
+    }
 }
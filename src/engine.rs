@@ -0,0 +1,1838 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    io::{self, Read, Write},
+    mem,
+    sync::{
+        mpsc::{self, Receiver, Sender, TryRecvError},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use fget::{
+    httpparse::{try_parse_response_head, ChunkedDecoder, ResponseHead},
+    make_error, PError,
+};
+use http::{header, Version};
+use mio::{Events, Interest, Poll, Registry, Token, Waker};
+use native_tls::{HandshakeError, MidHandshakeTlsStream, TlsStream};
+use quinn_proto::{Dir, StreamId};
+
+use crate::{
+    altsvc, h2, h3, hsts,
+    httpx::{
+        format_request_head, get_request, get_request_head, tls_connector, ConnAddr, HttpConfig,
+        HttpError, HttpHeaders, NbStream, ReadWrite, RedirectPolicy, Timings, MAX_ERROR_BODY_LEN,
+    },
+    quic,
+    ratelimit::{self, RateLimit},
+    trace,
+    urlinfo::UrlInfo,
+};
+
+const WAKER: Token = Token(usize::MAX);
+/// maximum time between two checks of timeouts and handler timers
+const TICK: Duration = Duration::from_millis(100);
+const READ_BUF_SIZE: usize = 16 * 1024;
+/// number of times a request refused by an HTTP/2 or HTTP/3 server (before any processing)
+/// is retried
+const MAX_REFUSED_RETRIES: u8 = 3;
+
+/// Callbacks of a request driven by the engine, all of them are called from an engine thread
+pub trait Handler: Send {
+    /// connection to server is established, called again after every redirect
+    fn on_connect(&mut self, local_addr: &ConnAddr, remote_addr: &ConnAddr);
+    /// final (not redirect) successful response received, an error aborts the request
+    fn on_response(&mut self, head: &ResponseHead, timings: &Timings) -> Result<(), PError>;
+    /// part of body received, returns false once all expected data has been received
+    fn on_data(&mut self, data: &[u8]) -> Result<bool, PError>;
+    /// called regularly while the request is in flight, an error aborts the request
+    fn on_tick(&mut self) -> Result<(), PError>;
+    /// request is over: Ok once the whole body (or all the handler wanted) was received
+    fn on_end(&mut self, res: Result<(), PError>);
+}
+
+/// A GET request to be run by the engine
+pub struct Job {
+    pub urlinfo: UrlInfo,
+    pub headers: HttpHeaders,
+    pub cfg: HttpConfig,
+    pub handler: Box<dyn Handler>,
+}
+
+impl Job {
+    /// identify the HTTP/2 connections this request may be sent on, if any
+    fn h2_key(&self) -> Option<String> {
+        let urlinfo = &self.urlinfo;
+        if !urlinfo.is_tls() || !self.cfg.http2() {
+            return None;
+        }
+
+        Some(format!(
+            "{}/{}/{}",
+            urlinfo.host_addr(),
+            urlinfo.domain,
+            self.cfg.conn_key()
+        ))
+    }
+
+    /// identify the HTTP/3 connections this request may be sent on, with the authority
+    /// (host:port) they connect to, if HTTP/3 is to be tried for it
+    fn h3_target(&self) -> Option<(String, String)> {
+        let urlinfo = &self.urlinfo;
+        if !urlinfo.is_tls() || !self.cfg.http3() {
+            return None;
+        }
+
+        let authority = altsvc::h3_authority(urlinfo)?;
+        let key = format!("{}/{}/{}", authority, urlinfo.domain, self.cfg.conn_key());
+        Some((key, authority))
+    }
+}
+
+struct Worker {
+    jobs: Sender<Job>,
+    waker: Waker,
+    handle: JoinHandle<()>,
+}
+
+/// Event loop based HTTP engine: a small fixed set of threads, each one driving many
+/// non-blocking connections (plain and TLS) using readiness events. Requests to a server
+/// speaking HTTP/2 are multiplexed as streams on one (or a few) connections, as well as
+/// requests sent over HTTP/3 (QUIC) when enabled.
+pub struct Engine {
+    workers: Vec<Worker>,
+    next: usize,
+    affinity: HashMap<String, usize>, // thread of requests which may share a connection
+}
+
+impl Engine {
+    /// start engine with given number of threads (at least one)
+    pub fn new(threads: usize) -> Result<Engine, PError> {
+        let mut workers = vec![];
+        for i in 0..threads.max(1) {
+            let poll = Poll::new()?;
+            let waker = Waker::new(poll.registry(), WAKER)?;
+            let (jobs, receiver) = mpsc::channel();
+            let handle = thread::Builder::new()
+                .name(format!("engine-{}", i))
+                .spawn(move || EventLoop::new(poll).run(receiver))?;
+
+            workers.push(Worker {
+                jobs,
+                waker,
+                handle,
+            });
+        }
+
+        Ok(Engine {
+            workers,
+            next: 0,
+            affinity: HashMap::new(),
+        })
+    }
+
+    /// run a request on one of the engine threads (round-robin, except that requests which
+    /// may share an HTTP/2 or HTTP/3 connection go to the same thread), the outcome is
+    /// reported to the request's handler
+    pub fn submit(&mut self, job: Job) -> Result<(), PError> {
+        let key = job.h3_target().map(|(key, _)| key).or_else(|| job.h2_key());
+        let idx = match key {
+            Some(key) => *self.affinity.entry(key).or_insert_with(|| {
+                self.next += 1;
+                self.next - 1
+            }),
+            None => {
+                self.next += 1;
+                self.next - 1
+            }
+        };
+        let worker = &self.workers[idx % self.workers.len()];
+
+        worker
+            .jobs
+            .send(job)
+            .map_err(|_| make_error("engine thread is gone"))?;
+        worker.waker.wake()?;
+
+        Ok(())
+    }
+}
+
+impl Drop for Engine {
+    /// stop all threads, requests still in flight are dropped
+    fn drop(&mut self) {
+        for worker in self.workers.drain(..) {
+            let Worker {
+                jobs,
+                waker,
+                handle,
+            } = worker;
+            drop(jobs);
+            let _ = waker.wake();
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Requests and connections driven by one engine thread
+struct EventLoop {
+    poll: Poll,
+    requests: HashMap<Token, Request>, // requests on their own (HTTP/1.1) connection
+    sessions: HashMap<Token, Session>, // HTTP/2 connections
+    h3_sessions: HashMap<Token, H3Session>, // HTTP/3 connections
+    candidates: HashMap<String, Token>, // connection being set up, maybe for HTTP/2
+    waiting: HashMap<Token, Vec<Request>>, // requests waiting for a candidate connection
+    h1_hosts: HashSet<String>,         // keys of servers which did not choose HTTP/2
+    woken: Vec<Token>,                 // to be driven on next turn, without any event
+    next_token: usize,
+}
+
+impl EventLoop {
+    fn new(poll: Poll) -> Self {
+        EventLoop {
+            poll,
+            requests: HashMap::new(),
+            sessions: HashMap::new(),
+            h3_sessions: HashMap::new(),
+            candidates: HashMap::new(),
+            waiting: HashMap::new(),
+            h1_hosts: HashSet::new(),
+            woken: vec![],
+            next_token: 0,
+        }
+    }
+
+    fn run(mut self, jobs: Receiver<Job>) {
+        let mut events = Events::with_capacity(256);
+        let mut last_tick = Instant::now();
+
+        loop {
+            loop {
+                match jobs.try_recv() {
+                    Ok(job) => self.dispatch(Request::new(job)),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return, // engine is dropped
+                }
+            }
+
+            // streams that never block have no readiness events, they are driven on every turn
+            // unless their reads are paused by a rate limit
+            let mut ready = mem::take(&mut self.woken);
+            ready.extend(
+                self.requests
+                    .iter()
+                    .filter(|(_, r)| r.ready && r.paused.is_none())
+                    .map(|(t, _)| *t),
+            );
+            ready.extend(
+                self.sessions
+                    .iter()
+                    .filter(|(_, s)| s.ready && s.paused.is_none())
+                    .map(|(t, _)| *t),
+            );
+            // QUIC connections have timers of their own (retransmissions, acks), connections
+            // paused by a rate limit are driven again once it allows reading
+            let deadline = self
+                .h3_sessions
+                .values_mut()
+                .filter_map(|s| s.quic.deadline())
+                .chain(self.requests.values().filter_map(|r| r.paused))
+                .chain(self.sessions.values().filter_map(|s| s.paused))
+                .min();
+            let timeout = match deadline {
+                _ if !ready.is_empty() => Duration::ZERO,
+                Some(deadline) => deadline.saturating_duration_since(Instant::now()).min(TICK),
+                None => TICK,
+            };
+            if let Err(err) = self.poll.poll(&mut events, Some(timeout)) {
+                if err.kind() != io::ErrorKind::Interrupted {
+                    self.abort(&format!("event loop failed: {}", err));
+                    return;
+                }
+            }
+            ready.extend(events.iter().map(|e| e.token()).filter(|t| *t != WAKER));
+            let now = Instant::now();
+            ready.extend(
+                self.h3_sessions
+                    .iter_mut()
+                    .filter_map(|(t, s)| s.quic.deadline().filter(|d| *d <= now).map(|_| *t)),
+            );
+            ready.extend(
+                self.requests
+                    .iter()
+                    .filter(|(_, r)| r.paused.is_some_and(|at| at <= now))
+                    .map(|(t, _)| *t),
+            );
+            ready.extend(
+                self.sessions
+                    .iter()
+                    .filter(|(_, s)| s.paused.is_some_and(|at| at <= now))
+                    .map(|(t, _)| *t),
+            );
+
+            for token in ready {
+                self.drive(token);
+            }
+
+            if last_tick.elapsed() >= TICK {
+                last_tick = Instant::now();
+                self.tick();
+            }
+
+            self.close_idle_sessions();
+        }
+    }
+
+    /// start a request: as a new stream of an HTTP/3 connection if it is to be tried, or
+    /// of an HTTP/2 connection to its server if there is one (or if one may be being set
+    /// up), otherwise on its own connection
+    fn dispatch(&mut self, mut req: Request) {
+        if let Some((key, authority)) = req.job.h3_target() {
+            req = match self.dispatch_h3(req, key, &authority) {
+                Some(req) => req,
+                None => return,
+            };
+        }
+
+        let key = req.job.h2_key().filter(|key| !self.h1_hosts.contains(key));
+        if let Some(key) = &key {
+            let session = self
+                .sessions
+                .iter_mut()
+                .find(|(_, s)| s.key == *key && s.can_open());
+            if let Some((token, session)) = session {
+                session.open(req);
+                self.woken.push(*token);
+                return;
+            }
+            if let Some(token) = self.candidates.get(key) {
+                self.waiting.entry(*token).or_default().push(req);
+                return;
+            }
+        }
+
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        req.alpn = match key {
+            Some(_) => Alpn::Offered,
+            None => Alpn::Off,
+        };
+        match req.connect(self.poll.registry(), token) {
+            Ok(()) => {
+                if let Some(key) = key {
+                    self.candidates.insert(key, token);
+                }
+                self.requests.insert(token, req);
+                self.woken.push(token);
+            }
+            Err(err) => req.job.handler.on_end(Err(err)),
+        }
+    }
+
+    /// start a request as a stream of an HTTP/3 connection to given authority, set up if
+    /// needed, returns the request back if no connection can be set up
+    fn dispatch_h3(&mut self, req: Request, key: String, authority: &str) -> Option<Request> {
+        let session = self
+            .h3_sessions
+            .iter_mut()
+            .find(|(_, s)| s.key == key && s.can_queue());
+        if let Some((token, session)) = session {
+            session.queue(req);
+            self.woken.push(*token);
+            return None;
+        }
+
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        let res = H3Session::connect(key, authority, &req).and_then(|mut session| {
+            let interest = Interest::READABLE | Interest::WRITABLE;
+            self.poll
+                .registry()
+                .register(session.quic.socket(), token, interest)?;
+            Ok(session)
+        });
+        match res {
+            Ok(mut session) => {
+                session.queue(req);
+                self.h3_sessions.insert(token, session);
+                self.woken.push(token);
+                None
+            }
+            Err(err) => {
+                fall_back_to_tcp(&req, authority, &err.to_string());
+                Some(req)
+            }
+        }
+    }
+
+    fn drive(&mut self, token: Token) {
+        if let Some(mut req) = self.requests.remove(&token) {
+            let outcome = req.drive();
+            self.handle_outcome(outcome, req, token);
+        } else if let Some(session) = self.sessions.get_mut(&token) {
+            let done = session.drive();
+            if session.conn.refuses_streams() {
+                // requests to this server go over connections of their own
+                self.h1_hosts.insert(session.key.clone());
+            }
+            for (req, outcome) in done {
+                self.finish(req, outcome);
+            }
+        } else if let Some(session) = self.h3_sessions.get_mut(&token) {
+            for (req, outcome) in session.drive() {
+                self.finish(req, outcome);
+            }
+        }
+    }
+
+    /// check timeouts and handler timers
+    fn tick(&mut self) {
+        let tokens: Vec<Token> = self.requests.keys().copied().collect();
+        for token in tokens {
+            if let Some(mut req) = self.requests.remove(&token) {
+                let outcome = req.tick();
+                self.handle_outcome(outcome, req, token);
+            }
+        }
+
+        let tokens: Vec<Token> = self.sessions.keys().copied().collect();
+        for token in tokens {
+            if let Some(session) = self.sessions.get_mut(&token) {
+                for (req, outcome) in session.tick() {
+                    self.finish(req, outcome);
+                }
+            }
+        }
+
+        let tokens: Vec<Token> = self.h3_sessions.keys().copied().collect();
+        for token in tokens {
+            if let Some(session) = self.h3_sessions.get_mut(&token) {
+                for (req, outcome) in session.tick() {
+                    self.finish(req, outcome);
+                }
+            }
+        }
+    }
+
+    /// handle outcome of a request on its own connection
+    fn handle_outcome(&mut self, outcome: Outcome, mut req: Request, token: Token) {
+        match outcome {
+            Outcome::Pending => {
+                if req.alpn == Alpn::Http1 {
+                    // server chose HTTP/1.1, requests waiting for it go on their own
+                    req.alpn = Alpn::Off;
+                    if let Some(key) = req.job.h2_key() {
+                        self.h1_hosts.insert(key);
+                    }
+                    self.release(token);
+                }
+                self.requests.insert(token, req);
+            }
+            Outcome::Http2(rw) => self.start_session(token, req, rw),
+            outcome => {
+                self.release(token);
+                self.finish(req, outcome);
+            }
+        }
+    }
+
+    /// request is over on its connection (or stream)
+    fn finish(&mut self, mut req: Request, outcome: Outcome) {
+        match outcome {
+            Outcome::Redirect(urlinfo) => {
+                req.job.urlinfo = urlinfo;
+                self.dispatch(req);
+            }
+            Outcome::Retry => self.dispatch(req),
+            Outcome::End(res) => req.job.handler.on_end(res),
+            Outcome::Pending | Outcome::Http2(_) => unreachable!("request is not over"),
+        }
+    }
+
+    /// connection of req did not end up as an HTTP/2 connection, requests waiting for it
+    /// are dispatched again
+    fn release(&mut self, token: Token) {
+        self.candidates.retain(|_, t| *t != token);
+        for req in self.waiting.remove(&token).unwrap_or_default() {
+            self.dispatch(req);
+        }
+    }
+
+    /// server chose HTTP/2 on the connection of req: it carries req and the requests
+    /// waiting for it as streams, as many as the server allows
+    fn start_session(&mut self, token: Token, req: Request, rw: Box<dyn ReadWrite>) {
+        let key = req.job.h2_key().unwrap_or_default();
+        self.candidates.remove(&key);
+
+        let mut session = Session::new(key, rw, &req);
+        let mut reqs = vec![req];
+        reqs.extend(self.waiting.remove(&token).unwrap_or_default());
+
+        let mut rest = vec![];
+        for req in reqs {
+            if session.can_open() {
+                session.open(req);
+            } else {
+                rest.push(req);
+            }
+        }
+        self.sessions.insert(token, session);
+        self.woken.push(token);
+
+        for req in rest {
+            self.dispatch(req);
+        }
+    }
+
+    /// close HTTP/2 and HTTP/3 connections which are broken or carry no stream anymore
+    fn close_idle_sessions(&mut self) {
+        self.sessions.retain(|_, session| {
+            if session.closed {
+                return false;
+            }
+            if session.streams.is_empty() {
+                session.close();
+                return false;
+            }
+            true
+        });
+        self.h3_sessions.retain(|_, session| {
+            if session.closed {
+                return false;
+            }
+            if session.streams.is_empty() && session.queued.is_empty() {
+                session.quic.close(h3::H3_NO_ERROR);
+                return false;
+            }
+            true
+        });
+    }
+
+    /// end all requests with given error
+    fn abort(&mut self, msg: &str) {
+        let mut reqs: Vec<Request> = self.requests.drain().map(|(_, req)| req).collect();
+        for (_, mut session) in self.sessions.drain() {
+            reqs.extend(session.streams.drain().map(|(_, stream)| stream.req));
+        }
+        for (_, mut session) in self.h3_sessions.drain() {
+            reqs.extend(session.streams.drain().map(|(_, (stream, _))| stream.req));
+            reqs.extend(session.queued.drain(..));
+        }
+        reqs.extend(self.waiting.drain().flat_map(|(_, reqs)| reqs));
+
+        for mut req in reqs {
+            req.job.handler.on_end(Err(make_error(msg)));
+        }
+    }
+}
+
+enum Phase {
+    Connecting(NbStream),
+    Handshaking(MidHandshakeTlsStream<NbStream>),
+    Sending(Box<dyn ReadWrite>, Vec<u8>, usize), // (stream, request head, bytes sent)
+    ReadingHead(Box<dyn ReadWrite>, Vec<u8>),    // (stream, bytes received so far)
+    ReadingBody(Box<dyn ReadWrite>),
+    ReadingErrorBody(Box<dyn ReadWrite>, HttpError),
+    Closed,
+}
+
+enum Step {
+    Next(Phase), // continue with next phase
+    Wait(Phase), // stream would block, wait for readiness in this phase
+    Done(Outcome),
+}
+
+enum Outcome {
+    Pending,
+    Http2(Box<dyn ReadWrite>), // server chose HTTP/2 on the connection (tls stream)
+    Redirect(UrlInfo),
+    Retry, // request was not processed by server, it can be sent again
+    End(Result<(), PError>),
+}
+
+/// What to do with the body of a response
+enum Body {
+    Skip(Outcome), // request is over (redirect, error without body, empty body)
+    Keep,          // pass it to handler
+    KeepError(HttpError),
+}
+
+/// Protocol negotiation (ALPN) on the tls connection of a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Alpn {
+    Off,     // HTTP/1.1 only
+    Offered, // HTTP/2 offered during handshake
+    Http1,   // HTTP/2 offered but server chose HTTP/1.1
+}
+
+/// State of a request driven by the engine
+struct Request {
+    job: Job,
+    phase: Phase,
+    ready: bool, // stream never blocks
+    alpn: Alpn,
+    redirects_left: Option<u8>,
+    refused: u8, // number of times the request was refused by an HTTP/2 server
+    local_addr: ConnAddr,
+    remote_addr: ConnAddr,
+    timings: Timings,
+    phase_start: Instant,
+    last_activity: Instant,
+    body_len: Option<u64>, // expected length of (kept part of) body, None if until close
+    received: u64,
+    chunked: Option<ChunkedDecoder>, // decoder of body sent in chunks
+    limits: Vec<Arc<RateLimit>>,     // rate limits of reads on the connection
+    paused: Option<Instant>,         // reads paused by a rate limit until then
+}
+
+impl Request {
+    fn new(mut job: Job) -> Self {
+        hsts::upgrade(&mut job.urlinfo);
+        let redirects_left = match job.cfg.redirect_policy() {
+            RedirectPolicy::Follow(max) => Some(max),
+            RedirectPolicy::None => None,
+        };
+        let unknown = ConnAddr::Inet(([0, 0, 0, 0], 0).into());
+
+        Request {
+            job,
+            phase: Phase::Closed,
+            ready: false,
+            alpn: Alpn::Off,
+            redirects_left,
+            refused: 0,
+            local_addr: unknown.clone(),
+            remote_addr: unknown,
+            timings: Timings::default(),
+            phase_start: Instant::now(),
+            last_activity: Instant::now(),
+            body_len: None,
+            received: 0,
+            chunked: None,
+            limits: vec![],
+            paused: None,
+        }
+    }
+
+    /// start connecting to the request's host
+    fn connect(&mut self, registry: &Registry, token: Token) -> Result<(), PError> {
+        let cfg = &self.job.cfg;
+        let conn = cfg
+            .transport()
+            .connect_nonblocking(&self.job.urlinfo.host_addr(), &cfg.conn_config())?;
+        self.timings = conn.timings;
+        self.local_addr = conn.local_addr;
+        self.remote_addr = conn.remote_addr;
+        self.body_len = None;
+        self.received = 0;
+        self.chunked = None;
+        self.limits = cfg.rate_limits();
+
+        let mut stream = conn.stream;
+        self.ready = match stream.source() {
+            Some(source) => {
+                registry.register(source, token, Interest::READABLE | Interest::WRITABLE)?;
+                false
+            }
+            None => true,
+        };
+
+        self.phase_start = Instant::now();
+        self.last_activity = Instant::now();
+        self.phase = Phase::Connecting(stream);
+
+        Ok(())
+    }
+
+    /// make as much progress as possible without blocking
+    fn drive(&mut self) -> Outcome {
+        self.paused = None;
+        let mut phase = mem::replace(&mut self.phase, Phase::Closed);
+        loop {
+            match self.advance(phase) {
+                Ok(Step::Next(next)) => phase = next,
+                Ok(Step::Wait(current)) => {
+                    self.phase = current;
+                    return Outcome::Pending;
+                }
+                Ok(Step::Done(outcome)) => return outcome,
+                Err(err) => return Outcome::End(Err(err)),
+            }
+        }
+    }
+
+    /// check timeouts and handler timers
+    fn tick(&mut self) -> Outcome {
+        let cfg = &self.job.cfg;
+        let timed_out = match self.phase {
+            Phase::Connecting(_) | Phase::Handshaking(_) => {
+                self.phase_start.elapsed() > cfg.connect_timeout()
+            }
+            _ if self.paused.is_some() => false, // not reading on purpose
+            _ => self.last_activity.elapsed() > cfg.read_timeout(),
+        };
+        if timed_out {
+            return Outcome::End(Err(make_error("operation timed out")));
+        }
+
+        match self.job.handler.on_tick() {
+            Ok(()) => Outcome::Pending,
+            Err(err) => Outcome::End(Err(err)),
+        }
+    }
+
+    fn advance(&mut self, phase: Phase) -> Result<Step, PError> {
+        match phase {
+            Phase::Connecting(stream) => {
+                if !stream.is_connected()? {
+                    return Ok(Step::Wait(Phase::Connecting(stream)));
+                }
+
+                self.timings.connect = self.phase_start.elapsed();
+                self.phase_start = Instant::now();
+                self.job
+                    .handler
+                    .on_connect(&self.local_addr, &self.remote_addr);
+
+                if !self.job.urlinfo.is_tls() {
+                    return self.start_sending(Box::new(stream));
+                }
+                let mut connector = tls_connector();
+                if self.alpn == Alpn::Offered {
+                    connector.request_alpns(&["h2", "http/1.1"]);
+                }
+                let res = connector.build()?.connect(&self.job.urlinfo.domain, stream);
+                self.handshaken(res)
+            }
+            Phase::Handshaking(mid) => {
+                let res = mid.handshake();
+                self.handshaken(res)
+            }
+            Phase::Sending(mut rw, data, mut pos) => {
+                while pos < data.len() {
+                    match rw.write(&data[pos..]) {
+                        Ok(0) => return Err(make_error("connection closed while sending request")),
+                        Ok(n) => {
+                            pos += n;
+                            self.last_activity = Instant::now();
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            return Ok(Step::Wait(Phase::Sending(rw, data, pos)))
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+
+                self.phase_start = Instant::now();
+                Ok(Step::Next(Phase::ReadingHead(rw, vec![])))
+            }
+            Phase::ReadingHead(mut rw, mut buf) => {
+                let mut chunk = [0u8; READ_BUF_SIZE];
+                loop {
+                    let n = match self.read(&mut rw, &mut chunk)? {
+                        Some(n) => n,
+                        None => return Ok(Step::Wait(Phase::ReadingHead(rw, buf))),
+                    };
+                    if n == 0 {
+                        return Err(make_error("connection closed before response was received"));
+                    }
+                    if buf.is_empty() {
+                        self.timings.ttfb = self.phase_start.elapsed();
+                    }
+
+                    buf.extend_from_slice(&chunk[..n]);
+                    if let Some((head, len)) = try_parse_response_head(&buf)? {
+                        let rest = buf.split_off(len);
+                        return self.received_head(rw, head, &rest);
+                    }
+                }
+            }
+            Phase::ReadingBody(mut rw) => {
+                let mut chunk = [0u8; READ_BUF_SIZE];
+                loop {
+                    let n = match self.read(&mut rw, &mut chunk)? {
+                        Some(n) => n,
+                        None => return Ok(Step::Wait(Phase::ReadingBody(rw))),
+                    };
+                    if n == 0 {
+                        if self.chunked.as_ref().is_some_and(|c| !c.is_done()) {
+                            return Err(make_error("connection closed before end of chunked body"));
+                        }
+                        return match self.body_len {
+                            Some(len) if self.received < len => Err(make_error(
+                                format!(
+                                    "connection closed after {} of {} bytes",
+                                    self.received, len
+                                )
+                                .as_str(),
+                            )),
+                            _ => Ok(Step::Done(Outcome::End(Ok(())))),
+                        };
+                    }
+                    if self.feed(&chunk[..n])? {
+                        return Ok(Step::Done(Outcome::End(Ok(()))));
+                    }
+                }
+            }
+            Phase::ReadingErrorBody(mut rw, mut err) => {
+                let mut chunk = [0u8; READ_BUF_SIZE];
+                loop {
+                    let n = match self.read(&mut rw, &mut chunk)? {
+                        Some(n) => n,
+                        None => return Ok(Step::Wait(Phase::ReadingErrorBody(rw, err))),
+                    };
+                    if n == 0 || self.keep_error_body(&mut err, &chunk[..n]) {
+                        return Ok(Step::Done(Outcome::End(Err(Box::new(err)))));
+                    }
+                }
+            }
+            Phase::Closed => Err(make_error("connection is closed")),
+        }
+    }
+
+    fn handshaken(
+        &mut self,
+        res: Result<TlsStream<NbStream>, HandshakeError<NbStream>>,
+    ) -> Result<Step, PError> {
+        match res {
+            Ok(stream) => {
+                self.timings.tls = self.phase_start.elapsed();
+                if self.alpn == Alpn::Offered {
+                    if stream.negotiated_alpn()?.as_deref() == Some(&b"h2"[..]) {
+                        return Ok(Step::Done(Outcome::Http2(Box::new(stream))));
+                    }
+                    self.alpn = Alpn::Http1;
+                }
+                self.start_sending(Box::new(stream))
+            }
+            Err(HandshakeError::WouldBlock(mid)) => Ok(Step::Wait(Phase::Handshaking(mid))),
+            Err(HandshakeError::Failure(err)) => Err(err.into()),
+        }
+    }
+
+    fn start_sending(&mut self, rw: Box<dyn ReadWrite>) -> Result<Step, PError> {
+        let urlinfo = &self.job.urlinfo;
+        let cfg = &self.job.cfg;
+        let head = get_request_head(&urlinfo.path, &urlinfo.host_addr(), cfg, &self.job.headers)?;
+        trace::debug_request(cfg.label(), &head);
+
+        // trace decrypted data in case of tls
+        let rw = trace::wrap(cfg.label(), rw);
+        Ok(Step::Next(Phase::Sending(rw, head.into_bytes(), 0)))
+    }
+
+    fn received_head(
+        &mut self,
+        rw: Box<dyn ReadWrite>,
+        head: ResponseHead,
+        rest: &[u8],
+    ) -> Result<Step, PError> {
+        let closing = head.version == Version::HTTP_10
+            || head
+                .header("connection")
+                .map(|conn| conn.eq_ignore_ascii_case("close"))
+                .unwrap_or_default();
+
+        match self.on_head(head, closing)? {
+            Body::Skip(outcome) => Ok(Step::Done(outcome)),
+            Body::Keep if !rest.is_empty() && self.feed(rest)? => {
+                Ok(Step::Done(Outcome::End(Ok(()))))
+            }
+            Body::Keep => Ok(Step::Next(Phase::ReadingBody(rw))),
+            Body::KeepError(mut err) => {
+                if self.keep_error_body(&mut err, rest) {
+                    return Ok(Step::Done(Outcome::End(Err(Box::new(err)))));
+                }
+                Ok(Step::Next(Phase::ReadingErrorBody(rw, err)))
+            }
+        }
+    }
+
+    /// handle final response head, delimited tells whether the end of body is signaled
+    /// (connection closed or end of stream) so that the body length may be unknown
+    fn on_head(&mut self, head: ResponseHead, delimited: bool) -> Result<Body, PError> {
+        trace::debug_response(self.job.cfg.label(), &head);
+        if self.job.urlinfo.is_tls() {
+            if let Some(sts) = head.header("strict-transport-security") {
+                hsts::record(&self.job.urlinfo.domain, sts);
+            }
+            if let Some(alt_svc) = head.header("alt-svc") {
+                altsvc::record(&self.job.urlinfo.host_addr(), alt_svc);
+            }
+        }
+
+        let content_len = head
+            .header("content-length")
+            .and_then(|len| len.parse::<u64>().ok());
+        let is_chunked = head
+            .header("transfer-encoding")
+            .map(|te| te.to_ascii_lowercase().contains("chunked"))
+            .unwrap_or_default();
+
+        let status = head.status.as_u16();
+        if status / 100 >= 4 {
+            // keep a bounded part of the body, whose end must be known (or up to close)
+            self.chunked = is_chunked.then(ChunkedDecoder::new);
+            self.body_len = match (is_chunked, content_len) {
+                (true, _) => Some(MAX_ERROR_BODY_LEN),
+                (false, Some(len)) => Some(len.min(MAX_ERROR_BODY_LEN)),
+                (false, None) if delimited => Some(MAX_ERROR_BODY_LEN),
+                (false, None) => Some(0),
+            };
+            let err = HttpError {
+                status: head.status,
+                headers: head.headers,
+                body: vec![],
+            };
+            if self.body_len == Some(0) {
+                return Ok(Body::Skip(Outcome::End(Err(Box::new(err)))));
+            }
+            return Ok(Body::KeepError(err));
+        }
+        if status / 100 == 3 {
+            return self.redirect(&head).map(Body::Skip);
+        }
+
+        self.job.handler.on_response(&head, &self.timings)?;
+
+        // the length of a chunked body is only known at its end
+        self.chunked = is_chunked.then(ChunkedDecoder::new);
+        self.body_len = content_len.filter(|_| !is_chunked);
+        if self.body_len == Some(0) {
+            return Ok(Body::Skip(Outcome::End(Ok(()))));
+        }
+
+        Ok(Body::Keep)
+    }
+
+    fn redirect(&mut self, head: &ResponseHead) -> Result<Outcome, PError> {
+        let left = match self.redirects_left {
+            Some(left) => left,
+            None => return Err(make_error("redirect is not supported")),
+        };
+        if left == 0 {
+            return Err(make_error("max redirects exceeded"));
+        }
+
+        let location = head.header("location").ok_or_else(|| {
+            make_error(
+                format!(
+                    "server return {} but no location header was found",
+                    head.status.as_u16()
+                )
+                .as_str(),
+            )
+        })?;
+        let mut urlinfo = UrlInfo::parse(location)?;
+        hsts::upgrade(&mut urlinfo);
+        self.redirects_left = Some(left - 1);
+
+        Ok(Outcome::Redirect(urlinfo))
+    }
+
+    /// pass body data to handler, returns true once the body is complete
+    fn feed(&mut self, data: &[u8]) -> Result<bool, PError> {
+        let mut decoded = vec![];
+        let data = match (&mut self.chunked, self.body_len) {
+            (Some(decoder), _) => {
+                decoder.decode(data, &mut decoded)?;
+                &decoded[..]
+            }
+            (None, Some(len)) => &data[..data.len().min((len - self.received) as usize)],
+            (None, None) => data,
+        };
+
+        self.received += data.len() as u64;
+        let more = data.is_empty() || self.job.handler.on_data(data)?;
+
+        let complete = match &self.chunked {
+            Some(decoder) => decoder.is_done(),
+            None => Some(self.received) == self.body_len,
+        };
+        Ok(!more || complete)
+    }
+
+    /// keep (a bounded part of) error body, returns true once it is complete
+    fn keep_error_body(&mut self, err: &mut HttpError, data: &[u8]) -> bool {
+        let mut decoded = vec![];
+        let data = match &mut self.chunked {
+            Some(decoder) => match decoder.decode(data, &mut decoded) {
+                Ok(_) => &decoded[..],
+                Err(_) => return true, // what was decoded so far is kept
+            },
+            None => data,
+        };
+
+        let len = self.body_len.unwrap_or(MAX_ERROR_BODY_LEN);
+        let n = data.len().min((len - self.received) as usize);
+        err.body.extend_from_slice(&data[..n]);
+        self.received += n as u64;
+        self.received == len || self.chunked.as_ref().is_some_and(|c| c.is_done())
+    }
+
+    /// read from stream, returns None if it would block or a rate limit is reached
+    fn read(&mut self, rw: &mut Box<dyn ReadWrite>, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        let res = read_limited(rw, buf, &self.limits, &mut self.paused)?;
+        if res.is_some() {
+            self.last_activity = Instant::now();
+        }
+
+        Ok(res)
+    }
+}
+
+/// read from a non-blocking stream, returns None if it would block
+fn read_nonblocking(rw: &mut Box<dyn ReadWrite>, buf: &mut [u8]) -> io::Result<Option<usize>> {
+    loop {
+        match rw.read(buf) {
+            Ok(n) => return Ok(Some(n)),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// read from a non-blocking stream no more than rate limits allow, returns None if it
+/// would block or if a limit is reached, reads are paused until given time then
+fn read_limited(
+    rw: &mut Box<dyn ReadWrite>,
+    buf: &mut [u8],
+    limits: &[Arc<RateLimit>],
+    paused: &mut Option<Instant>,
+) -> io::Result<Option<usize>> {
+    let n = match ratelimit::take(limits, buf.len(), Instant::now()) {
+        Ok(n) => n,
+        Err(at) => {
+            *paused = Some(at);
+            return Ok(None);
+        }
+    };
+
+    let res = read_nonblocking(rw, &mut buf[..n]);
+    let read = match res {
+        Ok(Some(read)) => read,
+        _ => 0,
+    };
+    ratelimit::give_back(limits, n - read);
+    res
+}
+
+/// An HTTP/2 connection carrying requests as concurrent streams
+struct Session {
+    key: String,
+    rw: Box<dyn ReadWrite>,
+    ready: bool, // stream never blocks
+    conn: h2::Connection,
+    out: Vec<u8>, // data not sent yet
+    streams: HashMap<u32, Stream>,
+    local_addr: ConnAddr,
+    remote_addr: ConnAddr,
+    reused: bool, // whether a request was sent already, following ones have no setup time
+    limits: Vec<Arc<RateLimit>>, // rate limits of reads on the connection
+    paused: Option<Instant>, // reads paused by a rate limit until then
+    closed: bool,
+}
+
+/// A request sent as a stream of an HTTP/2 or HTTP/3 connection
+struct Stream {
+    req: Request,
+    phase: StreamPhase,
+}
+
+enum StreamPhase {
+    Head(Instant), // waiting for response head, since time request was sent
+    Body,
+    ErrorBody(HttpError),
+}
+
+impl Session {
+    /// session on the (tls) connection set up by req
+    fn new(key: String, rw: Box<dyn ReadWrite>, req: &Request) -> Self {
+        Session {
+            key,
+            rw: trace::wrap(req.job.cfg.label(), rw),
+            ready: req.ready,
+            conn: h2::Connection::new(),
+            out: vec![],
+            streams: HashMap::new(),
+            local_addr: req.local_addr.clone(),
+            remote_addr: req.remote_addr.clone(),
+            reused: false,
+            limits: req.limits.clone(),
+            paused: None,
+            closed: false,
+        }
+    }
+
+    fn can_open(&self) -> bool {
+        !self.closed && self.conn.can_open()
+    }
+
+    /// send request as a new stream
+    fn open(&mut self, mut req: Request) {
+        match self.send(&mut req) {
+            Ok(id) => {
+                let phase = StreamPhase::Head(Instant::now());
+                self.streams.insert(id, Stream { req, phase });
+            }
+            Err(err) => req.job.handler.on_end(Err(err)),
+        }
+    }
+
+    fn send(&mut self, req: &mut Request) -> Result<u32, PError> {
+        let urlinfo = &req.job.urlinfo;
+        let cfg = &req.job.cfg;
+        let mut request = get_request(&urlinfo.path, &urlinfo.host_addr(), cfg, &req.job.headers)?;
+        *request.version_mut() = Version::HTTP_2;
+        request.headers_mut().remove(header::CONNECTION);
+        trace::debug_request(cfg.label(), &format_request_head(&request)?);
+        let id = self.conn.send_request(&request, "https")?;
+
+        if mem::replace(&mut self.reused, true) {
+            req.timings = Timings::default();
+            req.local_addr = self.local_addr.clone();
+            req.remote_addr = self.remote_addr.clone();
+            req.job
+                .handler
+                .on_connect(&self.local_addr, &self.remote_addr);
+        }
+        req.body_len = None;
+        req.received = 0;
+        req.last_activity = Instant::now();
+
+        Ok(id)
+    }
+
+    /// exchange as much data as possible without blocking, returns requests which are over
+    fn drive(&mut self) -> Vec<(Request, Outcome)> {
+        let mut done = vec![];
+        self.paused = None;
+        if let Err(err) = self.pump(&mut done) {
+            // connection is unusable, all its streams fail
+            self.closed = true;
+            let _ = self.flush(); // a goaway telling why, best effort
+            let msg = err.to_string();
+            for (_, stream) in self.streams.drain() {
+                done.push((stream.req, Outcome::End(Err(make_error(msg.as_str())))));
+            }
+        }
+
+        done
+    }
+
+    fn pump(&mut self, done: &mut Vec<(Request, Outcome)>) -> Result<(), PError> {
+        let mut chunk = [0u8; READ_BUF_SIZE];
+        loop {
+            self.flush()?;
+            let n = match read_limited(&mut self.rw, &mut chunk, &self.limits, &mut self.paused)? {
+                Some(n) => n,
+                None => return Ok(()),
+            };
+            if n == 0 {
+                return Err(make_error("connection closed before end of stream"));
+            }
+
+            for event in self.conn.recv(&chunk[..n])? {
+                let id = event.stream_id();
+                if let Some(mut stream) = self.streams.remove(&id) {
+                    match stream.on_event(event.into()) {
+                        None => {
+                            self.streams.insert(id, stream);
+                        }
+                        Some(outcome) => {
+                            self.conn.reset(id);
+                            done.push((stream.req, outcome));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// write pending data, until it would block
+    fn flush(&mut self) -> Result<(), PError> {
+        self.out.extend(self.conn.take_output());
+        while !self.out.is_empty() {
+            match self.rw.write(&self.out) {
+                Ok(0) => return Err(make_error("connection closed while sending request")),
+                Ok(n) => {
+                    self.out.drain(..n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// check timeouts and handler timers of all streams, returns requests which are over
+    fn tick(&mut self) -> Vec<(Request, Outcome)> {
+        let mut done = vec![];
+        let ids: Vec<u32> = self.streams.keys().copied().collect();
+        for id in ids {
+            let outcome = match self.streams.get_mut(&id) {
+                Some(stream) => {
+                    // streams are not idle while the connection is paused by a rate limit
+                    stream.req.paused = self.paused;
+                    stream.req.tick()
+                }
+                None => continue,
+            };
+            if matches!(outcome, Outcome::Pending) {
+                continue;
+            }
+            if let Some(stream) = self.streams.remove(&id) {
+                self.conn.reset(id);
+                done.push((stream.req, outcome));
+            }
+        }
+
+        // errors show up on next read
+        let _ = self.flush();
+        done
+    }
+
+    /// tell server the connection is closed, best effort
+    fn close(&mut self) {
+        self.conn.close();
+        let _ = self.flush();
+    }
+}
+
+/// An HTTP/3 connection (over QUIC) carrying requests as concurrent streams
+struct H3Session {
+    key: String,
+    authority: String, // host:port connected to
+    quic: quic::Connection,
+    connected: bool,
+    started: Instant,
+    connect_timeout: Duration,
+    timings: Timings, // connection setup, reported by requests sent once connected
+    queued: VecDeque<Request>, // waiting for the connection to be set up or for a stream
+    streams: HashMap<StreamId, (Stream, h3::ResponseStream)>,
+    uni: HashMap<StreamId, h3::UniStream>, // opened by server
+    local_addr: ConnAddr,
+    remote_addr: ConnAddr,
+    going_away: bool,
+    closed: bool,
+}
+
+impl H3Session {
+    /// start connecting to given authority, for requests like req
+    fn connect(key: String, authority: &str, req: &Request) -> Result<Self, PError> {
+        let cfg = &req.job.cfg;
+        let (quic, dns) = quic::Connection::connect(authority, &req.job.urlinfo.domain, cfg)?;
+
+        Ok(H3Session {
+            key,
+            authority: authority.to_string(),
+            local_addr: ConnAddr::Inet(quic.local_addr()?),
+            remote_addr: ConnAddr::Inet(quic.remote_addr()),
+            quic,
+            connected: false,
+            started: Instant::now(),
+            connect_timeout: cfg.connect_timeout(),
+            timings: Timings {
+                dns,
+                ..Default::default()
+            },
+            queued: VecDeque::new(),
+            streams: HashMap::new(),
+            uni: HashMap::new(),
+            going_away: false,
+            closed: false,
+        })
+    }
+
+    /// whether more requests can be sent on the connection
+    fn can_queue(&self) -> bool {
+        !self.closed && !self.going_away
+    }
+
+    /// send request as soon as a stream can be opened
+    fn queue(&mut self, req: Request) {
+        self.queued.push_back(req);
+    }
+
+    /// exchange as much data as possible without blocking, returns requests which are over
+    fn drive(&mut self) -> Vec<(Request, Outcome)> {
+        let mut done = vec![];
+        if let Err(err) = self.pump(&mut done) {
+            self.fail(&err.to_string(), &mut done);
+        }
+
+        done
+    }
+
+    fn pump(&mut self, done: &mut Vec<(Request, Outcome)>) -> Result<(), PError> {
+        for event in self.quic.drive()? {
+            match event {
+                quinn_proto::Event::Connected => {
+                    self.connected = true;
+                    self.timings.tls = self.started.elapsed(); // QUIC handshake includes tls
+                    self.quic.open_uni(&h3::control_stream())?;
+                }
+                quinn_proto::Event::ConnectionLost { reason } => {
+                    return Err(make_error(
+                        format!("quic connection lost: {}", reason).as_str(),
+                    ));
+                }
+                quinn_proto::Event::Stream(quinn_proto::StreamEvent::Opened { dir: Dir::Uni }) => {
+                    while let Some(id) = self.quic.accept_uni() {
+                        self.uni.insert(id, h3::UniStream::new());
+                        self.recv(id, done)?;
+                    }
+                }
+                quinn_proto::Event::Stream(quinn_proto::StreamEvent::Readable { id }) => {
+                    self.recv(id, done)?;
+                }
+                _ => {}
+            }
+        }
+
+        if self.connected {
+            self.open_streams(done);
+        }
+        self.quic.flush()
+    }
+
+    /// send queued requests on new streams, as many as the server allows
+    fn open_streams(&mut self, done: &mut Vec<(Request, Outcome)>) {
+        while let Some(mut req) = self.queued.pop_front() {
+            match self.send(&mut req) {
+                Ok(Some(id)) => {
+                    let stream = Stream {
+                        req,
+                        phase: StreamPhase::Head(Instant::now()),
+                    };
+                    self.streams.insert(id, (stream, h3::ResponseStream::new()));
+                }
+                Ok(None) => {
+                    self.queued.push_front(req);
+                    break;
+                }
+                Err(err) => done.push((req, Outcome::End(Err(err)))),
+            }
+        }
+
+        // only the first requests waited for the connection to be set up
+        self.timings = Timings::default();
+    }
+
+    fn send(&mut self, req: &mut Request) -> Result<Option<StreamId>, PError> {
+        let urlinfo = &req.job.urlinfo;
+        let cfg = &req.job.cfg;
+        let mut request = get_request(&urlinfo.path, &urlinfo.host_addr(), cfg, &req.job.headers)?;
+        *request.version_mut() = Version::HTTP_3;
+        request.headers_mut().remove(header::CONNECTION);
+        let id = match self.quic.open_bi(&h3::encode_request(&request, "https")?)? {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        trace::debug_request(cfg.label(), &format_request_head(&request)?);
+
+        req.timings = self.timings;
+        req.local_addr = self.local_addr.clone();
+        req.remote_addr = self.remote_addr.clone();
+        req.job
+            .handler
+            .on_connect(&self.local_addr, &self.remote_addr);
+        req.body_len = None;
+        req.received = 0;
+        req.last_activity = Instant::now();
+
+        Ok(Some(id))
+    }
+
+    /// handle data received on a stream
+    fn recv(&mut self, id: StreamId, done: &mut Vec<(Request, Outcome)>) -> Result<(), PError> {
+        if let Some(uni) = self.uni.get_mut(&id) {
+            let read = self.quic.read(id);
+            if let Some(goaway) = uni.recv(&read.data)? {
+                self.go_away(goaway, done);
+            }
+            if read.end || read.reset.is_some() {
+                self.uni.remove(&id);
+            }
+            return Ok(());
+        }
+
+        let (mut stream, mut response) = match self.streams.remove(&id) {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
+        let read = self.quic.read(id);
+        let mut events: Vec<StreamEvent> = match response.recv(&read.data) {
+            Ok(events) => events.into_iter().map(StreamEvent::from).collect(),
+            Err(err) => {
+                self.quic.stop(id, h3::H3_REQUEST_CANCELLED);
+                done.push((stream.req, Outcome::End(Err(err))));
+                return Ok(());
+            }
+        };
+        if read.end {
+            events.push(StreamEvent::End);
+        }
+        if let Some(code) = read.reset {
+            events.push(StreamEvent::Reset {
+                reason: format!("stream reset by server: {}", h3::error_name(code)),
+                retryable: code == h3::H3_REQUEST_REJECTED,
+            });
+        }
+
+        for event in events {
+            if let Some(outcome) = stream.on_event(event) {
+                self.quic.stop(id, h3::H3_REQUEST_CANCELLED);
+                done.push((stream.req, outcome));
+                return Ok(());
+            }
+        }
+        self.streams.insert(id, (stream, response));
+
+        Ok(())
+    }
+
+    /// server is going away, requests it will not process are sent again
+    fn go_away(&mut self, first_unprocessed: u64, done: &mut Vec<(Request, Outcome)>) {
+        self.going_away = true;
+        let ids: Vec<StreamId> = self
+            .streams
+            .keys()
+            .filter(|id| u64::from(**id) >= first_unprocessed)
+            .copied()
+            .collect();
+        for id in ids {
+            if let Some((stream, _)) = self.streams.remove(&id) {
+                done.push((stream.req, Outcome::Retry));
+            }
+        }
+        done.extend(self.queued.drain(..).map(|req| (req, Outcome::Retry)));
+    }
+
+    /// connection is unusable: if it was never set up, its requests go over tcp, otherwise
+    /// those which got no response yet are sent again
+    fn fail(&mut self, msg: &str, done: &mut Vec<(Request, Outcome)>) {
+        self.closed = true;
+        if !self.connected {
+            for req in self.queued.drain(..) {
+                fall_back_to_tcp(&req, &self.authority, msg);
+                done.push((req, Outcome::Retry));
+            }
+            return;
+        }
+
+        let mut reqs: Vec<Request> = self.queued.drain(..).collect();
+        for (_, (stream, _)) in self.streams.drain() {
+            match stream.phase {
+                StreamPhase::Head(_) => reqs.push(stream.req),
+                _ => done.push((stream.req, Outcome::End(Err(make_error(msg))))),
+            }
+        }
+        for mut req in reqs {
+            if req.refused < MAX_REFUSED_RETRIES {
+                req.refused += 1;
+                done.push((req, Outcome::Retry));
+            } else {
+                done.push((req, Outcome::End(Err(make_error(msg)))));
+            }
+        }
+    }
+
+    /// check setup timeout, and timeouts and handler timers of all streams, returns
+    /// requests which are over
+    fn tick(&mut self) -> Vec<(Request, Outcome)> {
+        let mut done = vec![];
+        if !self.connected && self.started.elapsed() > self.connect_timeout {
+            self.fail("quic handshake timed out", &mut done);
+            return done;
+        }
+
+        let ids: Vec<StreamId> = self.streams.keys().copied().collect();
+        for id in ids {
+            let outcome = match self.streams.get_mut(&id) {
+                Some((stream, _)) => stream.req.tick(),
+                None => continue,
+            };
+            if matches!(outcome, Outcome::Pending) {
+                continue;
+            }
+            if let Some((stream, _)) = self.streams.remove(&id) {
+                self.quic.stop(id, h3::H3_REQUEST_CANCELLED);
+                done.push((stream.req, outcome));
+            }
+        }
+
+        // errors show up on next drive
+        let _ = self.quic.flush();
+        done
+    }
+}
+
+/// HTTP/3 could not be used for req at given authority (no QUIC server, udp blocked), it
+/// goes over tcp, like all following requests to this authority
+fn fall_back_to_tcp(req: &Request, authority: &str, reason: &str) {
+    altsvc::mark_broken(authority);
+    trace::debug_info(
+        req.job.cfg.label(),
+        &format!("HTTP/3 failed ({}), falling back to tcp", reason),
+    );
+}
+
+/// What happened on a stream, as a result of data received on its connection
+enum StreamEvent {
+    Head(ResponseHead), // final (not interim) response head
+    Data(Vec<u8>),
+    End,
+    Reset { reason: String, retryable: bool }, // retryable if server did not process it
+}
+
+impl From<h2::Event> for StreamEvent {
+    fn from(event: h2::Event) -> Self {
+        match event {
+            h2::Event::Head(_, head) => StreamEvent::Head(head),
+            h2::Event::Data(_, data) => StreamEvent::Data(data),
+            h2::Event::End(_) => StreamEvent::End,
+            h2::Event::Reset {
+                reason, retryable, ..
+            } => StreamEvent::Reset { reason, retryable },
+        }
+    }
+}
+
+impl From<h3::Event> for StreamEvent {
+    fn from(event: h3::Event) -> Self {
+        match event {
+            h3::Event::Head(head) => StreamEvent::Head(head),
+            h3::Event::Data(data) => StreamEvent::Data(data),
+        }
+    }
+}
+
+impl Stream {
+    /// handle event of the stream, returns the outcome once the request is over
+    fn on_event(&mut self, event: StreamEvent) -> Option<Outcome> {
+        self.req.last_activity = Instant::now();
+        match self.advance(event) {
+            Ok(outcome) => outcome,
+            Err(err) => Some(Outcome::End(Err(err))),
+        }
+    }
+
+    fn advance(&mut self, event: StreamEvent) -> Result<Option<Outcome>, PError> {
+        let req = &mut self.req;
+        let phase = mem::replace(&mut self.phase, StreamPhase::Body);
+        match (phase, event) {
+            (StreamPhase::Head(sent), StreamEvent::Head(head)) => {
+                req.timings.ttfb = sent.elapsed();
+                match req.on_head(head, true)? {
+                    Body::Skip(outcome) => return Ok(Some(outcome)),
+                    Body::Keep => {}
+                    Body::KeepError(err) => self.phase = StreamPhase::ErrorBody(err),
+                }
+                Ok(None)
+            }
+            (StreamPhase::Body, StreamEvent::Data(data)) => {
+                if req.feed(&data)? {
+                    return Ok(Some(Outcome::End(Ok(()))));
+                }
+                Ok(None)
+            }
+            (StreamPhase::ErrorBody(mut err), StreamEvent::Data(data)) => {
+                if req.keep_error_body(&mut err, &data) {
+                    return Ok(Some(Outcome::End(Err(Box::new(err)))));
+                }
+                self.phase = StreamPhase::ErrorBody(err);
+                Ok(None)
+            }
+            (StreamPhase::Body, StreamEvent::End) => match req.body_len {
+                Some(len) if req.received < len => Err(make_error(
+                    format!("stream ended after {} of {} bytes", req.received, len).as_str(),
+                )),
+                _ => Ok(Some(Outcome::End(Ok(())))),
+            },
+            (StreamPhase::ErrorBody(err), StreamEvent::End) => {
+                Ok(Some(Outcome::End(Err(Box::new(err)))))
+            }
+            (StreamPhase::Head(_), StreamEvent::End) => {
+                Err(make_error("stream ended before response was received"))
+            }
+            (StreamPhase::Head(_), StreamEvent::Reset { retryable, .. })
+                if retryable && req.refused < MAX_REFUSED_RETRIES =>
+            {
+                req.refused += 1;
+                Ok(Some(Outcome::Retry))
+            }
+            (_, StreamEvent::Reset { reason, .. }) => Err(make_error(reason.as_str())),
+            (_, _) => Err(make_error("unexpected response on stream")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        h3server::{test_cert, H3Server},
+        httpx::{HttpClient, TcpTransport, Transport},
+    };
+    use native_tls::{Identity, TlsAcceptor};
+    use std::{net::TcpListener, sync::Arc};
+
+    /// serve given raw responses, one per connection, `{base}` is replaced by server url
+    fn serve(responses: &[&str]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let responses: Vec<String> = responses
+            .iter()
+            .map(|r| r.replace("{base}", &base))
+            .collect();
+
+        thread::spawn(move || {
+            for resp in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut req = vec![];
+                let mut buf = [0u8; 1024];
+                while !req.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).unwrap();
+                    req.extend_from_slice(&buf[..n]);
+                }
+                stream.write_all(resp.as_bytes()).unwrap();
+            }
+        });
+
+        base
+    }
+
+    /// collects body and reports (status, body, error) once the request is over
+    struct TestHandler {
+        status: u16,
+        body: Vec<u8>,
+        done: Sender<(u16, Vec<u8>, Option<String>)>,
+    }
+
+    impl Handler for TestHandler {
+        fn on_connect(&mut self, _local_addr: &ConnAddr, _remote_addr: &ConnAddr) {}
+
+        fn on_response(&mut self, head: &ResponseHead, _timings: &Timings) -> Result<(), PError> {
+            self.status = head.status.as_u16();
+            Ok(())
+        }
+
+        fn on_data(&mut self, data: &[u8]) -> Result<bool, PError> {
+            self.body.extend_from_slice(data);
+            Ok(true)
+        }
+
+        fn on_tick(&mut self) -> Result<(), PError> {
+            Ok(())
+        }
+
+        fn on_end(&mut self, res: Result<(), PError>) {
+            let body = mem::take(&mut self.body);
+            let _ = match res {
+                Ok(()) => self.done.send((self.status, body, None)),
+                Err(err) => match err.downcast_ref::<HttpError>() {
+                    Some(http_err) => {
+                        let status = http_err.status.as_u16();
+                        self.done.send((status, http_err.body.clone(), None))
+                    }
+                    None => self.done.send((self.status, body, Some(err.to_string()))),
+                },
+            };
+        }
+    }
+
+    /// like serve but over tls, with the certificate trusted by the test process
+    fn serve_tls(responses: &[&str]) -> String {
+        let cert = test_cert();
+        let identity = Identity::from_pkcs8(cert.cert_pem.as_bytes(), cert.key_pem.as_bytes());
+        let acceptor = TlsAcceptor::new(identity.unwrap()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!(
+            "https://localhost:{}",
+            listener.local_addr().unwrap().port()
+        );
+        let responses: Vec<String> = responses.iter().map(|r| r.to_string()).collect();
+
+        thread::spawn(move || {
+            for resp in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut stream = acceptor.accept(stream).unwrap();
+                let mut req = vec![];
+                let mut buf = [0u8; 1024];
+                while !req.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).unwrap();
+                    req.extend_from_slice(&buf[..n]);
+                }
+                stream.write_all(resp.as_bytes()).unwrap();
+            }
+        });
+
+        base
+    }
+
+    /// run requests for given urls, with given range if any, on one engine (trying HTTP/3
+    /// or not), returns (status, body, error) of each of them
+    fn run_all(reqs: &[(&str, Option<&str>)], http3: bool) -> Vec<(u16, Vec<u8>, Option<String>)> {
+        let mut engine = Engine::new(1).unwrap();
+        let transport: Arc<dyn Transport> = Arc::new(TcpTransport);
+        let mut results = vec![];
+        for (url, range) in reqs {
+            let urlinfo = UrlInfo::parse(url).unwrap();
+            let cfg = HttpClient::builder()
+                .from_url_info(&urlinfo)
+                .with_transport(transport.clone())
+                .with_http3(http3)
+                .config();
+            let mut headers = HttpHeaders::new();
+            if let Some(range) = range {
+                headers.insert("Range".to_string(), range.to_string());
+            }
+            let (done, result) = mpsc::channel();
+
+            engine
+                .submit(Job {
+                    urlinfo,
+                    headers,
+                    cfg,
+                    handler: Box::new(TestHandler {
+                        status: 0,
+                        body: vec![],
+                        done,
+                    }),
+                })
+                .unwrap();
+            results.push(result);
+        }
+
+        results
+            .into_iter()
+            .map(|result| result.recv_timeout(Duration::from_secs(10)).unwrap())
+            .collect()
+    }
+
+    fn run(url: &str) -> (u16, Vec<u8>, Option<String>) {
+        run_all(&[(url, None)], false).remove(0)
+    }
+
+    #[test]
+    fn test_redirect_and_body() {
+        let base = serve(&[
+            "HTTP/1.1 301 Moved Permanently\r\nLocation: {base}/file\r\nContent-Length: 0\r\n\r\n",
+            "HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 11\r\n\r\nhello world",
+        ]);
+
+        let (status, body, err) = run(&format!("{}/old", base));
+        assert_eq!(None, err);
+        assert_eq!(200, status);
+        assert_eq!(b"hello world", &body[..]);
+    }
+
+    #[test]
+    fn test_error_body_and_truncated_body() {
+        let base = serve(&[
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 7\r\n\r\nmissing",
+            "HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\nshort",
+        ]);
+
+        let (status, body, err) = run(&format!("{}/missing", base));
+        assert_eq!((404, &b"missing"[..], None), (status, &body[..], err));
+
+        let (_, body, err) = run(&format!("{}/short", base));
+        assert_eq!(b"short", &body[..]);
+        assert_eq!(
+            Some("connection closed after 5 of 100 bytes".to_string()),
+            err
+        );
+    }
+
+    #[test]
+    fn test_chunked_and_unknown_length_body() {
+        let base = serve(&[
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nConnection: close\r\n\r\nuntil close",
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhel",
+            "HTTP/1.1 503 Service Unavailable\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nbusy\r\n0\r\n\r\n",
+        ]);
+
+        let (_, body, err) = run(&format!("{}/chunked", base));
+        assert_eq!((&b"hello world"[..], None), (&body[..], err));
+
+        let (_, body, err) = run(&format!("{}/unknown", base));
+        assert_eq!((&b"until close"[..], None), (&body[..], err));
+
+        let (_, body, err) = run(&format!("{}/truncated", base));
+        assert_eq!(b"hel", &body[..]);
+        assert_eq!(
+            Some("connection closed before end of chunked body".to_string()),
+            err
+        );
+
+        let (status, body, err) = run(&format!("{}/busy", base));
+        assert_eq!((503, &b"busy"[..], None), (status, &body[..], err));
+    }
+
+    #[test]
+    fn test_http3_ranged_parts() {
+        let body: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let server = H3Server::start(&[("/file", &body)]);
+        let url = format!("https://localhost:{}/file", server.port());
+        let missing = format!("https://localhost:{}/missing", server.port());
+
+        let results = run_all(
+            &[
+                (&url, Some("bytes=0-49999")),
+                (&url, Some("bytes=50000-99999")),
+                (&url, Some("bytes=100000-199999")),
+                (&missing, None),
+            ],
+            true,
+        );
+        assert_eq!(
+            (206, &body[..50_000], None),
+            (results[0].0, &results[0].1[..], results[0].2.clone())
+        );
+        assert_eq!(
+            (206, &body[50_000..100_000], None),
+            (results[1].0, &results[1].1[..], results[1].2.clone())
+        );
+        assert_eq!(
+            (206, &body[100_000..], None),
+            (results[2].0, &results[2].1[..], results[2].2.clone())
+        );
+        assert_eq!(
+            (404, &b"not found"[..], None),
+            (results[3].0, &results[3].1[..], results[3].2.clone())
+        );
+
+        // all parts are streams of one connection
+        assert_eq!(1, server.connections());
+        assert_eq!(4, server.requests().len());
+    }
+
+    #[test]
+    fn test_http3_fallback_and_alt_svc() {
+        let server = H3Server::start(&[("/file", b"over http3")]);
+        let alt_svc = format!("h3=\":{}\"; ma=60", server.port());
+        let base = serve_tls(&[&format!(
+            "HTTP/1.1 200 OK\r\nAlt-Svc: {}\r\nContent-Length: 8\r\n\r\nover tcp",
+            alt_svc
+        )]);
+        let url = format!("{}/file", base);
+
+        // nothing listens on udp at the origin: request falls back to tcp, whose response
+        // advertises the HTTP/3 server, used by the following request
+        let (status, body, err) = run_all(&[(&url, None)], true).remove(0);
+        assert_eq!((200, &b"over tcp"[..], None), (status, &body[..], err));
+
+        let (status, body, err) = run_all(&[(&url, None)], true).remove(0);
+        assert_eq!((200, &b"over http3"[..], None), (status, &body[..], err));
+        assert_eq!(vec!["/file -".to_string()], server.requests());
+    }
+}
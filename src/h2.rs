@@ -0,0 +1,764 @@
+use std::{collections::HashMap, mem};
+
+use fget::{httpparse::ResponseHead, make_error, PError};
+use http::{header, HeaderName, Request, StatusCode, Version};
+
+/// client connection preface, followed by our SETTINGS frame
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const FRAME_HEAD_LEN: usize = 9;
+/// maximum frame size until the peer allows larger ones, we never allow more
+const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024;
+const MAX_FRAME_SIZE: usize = (1 << 24) - 1; // largest value of max frame size setting
+const DEFAULT_WINDOW: u32 = 65535;
+/// receive windows, large enough for a fast transfer not to wait for window updates
+const STREAM_WINDOW: u32 = 4 * 1024 * 1024;
+const CONN_WINDOW: u32 = 16 * 1024 * 1024;
+/// limit of concurrent streams assumed until the server tells its own
+const DEFAULT_MAX_STREAMS: usize = 100;
+const MAX_STREAM_ID: u32 = (1 << 31) - 1;
+const MAX_HEADER_BLOCK_LEN: usize = 256 * 1024;
+
+// frame types
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+// frame flags
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY: u8 = 0x20;
+
+// settings
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+
+// error codes
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const REFUSED_STREAM: u32 = 0x7;
+const CANCEL: u32 = 0x8;
+
+/// What happened on a stream, as a result of data received on the connection
+#[derive(Debug)]
+pub enum Event {
+    /// final (not interim) response head
+    Head(u32, ResponseHead),
+    /// part of response body
+    Data(u32, Vec<u8>),
+    /// response is complete
+    End(u32),
+    /// stream is aborted by server, it can be retried if server did not process it at all
+    Reset {
+        id: u32,
+        reason: String,
+        retryable: bool,
+    },
+}
+
+impl Event {
+    pub fn stream_id(&self) -> u32 {
+        match self {
+            Event::Head(id, _) | Event::Data(id, _) | Event::End(id) => *id,
+            Event::Reset { id, .. } => *id,
+        }
+    }
+}
+
+/// header block being received (HEADERS followed by CONTINUATION frames)
+struct HeaderBlock {
+    stream_id: u32,
+    data: Vec<u8>,
+    end_stream: bool,
+}
+
+/// Client side of an HTTP/2 connection (RFC 9113) sending GET requests as streams and
+/// receiving their responses. It does no io: received bytes are fed to `recv` and bytes
+/// to be sent are taken with `take_output`, so that it can be driven by an event loop.
+pub struct Connection {
+    out: Vec<u8>,
+    buf: Vec<u8>, // received data not parsed yet (incomplete frame)
+    decoder: hpack::Decoder<'static>,
+    next_id: u32,
+    streams: HashMap<u32, u32>, // open streams: id => bytes received not yet acknowledged
+    conn_unacked: u32,
+    max_streams: usize,
+    max_frame_size: usize, // of frames we send
+    header_block: Option<HeaderBlock>,
+    going_away: bool,
+}
+
+impl Connection {
+    pub fn new() -> Self {
+        let mut conn = Connection {
+            out: PREFACE.to_vec(),
+            buf: vec![],
+            decoder: hpack::Decoder::new(),
+            next_id: 1,
+            streams: HashMap::new(),
+            conn_unacked: 0,
+            max_streams: DEFAULT_MAX_STREAMS,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            header_block: None,
+            going_away: false,
+        };
+
+        let mut settings = vec![];
+        for (id, val) in [
+            (SETTINGS_ENABLE_PUSH, 0),
+            (SETTINGS_INITIAL_WINDOW_SIZE, STREAM_WINDOW),
+        ] {
+            settings.extend_from_slice(&id.to_be_bytes());
+            settings.extend_from_slice(&val.to_be_bytes());
+        }
+        conn.write_frame(SETTINGS, 0, 0, &settings);
+        conn.write_window_update(0, CONN_WINDOW - DEFAULT_WINDOW);
+
+        conn
+    }
+
+    /// whether a new stream can be opened now
+    pub fn can_open(&self) -> bool {
+        !self.going_away && self.streams.len() < self.max_streams && self.next_id <= MAX_STREAM_ID
+    }
+
+    /// whether the server allows no stream at all, requests must then be sent over
+    /// connections of their own
+    pub fn refuses_streams(&self) -> bool {
+        self.max_streams == 0
+    }
+
+    /// open a new stream sending given request (without body), returns stream id
+    pub fn send_request<T>(&mut self, req: &Request<T>, scheme: &str) -> Result<u32, PError> {
+        if !self.can_open() {
+            return Err(make_error(
+                "no more streams can be opened on this connection",
+            ));
+        }
+
+        let authority = match req.headers().get(header::HOST) {
+            Some(host) => host.to_str()?,
+            None => req
+                .uri()
+                .authority()
+                .map(|a| a.as_str())
+                .unwrap_or_default(),
+        };
+        let path = req
+            .uri()
+            .path_and_query()
+            .map(|p| p.as_str())
+            .unwrap_or("/");
+
+        let mut block = vec![];
+        encode_field(&mut block, b":method", req.method().as_str().as_bytes());
+        encode_field(&mut block, b":scheme", scheme.as_bytes());
+        encode_field(&mut block, b":authority", authority.as_bytes());
+        encode_field(&mut block, b":path", path.as_bytes());
+        for (key, val) in req.headers().iter() {
+            if !is_connection_header(key) {
+                encode_field(&mut block, key.as_str().as_bytes(), val.as_bytes());
+            }
+        }
+
+        let id = self.next_id;
+        self.next_id += 2;
+        self.streams.insert(id, 0);
+
+        // split header block in frames no larger than the server accepts
+        let chunks: Vec<&[u8]> = block.chunks(self.max_frame_size).collect();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let (kind, mut flags) = match i {
+                0 => (HEADERS, END_STREAM),
+                _ => (CONTINUATION, 0),
+            };
+            if i == chunks.len() - 1 {
+                flags |= END_HEADERS;
+            }
+            self.write_frame(kind, flags, id, chunk);
+        }
+
+        Ok(id)
+    }
+
+    /// cancel stream if it is still open, e.g. once the client got all it wanted
+    pub fn reset(&mut self, id: u32) {
+        if self.streams.remove(&id).is_some() {
+            self.write_frame(RST_STREAM, 0, id, &CANCEL.to_be_bytes());
+        }
+    }
+
+    /// tell server the connection is going to be closed
+    pub fn close(&mut self) {
+        self.go_away(NO_ERROR);
+    }
+
+    fn go_away(&mut self, code: u32) {
+        if !self.going_away {
+            self.going_away = true;
+            let mut payload = 0u32.to_be_bytes().to_vec(); // no pushed stream was processed
+            payload.extend_from_slice(&code.to_be_bytes());
+            self.write_frame(GOAWAY, 0, 0, &payload);
+        }
+    }
+
+    /// bytes to be sent to server
+    pub fn take_output(&mut self) -> Vec<u8> {
+        mem::take(&mut self.out)
+    }
+
+    /// handle data received from server, an error means the connection is unusable
+    pub fn recv(&mut self, data: &[u8]) -> Result<Vec<Event>, PError> {
+        let mut buf = mem::take(&mut self.buf);
+        buf.extend_from_slice(data);
+
+        let mut events = vec![];
+        let mut pos = 0;
+        while buf.len() - pos >= FRAME_HEAD_LEN {
+            let head = &buf[pos..pos + FRAME_HEAD_LEN];
+            let len = u32::from_be_bytes([0, head[0], head[1], head[2]]) as usize;
+            if len > DEFAULT_MAX_FRAME_SIZE {
+                return Err(make_error("http2 frame is larger than allowed"));
+            }
+            if buf.len() - pos - FRAME_HEAD_LEN < len {
+                break;
+            }
+
+            let (kind, flags) = (head[3], head[4]);
+            let id = u32::from_be_bytes([head[5], head[6], head[7], head[8]]) & MAX_STREAM_ID;
+            let payload = &buf[pos + FRAME_HEAD_LEN..pos + FRAME_HEAD_LEN + len];
+            self.recv_frame(kind, flags, id, payload, &mut events)?;
+            pos += FRAME_HEAD_LEN + len;
+        }
+
+        buf.drain(..pos);
+        self.buf = buf;
+
+        Ok(events)
+    }
+
+    fn recv_frame(
+        &mut self,
+        kind: u8,
+        flags: u8,
+        id: u32,
+        payload: &[u8],
+        events: &mut Vec<Event>,
+    ) -> Result<(), PError> {
+        if self.header_block.is_some() && kind != CONTINUATION {
+            return Err(make_error("http2 header block is interrupted"));
+        }
+
+        match kind {
+            DATA => {
+                if id == 0 {
+                    return Err(make_error("http2 data frame on connection stream"));
+                }
+                let data = unpad(flags, payload)?;
+
+                // flow control counts the whole payload, padding included
+                let len = payload.len() as u32;
+                self.conn_unacked += len;
+                if self.conn_unacked >= CONN_WINDOW / 2 {
+                    let n = mem::take(&mut self.conn_unacked);
+                    self.write_window_update(0, n);
+                }
+
+                // data of a stream we reset is ignored
+                let unacked = match self.streams.get_mut(&id) {
+                    Some(unacked) => unacked,
+                    None => return Ok(()),
+                };
+                *unacked += len;
+                let n = *unacked;
+                if flags & END_STREAM == 0 && n >= STREAM_WINDOW / 2 {
+                    *unacked = 0;
+                    self.write_window_update(id, n);
+                }
+
+                if !data.is_empty() {
+                    events.push(Event::Data(id, data.to_vec()));
+                }
+                if flags & END_STREAM != 0 {
+                    self.streams.remove(&id);
+                    events.push(Event::End(id));
+                }
+            }
+            HEADERS => {
+                let mut data = unpad(flags, payload)?;
+                if flags & PRIORITY != 0 {
+                    data = data
+                        .get(5..)
+                        .ok_or_else(|| make_error("invalid http2 headers frame"))?;
+                }
+                self.header_block = Some(HeaderBlock {
+                    stream_id: id,
+                    data: data.to_vec(),
+                    end_stream: flags & END_STREAM != 0,
+                });
+                if flags & END_HEADERS != 0 {
+                    self.end_header_block(events)?;
+                }
+            }
+            CONTINUATION => {
+                let block = match self.header_block.as_mut() {
+                    Some(block) if block.stream_id == id => block,
+                    _ => return Err(make_error("unexpected http2 continuation frame")),
+                };
+                block.data.extend_from_slice(payload);
+                if block.data.len() > MAX_HEADER_BLOCK_LEN {
+                    return Err(make_error("http2 header block is too large"));
+                }
+                if flags & END_HEADERS != 0 {
+                    self.end_header_block(events)?;
+                }
+            }
+            RST_STREAM => {
+                let code = read_u32(payload, 0)?;
+                if self.streams.remove(&id).is_some() {
+                    events.push(Event::Reset {
+                        id,
+                        reason: format!("stream reset by server ({})", error_name(code)),
+                        retryable: code == REFUSED_STREAM,
+                    });
+                }
+            }
+            SETTINGS if flags & ACK == 0 => {
+                if !payload.len().is_multiple_of(6) {
+                    return Err(make_error("invalid http2 settings frame"));
+                }
+                for setting in payload.chunks(6) {
+                    let val = read_u32(setting, 2)?;
+                    match u16::from_be_bytes([setting[0], setting[1]]) {
+                        SETTINGS_MAX_CONCURRENT_STREAMS => self.max_streams = val as usize,
+                        SETTINGS_MAX_FRAME_SIZE => {
+                            if !(DEFAULT_MAX_FRAME_SIZE..=MAX_FRAME_SIZE).contains(&(val as usize))
+                            {
+                                self.go_away(PROTOCOL_ERROR);
+                                return Err(make_error(&format!(
+                                    "invalid http2 max frame size setting ({})",
+                                    val
+                                )));
+                            }
+                            self.max_frame_size = val as usize;
+                        }
+                        _ => {}
+                    }
+                }
+                self.write_frame(SETTINGS, ACK, 0, &[]);
+
+                // streams sent before the server told it allows none are not processed
+                if self.refuses_streams() {
+                    let mut ids: Vec<u32> = self.streams.drain().map(|(id, _)| id).collect();
+                    ids.sort_unstable();
+                    for id in ids {
+                        events.push(Event::Reset {
+                            id,
+                            reason: "server allows no stream".to_string(),
+                            retryable: true,
+                        });
+                    }
+                }
+            }
+            PUSH_PROMISE => return Err(make_error("http2 server push is not supported")),
+            PING if flags & ACK == 0 => self.write_frame(PING, ACK, 0, payload),
+            GOAWAY => {
+                // streams the server did not (and will not) process can be retried
+                let last_id = read_u32(payload, 0)? & MAX_STREAM_ID;
+                self.going_away = true;
+
+                let mut ids: Vec<u32> = self.streams.keys().copied().collect();
+                ids.retain(|id| *id > last_id);
+                ids.sort_unstable();
+                for id in ids {
+                    self.streams.remove(&id);
+                    events.push(Event::Reset {
+                        id,
+                        reason: "connection is going away".to_string(),
+                        retryable: true,
+                    });
+                }
+            }
+            // we never send data, so window updates of server are useless, and frames
+            // of unknown type must be ignored
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn end_header_block(&mut self, events: &mut Vec<Event>) -> Result<(), PError> {
+        let block = match self.header_block.take() {
+            Some(block) => block,
+            None => return Ok(()),
+        };
+
+        // the block must be decoded even for an unknown stream to keep decoder state in sync
+        let fields = self
+            .decoder
+            .decode(&block.data)
+            .map_err(|err| make_error(format!("invalid http2 header block: {:?}", err).as_str()))?;
+
+        let id = block.stream_id;
+        if !self.streams.contains_key(&id) {
+            return Ok(());
+        }
+
+        let mut status = None;
+        let mut headers = vec![];
+        for (key, val) in fields {
+            let key = String::from_utf8_lossy(&key).to_string();
+            let val = String::from_utf8_lossy(&val).to_string();
+            if key == ":status" {
+                status = Some(val.parse::<u16>()?);
+            } else if !key.starts_with(':') {
+                headers.push((key, val));
+            }
+        }
+
+        // a block without status is trailers, interim (1xx) responses are skipped
+        if let Some(code) = status.filter(|code| *code >= 200) {
+            let status = StatusCode::from_u16(code)?;
+            events.push(Event::Head(
+                id,
+                ResponseHead {
+                    version: Version::HTTP_2,
+                    status,
+                    reason: status.canonical_reason().unwrap_or_default().to_string(),
+                    headers,
+                },
+            ));
+        }
+        if block.end_stream {
+            self.streams.remove(&id);
+            events.push(Event::End(id));
+        }
+
+        Ok(())
+    }
+
+    fn write_frame(&mut self, kind: u8, flags: u8, id: u32, payload: &[u8]) {
+        self.out
+            .extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+        self.out.push(kind);
+        self.out.push(flags);
+        self.out.extend_from_slice(&id.to_be_bytes());
+        self.out.extend_from_slice(payload);
+    }
+
+    fn write_window_update(&mut self, id: u32, increment: u32) {
+        self.write_frame(WINDOW_UPDATE, 0, id, &increment.to_be_bytes());
+    }
+}
+
+/// headers specific to an HTTP/1.1 connection, they must not be sent over HTTP/2
+pub fn is_connection_header(key: &HeaderName) -> bool {
+    [
+        header::HOST, // sent as :authority
+        header::CONNECTION,
+        header::TRANSFER_ENCODING,
+        header::UPGRADE,
+        header::TE,
+        HeaderName::from_static("keep-alive"),
+        HeaderName::from_static("proxy-connection"),
+    ]
+    .contains(key)
+}
+
+/// encode header field as a literal without indexing (HPACK), so that the encoder has no
+/// state at all and names and values are sent as is (no huffman encoding)
+fn encode_field(out: &mut Vec<u8>, key: &[u8], val: &[u8]) {
+    out.push(0);
+    for s in [key, val] {
+        encode_int(out, s.len(), 7);
+        out.extend_from_slice(s);
+    }
+}
+
+/// HPACK integer with given prefix size, the other bits of the first byte are zero
+pub fn encode_int(out: &mut Vec<u8>, mut val: usize, prefix_bits: u8) {
+    let max = (1 << prefix_bits) - 1;
+    if val < max {
+        out.push(val as u8);
+        return;
+    }
+
+    out.push(max as u8);
+    val -= max;
+    while val >= 128 {
+        out.push((val % 128 + 128) as u8);
+        val /= 128;
+    }
+    out.push(val as u8);
+}
+
+/// payload of a frame without its padding (if any)
+fn unpad(flags: u8, payload: &[u8]) -> Result<&[u8], PError> {
+    if flags & PADDED == 0 {
+        return Ok(payload);
+    }
+
+    let pad_len = *payload
+        .first()
+        .ok_or_else(|| make_error("invalid http2 padding"))? as usize;
+    payload
+        .get(1..payload.len().saturating_sub(pad_len))
+        .ok_or_else(|| make_error("invalid http2 padding"))
+}
+
+fn read_u32(data: &[u8], pos: usize) -> Result<u32, PError> {
+    data.get(pos..pos + 4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| make_error("http2 frame is too short"))
+}
+
+fn error_name(code: u32) -> String {
+    let name = match code {
+        0x0 => "NO_ERROR",
+        0x1 => "PROTOCOL_ERROR",
+        0x2 => "INTERNAL_ERROR",
+        0x3 => "FLOW_CONTROL_ERROR",
+        0x4 => "SETTINGS_TIMEOUT",
+        0x5 => "STREAM_CLOSED",
+        0x6 => "FRAME_SIZE_ERROR",
+        0x7 => "REFUSED_STREAM",
+        0x8 => "CANCEL",
+        0x9 => "COMPRESSION_ERROR",
+        0xa => "CONNECT_ERROR",
+        0xb => "ENHANCE_YOUR_CALM",
+        0xc => "INADEQUATE_SECURITY",
+        0xd => "HTTP_1_1_REQUIRED",
+        _ => return format!("error code {:#x}", code),
+    };
+
+    name.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(kind: u8, flags: u8, id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut conn = Connection::new();
+        conn.out.clear();
+        conn.write_frame(kind, flags, id, payload);
+        conn.out
+    }
+
+    fn head_frame(id: u32, status: &str, flags: u8) -> Vec<u8> {
+        let mut block = vec![];
+        encode_field(&mut block, b":status", status.as_bytes());
+        encode_field(&mut block, b"content-length", b"5");
+        frame(HEADERS, flags | END_HEADERS, id, &block)
+    }
+
+    /// (type, flags, stream id, payload) of frames in output
+    fn parse_frames(mut data: &[u8]) -> Vec<(u8, u8, u32, Vec<u8>)> {
+        let mut frames = vec![];
+        while data.len() >= FRAME_HEAD_LEN {
+            let len = u32::from_be_bytes([0, data[0], data[1], data[2]]) as usize;
+            let id = read_u32(data, 5).unwrap();
+            let payload = data[FRAME_HEAD_LEN..FRAME_HEAD_LEN + len].to_vec();
+            frames.push((data[3], data[4], id, payload));
+            data = &data[FRAME_HEAD_LEN + len..];
+        }
+        frames
+    }
+
+    fn request(path: &str) -> Request<()> {
+        Request::get(path)
+            .header("host", "example.com")
+            .header("connection", "Keep-Alive")
+            .header("range", "bytes=0-4")
+            .body(())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_request_and_response() {
+        let mut conn = Connection::new();
+        assert_eq!(
+            1,
+            conn.send_request(&request("/file?a=1"), "https").unwrap()
+        );
+
+        let out = conn.take_output();
+        assert!(out.starts_with(PREFACE));
+        let frames = parse_frames(&out[PREFACE.len()..]);
+        let kinds: Vec<u8> = frames.iter().map(|f| f.0).collect();
+        assert_eq!(vec![SETTINGS, WINDOW_UPDATE, HEADERS], kinds);
+
+        let (_, flags, id, block) = &frames[2];
+        assert_eq!((END_STREAM | END_HEADERS, 1), (*flags, *id));
+        let fields: Vec<(String, String)> = hpack::Decoder::new()
+            .decode(block)
+            .unwrap()
+            .into_iter()
+            .map(|(k, v)| (String::from_utf8(k).unwrap(), String::from_utf8(v).unwrap()))
+            .collect();
+        let expected = [
+            (":method", "GET"),
+            (":scheme", "https"),
+            (":authority", "example.com"),
+            (":path", "/file?a=1"),
+            ("range", "bytes=0-4"),
+        ];
+        let expected: Vec<(String, String)> = expected
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        assert_eq!(expected, fields);
+
+        // server allows a single stream
+        assert!(conn
+            .recv(&frame(SETTINGS, 0, 0, &[0, 3, 0, 0, 0, 1]))
+            .unwrap()
+            .is_empty());
+        assert!(!conn.can_open());
+
+        // response arrives in pieces cut anywhere
+        let mut data = head_frame(1, "100", 0);
+        data.extend(head_frame(1, "206", 0));
+        data.extend(frame(DATA, 0, 1, b"hel"));
+        data.extend(frame(DATA, PADDED | END_STREAM, 1, b"\x02lo\0\0"));
+
+        let mut events = vec![];
+        for piece in data.chunks(7) {
+            events.extend(conn.recv(piece).unwrap());
+        }
+        assert_eq!(4, events.len());
+        match &events[0] {
+            Event::Head(1, head) => {
+                assert_eq!(206, head.status.as_u16());
+                assert_eq!(Some("5"), head.header("content-length"));
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+        assert!(matches!(&events[1], Event::Data(1, data) if data == b"hel"));
+        assert!(matches!(&events[2], Event::Data(1, data) if data == b"lo"));
+        assert!(matches!(&events[3], Event::End(1)));
+        assert!(conn.can_open());
+
+        // settings are acknowledged
+        let frames = parse_frames(&conn.take_output());
+        assert_eq!(vec![(SETTINGS, ACK, 0, vec![])], frames);
+    }
+
+    #[test]
+    fn test_reset_and_goaway() {
+        let mut conn = Connection::new();
+        for id in [1, 3, 5] {
+            assert_eq!(id, conn.send_request(&request("/"), "https").unwrap());
+        }
+        conn.take_output();
+
+        let mut data = frame(RST_STREAM, 0, 1, &REFUSED_STREAM.to_be_bytes());
+        data.extend(frame(PING, 0, 0, b"12345678"));
+        data.extend(frame(GOAWAY, 0, 0, &[0, 0, 0, 3, 0, 0, 0, 0]));
+        let events = conn.recv(&data).unwrap();
+
+        let resets: Vec<(u32, bool)> = events
+            .iter()
+            .map(|e| match e {
+                Event::Reset { id, retryable, .. } => (*id, *retryable),
+                event => panic!("unexpected event {:?}", event),
+            })
+            .collect();
+        assert_eq!(vec![(1, true), (5, true)], resets);
+        assert!(!conn.can_open());
+
+        // stream 3 is still processed, then cancelled by client
+        conn.reset(3);
+        conn.reset(3);
+        let frames = parse_frames(&conn.take_output());
+        assert_eq!(
+            vec![
+                (PING, ACK, 0, b"12345678".to_vec()),
+                (RST_STREAM, 0, 3, CANCEL.to_be_bytes().to_vec())
+            ],
+            frames
+        );
+
+        let events = conn.recv(&frame(DATA, END_STREAM, 3, b"late")).unwrap();
+        assert!(events.is_empty());
+        assert!(conn.recv(&frame(PUSH_PROMISE, 0, 3, &[0; 8])).is_err());
+    }
+
+    #[test]
+    fn test_settings() {
+        // a server allowing no stream refuses the ones sent before it said so
+        let mut conn = Connection::new();
+        conn.send_request(&request("/"), "https").unwrap();
+        conn.take_output();
+        let events = conn
+            .recv(&frame(SETTINGS, 0, 0, &[0, 3, 0, 0, 0, 0]))
+            .unwrap();
+        assert_eq!(1, events.len());
+        assert!(matches!(
+            &events[0],
+            Event::Reset {
+                id: 1,
+                retryable: true,
+                ..
+            }
+        ));
+        assert!(conn.refuses_streams());
+        assert!(!conn.can_open());
+        assert!(conn.send_request(&request("/"), "https").is_err());
+
+        // header blocks are then split in frames of the size allowed
+        let mut conn = Connection::new();
+        let settings = [0, 5, 0, 0, 0x40, 0x01]; // 16385
+        conn.recv(&frame(SETTINGS, 0, 0, &settings)).unwrap();
+        assert_eq!(DEFAULT_MAX_FRAME_SIZE + 1, conn.max_frame_size);
+
+        // frame sizes out of the range allowed are a protocol error
+        for size in [0u32, 16383, 1 << 24] {
+            let mut conn = Connection::new();
+            conn.take_output();
+            let mut settings = vec![0, 5];
+            settings.extend_from_slice(&size.to_be_bytes());
+            assert!(conn.recv(&frame(SETTINGS, 0, 0, &settings)).is_err());
+            assert!(!conn.can_open());
+
+            let mut payload = 0u32.to_be_bytes().to_vec();
+            payload.extend_from_slice(&PROTOCOL_ERROR.to_be_bytes());
+            let frames = parse_frames(&conn.take_output());
+            assert_eq!(vec![(GOAWAY, 0, 0, payload)], frames);
+        }
+    }
+
+    #[test]
+    fn test_window_update() {
+        let mut conn = Connection::new();
+        conn.send_request(&request("/"), "https").unwrap();
+        conn.take_output();
+
+        let data = vec![0u8; DEFAULT_MAX_FRAME_SIZE];
+        let frames_per_update = (STREAM_WINDOW / 2) as usize / data.len();
+        for _ in 0..frames_per_update {
+            conn.recv(&frame(DATA, 0, 1, &data)).unwrap();
+        }
+
+        let frames = parse_frames(&conn.take_output());
+        assert_eq!(
+            vec![(
+                WINDOW_UPDATE,
+                0,
+                1,
+                (STREAM_WINDOW / 2).to_be_bytes().to_vec()
+            )],
+            frames
+        );
+    }
+}
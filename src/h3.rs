@@ -0,0 +1,696 @@
+use std::mem;
+
+use fget::{httpparse::ResponseHead, make_error, PError};
+use http::{header, Request, StatusCode, Version};
+
+use crate::h2;
+
+/// largest frame (other than DATA) accepted, DATA frames are passed on as they come
+const MAX_FRAME_LEN: u64 = 256 * 1024;
+
+// frame types
+pub const DATA: u64 = 0x0;
+pub const HEADERS: u64 = 0x1;
+const CANCEL_PUSH: u64 = 0x3;
+const SETTINGS: u64 = 0x4;
+const PUSH_PROMISE: u64 = 0x5;
+const GOAWAY: u64 = 0x7;
+const MAX_PUSH_ID: u64 = 0xd;
+
+// unidirectional stream types
+const CONTROL_STREAM: u64 = 0x0;
+
+// settings, a zero dynamic table capacity keeps QPACK stateless
+const SETTINGS_QPACK_MAX_TABLE_CAPACITY: u64 = 0x1;
+const SETTINGS_QPACK_BLOCKED_STREAMS: u64 = 0x7;
+
+// error codes, sent as QUIC application error codes
+pub const H3_NO_ERROR: u64 = 0x100;
+pub const H3_REQUEST_REJECTED: u64 = 0x10b;
+pub const H3_REQUEST_CANCELLED: u64 = 0x10c;
+
+/// QPACK static table (RFC 9204, appendix A)
+const STATIC_TABLE: [(&str, &str); 99] = [
+    (":authority", ""),
+    (":path", "/"),
+    ("age", "0"),
+    ("content-disposition", ""),
+    ("content-length", "0"),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("referer", ""),
+    ("set-cookie", ""),
+    (":method", "CONNECT"),
+    (":method", "DELETE"),
+    (":method", "GET"),
+    (":method", "HEAD"),
+    (":method", "OPTIONS"),
+    (":method", "POST"),
+    (":method", "PUT"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "103"),
+    (":status", "200"),
+    (":status", "304"),
+    (":status", "404"),
+    (":status", "503"),
+    ("accept", "*/*"),
+    ("accept", "application/dns-message"),
+    ("accept-encoding", "gzip, deflate, br"),
+    ("accept-ranges", "bytes"),
+    ("access-control-allow-headers", "cache-control"),
+    ("access-control-allow-headers", "content-type"),
+    ("access-control-allow-origin", "*"),
+    ("cache-control", "max-age=0"),
+    ("cache-control", "max-age=2592000"),
+    ("cache-control", "max-age=604800"),
+    ("cache-control", "no-cache"),
+    ("cache-control", "no-store"),
+    ("cache-control", "public, max-age=31536000"),
+    ("content-encoding", "br"),
+    ("content-encoding", "gzip"),
+    ("content-type", "application/dns-message"),
+    ("content-type", "application/javascript"),
+    ("content-type", "application/json"),
+    ("content-type", "application/x-www-form-urlencoded"),
+    ("content-type", "image/gif"),
+    ("content-type", "image/jpeg"),
+    ("content-type", "image/png"),
+    ("content-type", "text/css"),
+    ("content-type", "text/html; charset=utf-8"),
+    ("content-type", "text/plain"),
+    ("content-type", "text/plain;charset=utf-8"),
+    ("range", "bytes=0-"),
+    ("strict-transport-security", "max-age=31536000"),
+    (
+        "strict-transport-security",
+        "max-age=31536000; includesubdomains",
+    ),
+    (
+        "strict-transport-security",
+        "max-age=31536000; includesubdomains; preload",
+    ),
+    ("vary", "accept-encoding"),
+    ("vary", "origin"),
+    ("x-content-type-options", "nosniff"),
+    ("x-xss-protection", "1; mode=block"),
+    (":status", "100"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "302"),
+    (":status", "400"),
+    (":status", "403"),
+    (":status", "421"),
+    (":status", "425"),
+    (":status", "500"),
+    ("accept-language", ""),
+    ("access-control-allow-credentials", "FALSE"),
+    ("access-control-allow-credentials", "TRUE"),
+    ("access-control-allow-headers", "*"),
+    ("access-control-allow-methods", "get"),
+    ("access-control-allow-methods", "get, post, options"),
+    ("access-control-allow-methods", "options"),
+    ("access-control-expose-headers", "content-length"),
+    ("access-control-request-headers", "content-type"),
+    ("access-control-request-method", "get"),
+    ("access-control-request-method", "post"),
+    ("alt-svc", "clear"),
+    ("authorization", ""),
+    (
+        "content-security-policy",
+        "script-src 'none'; object-src 'none'; base-uri 'none'",
+    ),
+    ("early-data", "1"),
+    ("expect-ct", ""),
+    ("forwarded", ""),
+    ("if-range", ""),
+    ("origin", ""),
+    ("purpose", "prefetch"),
+    ("server", ""),
+    ("timing-allow-origin", "*"),
+    ("upgrade-insecure-requests", "1"),
+    ("user-agent", ""),
+    ("x-forwarded-for", ""),
+    ("x-frame-options", "deny"),
+    ("x-frame-options", "sameorigin"),
+];
+
+/// What was received on a request stream
+#[derive(Debug)]
+pub enum Event {
+    /// final (not interim) response head
+    Head(ResponseHead),
+    /// part of response body
+    Data(Vec<u8>),
+}
+
+/// Response received on a request stream of an HTTP/3 connection (RFC 9114). It does no io:
+/// QUIC streams are handled by the caller, which feeds data received on the stream to
+/// `recv`. Body data is passed on as it comes, without waiting for whole DATA frames.
+#[derive(Debug, Default)]
+pub struct ResponseStream {
+    buf: Vec<u8>,   // received data not parsed yet (incomplete frame)
+    data_left: u64, // bytes of the current DATA frame not received yet
+    head: bool,     // final response head was received
+}
+
+impl ResponseStream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// handle data received on the stream, an error means the response is malformed
+    pub fn recv(&mut self, data: &[u8]) -> Result<Vec<Event>, PError> {
+        let mut buf = mem::take(&mut self.buf);
+        buf.extend_from_slice(data);
+
+        let mut events = vec![];
+        let mut pos = 0;
+        loop {
+            if self.data_left > 0 {
+                let n = ((buf.len() - pos) as u64).min(self.data_left) as usize;
+                if n == 0 {
+                    break;
+                }
+                events.push(Event::Data(buf[pos..pos + n].to_vec()));
+                self.data_left -= n as u64;
+                pos += n;
+                continue;
+            }
+
+            let (kind, len, head_len) = match read_frame_head(&buf[pos..]) {
+                Some(head) => head,
+                None => break,
+            };
+            if kind == DATA {
+                if !self.head {
+                    return Err(make_error("http3 data received before response head"));
+                }
+                self.data_left = len;
+                pos += head_len;
+                continue;
+            }
+            if len > MAX_FRAME_LEN {
+                return Err(make_error("http3 frame is larger than allowed"));
+            }
+            if ((buf.len() - pos - head_len) as u64) < len {
+                break;
+            }
+
+            let payload = &buf[pos + head_len..pos + head_len + len as usize];
+            match kind {
+                HEADERS => {
+                    if let Some(head) = self.recv_headers(payload)? {
+                        events.push(Event::Head(head));
+                    }
+                }
+                CANCEL_PUSH | SETTINGS | PUSH_PROMISE | GOAWAY | MAX_PUSH_ID => {
+                    return Err(make_error("unexpected http3 frame on request stream"));
+                }
+                _ => {} // unknown and reserved frame types are ignored
+            }
+            pos += head_len + len as usize;
+        }
+
+        buf.drain(..pos);
+        self.buf = buf;
+
+        Ok(events)
+    }
+
+    fn recv_headers(&mut self, payload: &[u8]) -> Result<Option<ResponseHead>, PError> {
+        let fields = decode_field_section(payload)?;
+        if self.head {
+            return Ok(None); // trailers
+        }
+
+        let mut status = None;
+        let mut headers = vec![];
+        for (key, val) in fields {
+            if key == ":status" {
+                status = Some(val.parse::<u16>()?);
+            } else if !key.starts_with(':') {
+                headers.push((key, val));
+            }
+        }
+
+        // interim (1xx) responses are skipped
+        let code = status.ok_or_else(|| make_error("http3 response without status"))?;
+        if code < 200 {
+            return Ok(None);
+        }
+
+        let status = StatusCode::from_u16(code)?;
+        self.head = true;
+        Ok(Some(ResponseHead {
+            version: Version::HTTP_3,
+            status,
+            reason: status.canonical_reason().unwrap_or_default().to_string(),
+            headers,
+        }))
+    }
+}
+
+/// Unidirectional stream opened by the server: its control stream is parsed (to know when
+/// it is going away), the other ones (QPACK streams, unknown types) are ignored
+#[derive(Debug, Default)]
+pub struct UniStream {
+    buf: Vec<u8>,
+    kind: Option<u64>,
+}
+
+impl UniStream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// handle data received on the stream, returns the id of the first request stream the
+    /// server will not process if it sent a GOAWAY frame
+    pub fn recv(&mut self, data: &[u8]) -> Result<Option<u64>, PError> {
+        if self.kind.is_some() && self.kind != Some(CONTROL_STREAM) {
+            return Ok(None);
+        }
+        self.buf.extend_from_slice(data);
+
+        let mut pos = 0;
+        if self.kind.is_none() {
+            match read_varint(&self.buf) {
+                Some((kind, n)) => {
+                    self.kind = Some(kind);
+                    pos = n;
+                }
+                None => return Ok(None),
+            }
+        }
+        if self.kind != Some(CONTROL_STREAM) {
+            self.buf.clear();
+            return Ok(None);
+        }
+
+        let mut goaway = None;
+        while let Some((kind, len, head_len)) = read_frame_head(&self.buf[pos..]) {
+            if len > MAX_FRAME_LEN {
+                return Err(make_error("http3 frame is larger than allowed"));
+            }
+            if ((self.buf.len() - pos - head_len) as u64) < len {
+                break;
+            }
+
+            let payload = &self.buf[pos + head_len..pos + head_len + len as usize];
+            match kind {
+                GOAWAY => {
+                    let (id, _) = read_varint(payload)
+                        .ok_or_else(|| make_error("invalid http3 goaway frame"))?;
+                    goaway = Some(id);
+                }
+                DATA | HEADERS | PUSH_PROMISE => {
+                    return Err(make_error("unexpected http3 frame on control stream"));
+                }
+                _ => {} // nothing we send depends on the server settings
+            }
+            pos += head_len + len as usize;
+        }
+        self.buf.drain(..pos);
+
+        Ok(goaway)
+    }
+}
+
+/// first bytes of the client control stream: its type followed by our settings
+pub fn control_stream() -> Vec<u8> {
+    let mut settings = vec![];
+    for (id, val) in [
+        (SETTINGS_QPACK_MAX_TABLE_CAPACITY, 0),
+        (SETTINGS_QPACK_BLOCKED_STREAMS, 0),
+    ] {
+        encode_varint(&mut settings, id);
+        encode_varint(&mut settings, val);
+    }
+
+    let mut out = vec![];
+    encode_varint(&mut out, CONTROL_STREAM);
+    write_frame(&mut out, SETTINGS, &settings);
+    out
+}
+
+/// HEADERS frame of given request (without body), to be sent on a new request stream
+pub fn encode_request<T>(req: &Request<T>, scheme: &str) -> Result<Vec<u8>, PError> {
+    let authority = match req.headers().get(header::HOST) {
+        Some(host) => host.to_str()?,
+        None => req
+            .uri()
+            .authority()
+            .map(|a| a.as_str())
+            .unwrap_or_default(),
+    };
+    let path = req
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+
+    let mut fields = vec![
+        (":method", req.method().as_str().as_bytes()),
+        (":scheme", scheme.as_bytes()),
+        (":authority", authority.as_bytes()),
+        (":path", path.as_bytes()),
+    ];
+    for (key, val) in req.headers().iter() {
+        if !h2::is_connection_header(key) {
+            fields.push((key.as_str(), val.as_bytes()));
+        }
+    }
+
+    Ok(encode_headers(&fields))
+}
+
+/// HEADERS frame carrying given fields, encoded as literals (QPACK) so that the encoder
+/// has no state and never references the dynamic table
+pub fn encode_headers(fields: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut block = vec![0, 0]; // required insert count and base
+    for (key, val) in fields {
+        encode_string(&mut block, key.as_bytes(), 3, 0x20); // literal with literal name
+        encode_string(&mut block, val, 7, 0);
+    }
+
+    let mut out = vec![];
+    write_frame(&mut out, HEADERS, &block);
+    out
+}
+
+/// decode a QPACK field section which does not reference the dynamic table (whose
+/// capacity is zero as we advertise), returns (name, value) pairs
+pub fn decode_field_section(data: &[u8]) -> Result<Vec<(String, String)>, PError> {
+    let mut pos = 0;
+    let required_insert_count = decode_int(data, &mut pos, 8)?;
+    decode_int(data, &mut pos, 7)?; // base, only relevant to dynamic references
+    if required_insert_count != 0 {
+        return Err(dynamic_table_error());
+    }
+
+    let mut fields = vec![];
+    while pos < data.len() {
+        let first = data[pos];
+        if first & 0x80 != 0 {
+            // indexed field line
+            if first & 0x40 == 0 {
+                return Err(dynamic_table_error());
+            }
+            let (name, val) = static_entry(decode_int(data, &mut pos, 6)?)?;
+            fields.push((name.to_string(), val.to_string()));
+        } else if first & 0x40 != 0 {
+            // literal field line with name reference
+            if first & 0x10 == 0 {
+                return Err(dynamic_table_error());
+            }
+            let (name, _) = static_entry(decode_int(data, &mut pos, 4)?)?;
+            let val = decode_string(data, &mut pos, 7)?;
+            fields.push((name.to_string(), val));
+        } else if first & 0x20 != 0 {
+            // literal field line with literal name
+            let name = decode_string(data, &mut pos, 3)?;
+            let val = decode_string(data, &mut pos, 7)?;
+            fields.push((name, val));
+        } else {
+            // post-base references
+            return Err(dynamic_table_error());
+        }
+    }
+
+    Ok(fields)
+}
+
+/// type, payload length and length of the head of the frame at the start of data, None
+/// if the head is incomplete
+pub fn read_frame_head(data: &[u8]) -> Option<(u64, u64, usize)> {
+    let (kind, n) = read_varint(data)?;
+    let (len, m) = read_varint(&data[n..])?;
+    Some((kind, len, n + m))
+}
+
+pub fn write_frame(out: &mut Vec<u8>, kind: u64, payload: &[u8]) {
+    encode_varint(out, kind);
+    encode_varint(out, payload.len() as u64);
+    out.extend_from_slice(payload);
+}
+
+/// QUIC variable-length integer at the start of data, with its length
+fn read_varint(data: &[u8]) -> Option<(u64, usize)> {
+    let first = *data.first()?;
+    let len = 1 << (first >> 6);
+    let bytes = data.get(1..len)?;
+
+    let val = bytes
+        .iter()
+        .fold((first & 0x3f) as u64, |val, b| (val << 8) | *b as u64);
+    Some((val, len))
+}
+
+fn encode_varint(out: &mut Vec<u8>, val: u64) {
+    match val {
+        0..=0x3f => out.push(val as u8),
+        0x40..=0x3fff => out.extend_from_slice(&(val as u16 | 0x4000).to_be_bytes()),
+        0x4000..=0x3fff_ffff => out.extend_from_slice(&(val as u32 | 0x8000_0000).to_be_bytes()),
+        _ => out.extend_from_slice(&(val | 0xc000_0000_0000_0000).to_be_bytes()),
+    }
+}
+
+/// QPACK string literal (without huffman encoding), after the given flags of first byte
+fn encode_string(out: &mut Vec<u8>, s: &[u8], prefix_bits: u8, flags: u8) {
+    let start = out.len();
+    h2::encode_int(out, s.len(), prefix_bits);
+    out[start] |= flags;
+    out.extend_from_slice(s);
+}
+
+/// QPACK string literal, whose huffman flag is the bit above the length prefix
+fn decode_string(data: &[u8], pos: &mut usize, prefix_bits: u8) -> Result<String, PError> {
+    let huffman = data.get(*pos).map(|b| b & (1 << prefix_bits) != 0) == Some(true);
+    let len = decode_int(data, pos, prefix_bits)? as usize;
+    let raw = data
+        .get(*pos..*pos + len)
+        .ok_or_else(|| make_error("invalid http3 header block"))?;
+    *pos += len;
+
+    if !huffman {
+        return Ok(String::from_utf8_lossy(raw).to_string());
+    }
+    let decoded = hpack::huffman::HuffmanDecoder::new()
+        .decode(raw)
+        .map_err(|err| make_error(format!("invalid http3 header block: {:?}", err).as_str()))?;
+    Ok(String::from_utf8_lossy(&decoded).to_string())
+}
+
+/// QPACK (same as HPACK) integer with given prefix size
+fn decode_int(data: &[u8], pos: &mut usize, prefix_bits: u8) -> Result<u64, PError> {
+    let mut next = || -> Result<u8, PError> {
+        let b = *data
+            .get(*pos)
+            .ok_or_else(|| make_error("invalid http3 header block"))?;
+        *pos += 1;
+        Ok(b)
+    };
+
+    let max = (1u64 << prefix_bits) - 1;
+    let mut val = next()? as u64 & max;
+    if val < max {
+        return Ok(val);
+    }
+    for shift in (0..63).step_by(7) {
+        let b = next()?;
+        val += ((b & 0x7f) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Ok(val);
+        }
+    }
+
+    Err(make_error("invalid http3 header block"))
+}
+
+fn static_entry(idx: u64) -> Result<(&'static str, &'static str), PError> {
+    STATIC_TABLE
+        .get(idx as usize)
+        .copied()
+        .ok_or_else(|| make_error("invalid qpack static table index"))
+}
+
+fn dynamic_table_error() -> PError {
+    make_error("http3 header block references the qpack dynamic table")
+}
+
+/// name of an HTTP/3 error code, as reported in stream resets and connection closes
+pub fn error_name(code: u64) -> String {
+    let name = match code {
+        0x100 => "H3_NO_ERROR",
+        0x101 => "H3_GENERAL_PROTOCOL_ERROR",
+        0x102 => "H3_INTERNAL_ERROR",
+        0x103 => "H3_STREAM_CREATION_ERROR",
+        0x104 => "H3_CLOSED_CRITICAL_STREAM",
+        0x105 => "H3_FRAME_UNEXPECTED",
+        0x106 => "H3_FRAME_ERROR",
+        0x107 => "H3_EXCESSIVE_LOAD",
+        0x108 => "H3_ID_ERROR",
+        0x109 => "H3_SETTINGS_ERROR",
+        0x10a => "H3_MISSING_SETTINGS",
+        0x10b => "H3_REQUEST_REJECTED",
+        0x10c => "H3_REQUEST_CANCELLED",
+        0x10d => "H3_REQUEST_INCOMPLETE",
+        0x10e => "H3_MESSAGE_ERROR",
+        0x10f => "H3_CONNECT_ERROR",
+        0x110 => "H3_VERSION_FALLBACK",
+        _ => return format!("error code {:#x}", code),
+    };
+
+    name.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(path: &str) -> Request<()> {
+        Request::builder()
+            .uri(path)
+            .header(header::HOST, "example.com")
+            .header(header::CONNECTION, "keep-alive")
+            .header(header::RANGE, "bytes=0-99")
+            .body(())
+            .unwrap()
+    }
+
+    /// payload of the frame at the start of data, which must be complete
+    fn frame_payload(data: &[u8]) -> (u64, &[u8]) {
+        let (kind, len, head_len) = read_frame_head(data).unwrap();
+        (kind, &data[head_len..head_len + len as usize])
+    }
+
+    #[test]
+    fn test_varint() {
+        for val in [
+            0,
+            63,
+            64,
+            16383,
+            16384,
+            (1 << 30) - 1,
+            1 << 30,
+            (1 << 62) - 1,
+        ] {
+            let mut out = vec![];
+            encode_varint(&mut out, val);
+            assert_eq!(Some((val, out.len())), read_varint(&out));
+            assert_eq!(None, read_varint(&out[..out.len() - 1]));
+        }
+        // example of RFC 9000, appendix A.1
+        assert_eq!(
+            Some((151288809941952652, 8)),
+            read_varint(&[0xc2, 0x19, 0x7c, 0x5e, 0xff, 0x14, 0xe8, 0x8c])
+        );
+    }
+
+    #[test]
+    fn test_request_and_response() {
+        let data = encode_request(&request("/file?x=1"), "https").unwrap();
+        let (kind, block) = frame_payload(&data);
+        assert_eq!(HEADERS, kind);
+        let fields = decode_field_section(block).unwrap();
+        assert_eq!(
+            vec![
+                (":method", "GET"),
+                (":scheme", "https"),
+                (":authority", "example.com"),
+                (":path", "/file?x=1"),
+                ("range", "bytes=0-99"),
+            ],
+            fields
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .collect::<Vec<_>>()
+        );
+
+        // interim response, then response using the static table and huffman encoding:
+        // :status 206, content-length (name reference) and a huffman encoded value
+        let mut data = encode_headers(&[(":status", b"103")]);
+        let mut block = vec![0, 0, 0xc0 | 63, 65 - 63, 0x50 | 4, 1, b'5', 0x26];
+        block.extend_from_slice(b"x-host");
+        block.push(0x80 | 12);
+        block.extend_from_slice(&[
+            0xf1, 0xe3, 0xc2, 0xe5, 0xf2, 0x3a, 0x6b, 0xa0, 0xab, 0x90, 0xf4, 0xff,
+        ]);
+        write_frame(&mut data, HEADERS, &block);
+        write_frame(&mut data, 0x21, b"reserved"); // unknown frame type
+        write_frame(&mut data, DATA, b"hel");
+        write_frame(&mut data, DATA, b"lo");
+
+        // fed byte by byte, body is passed on as it comes
+        let mut stream = ResponseStream::new();
+        let mut events = vec![];
+        for b in data.iter() {
+            events.extend(stream.recv(&[*b]).unwrap());
+        }
+        let head = match &events[0] {
+            Event::Head(head) => head,
+            event => panic!("unexpected event {:?}", event),
+        };
+        assert_eq!(206, head.status.as_u16());
+        assert_eq!(Some("5"), head.header("content-length"));
+        assert_eq!(Some("www.example.com"), head.header("x-host"));
+
+        let body: Vec<u8> = events[1..]
+            .iter()
+            .flat_map(|event| match event {
+                Event::Data(data) => data.clone(),
+                event => panic!("unexpected event {:?}", event),
+            })
+            .collect();
+        assert_eq!(b"hello", &body[..]);
+    }
+
+    #[test]
+    fn test_malformed_responses() {
+        let mut data = vec![];
+        write_frame(&mut data, DATA, b"body");
+        assert!(ResponseStream::new().recv(&data).is_err());
+
+        // references to the dynamic table, which we never allow
+        let mut data = vec![];
+        write_frame(&mut data, HEADERS, &[1, 0, 0x80]);
+        assert!(ResponseStream::new().recv(&data).is_err());
+        let mut data = vec![];
+        write_frame(&mut data, HEADERS, &[0, 0, 0x80 | 1]);
+        assert!(ResponseStream::new().recv(&data).is_err());
+
+        let mut data = encode_headers(&[(":status", b"200")]);
+        write_frame(&mut data, GOAWAY, &[0]);
+        assert!(ResponseStream::new().recv(&data).is_err());
+    }
+
+    #[test]
+    fn test_uni_streams() {
+        let (kind, _) = read_varint(&control_stream()).unwrap();
+        assert_eq!(CONTROL_STREAM, kind);
+
+        // server control stream: settings then goaway
+        let mut data = vec![CONTROL_STREAM as u8];
+        write_frame(&mut data, SETTINGS, &[0x1, 0x0, 0x7, 0x0]);
+        let mut goaway = vec![];
+        encode_varint(&mut goaway, 8);
+        write_frame(&mut data, GOAWAY, &goaway);
+
+        let mut stream = UniStream::new();
+        let (head, tail) = data.split_at(data.len() - 1);
+        assert_eq!(None, stream.recv(head).unwrap());
+        assert_eq!(Some(8), stream.recv(tail).unwrap());
+
+        // QPACK encoder stream is ignored
+        let mut stream = UniStream::new();
+        assert_eq!(None, stream.recv(&[0x2, 0x3f, 0xe1, 0x1f]).unwrap());
+        assert_eq!(None, stream.recv(&data).unwrap());
+    }
+}
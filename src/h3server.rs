@@ -0,0 +1,323 @@
+use std::{
+    collections::HashMap,
+    net::UdpSocket,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use bytes::BytesMut;
+use quinn_proto::{
+    crypto::rustls::QuicServerConfig, Connection, ConnectionHandle, DatagramEvent, Dir, Endpoint,
+    EndpointConfig, ServerConfig, StreamId,
+};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+
+use crate::h3;
+
+/// Self-signed certificate of `localhost`, trusted by clients of the test process
+pub struct TestCert {
+    pub cert_pem: String,
+    pub key_pem: String,
+    pub cert_der: CertificateDer<'static>,
+    key_der: Vec<u8>,
+}
+
+/// certificate of test servers, generated once and added to the roots of native-tls and
+/// QUIC client configs in test builds
+pub fn test_cert() -> &'static TestCert {
+    static CERT: OnceLock<TestCert> = OnceLock::new();
+    CERT.get_or_init(|| {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+            .expect("failed to generate certificate");
+        TestCert {
+            cert_pem: certified.cert.pem(),
+            key_pem: certified.signing_key.serialize_pem(),
+            cert_der: certified.cert.der().clone(),
+            key_der: certified.signing_key.serialize_der(),
+        }
+    })
+}
+
+#[derive(Debug, Default)]
+struct State {
+    files: HashMap<String, Vec<u8>>, // keyed by path
+    requests: Vec<String>,           // "path range" of every request
+    connections: usize,
+}
+
+/// HTTP/3 server on localhost (QUIC on udp) serving static files with range support, to
+/// test clients against a real QUIC stack without network
+pub struct H3Server {
+    port: u16,
+    state: Arc<Mutex<State>>,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl H3Server {
+    /// serve given (path, body) files
+    pub fn start(files: &[(&str, &[u8])]) -> H3Server {
+        let state = State {
+            files: files
+                .iter()
+                .map(|(path, body)| (path.to_string(), body.to_vec()))
+                .collect(),
+            ..Default::default()
+        };
+        let state = Arc::new(Mutex::new(state));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(2)))
+            .unwrap();
+        let port = socket.local_addr().unwrap().port();
+
+        let mut server = ServerLoop {
+            socket,
+            endpoint: Endpoint::new(
+                Arc::new(EndpointConfig::default()),
+                Some(server_config()),
+                false,
+                None,
+            ),
+            conns: HashMap::new(),
+            state: state.clone(),
+        };
+        let stopped = stop.clone();
+        let handle = thread::spawn(move || {
+            while !stopped.load(Ordering::Relaxed) {
+                server.turn();
+            }
+        });
+
+        H3Server {
+            port,
+            state,
+            stop,
+            handle: Some(handle),
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// all requests received so far
+    pub fn requests(&self) -> Vec<String> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// number of connections accepted so far
+    pub fn connections(&self) -> usize {
+        self.state.lock().unwrap().connections
+    }
+}
+
+impl Drop for H3Server {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn server_config() -> Arc<ServerConfig> {
+    let cert = test_cert();
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(cert.key_der.clone()));
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut tls = rustls::ServerConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert.cert_der.clone()], key)
+        .unwrap();
+    tls.alpn_protocols = vec![b"h3".to_vec()];
+
+    let crypto = QuicServerConfig::try_from(tls).unwrap();
+    Arc::new(ServerConfig::with_crypto(Arc::new(crypto)))
+}
+
+/// A request stream: request received so far, then response being sent
+#[derive(Default)]
+struct ServerStream {
+    request: Vec<u8>,
+    response: Option<(Vec<u8>, usize)>, // (response, bytes sent)
+}
+
+struct ServerLoop {
+    socket: UdpSocket,
+    endpoint: Endpoint,
+    conns: HashMap<ConnectionHandle, (Connection, HashMap<StreamId, ServerStream>)>,
+    state: Arc<Mutex<State>>,
+}
+
+impl ServerLoop {
+    /// receive a datagram (if any) and make progress on all connections
+    fn turn(&mut self) {
+        let mut buf = vec![0u8; 64 * 1024];
+        let now = Instant::now();
+        // a datagram or the read timeout
+        if let Ok((n, from)) = self.socket.recv_from(&mut buf) {
+            let mut out = vec![];
+            let data = BytesMut::from(&buf[..n]);
+            match self.endpoint.handle(now, from, None, None, data, &mut out) {
+                Some(DatagramEvent::NewConnection(incoming)) => {
+                    match self.endpoint.accept(incoming, now, &mut out, None) {
+                        Ok((handle, conn)) => {
+                            self.conns.insert(handle, (conn, HashMap::new()));
+                            self.state.lock().unwrap().connections += 1;
+                        }
+                        Err(err) => {
+                            if let Some(transmit) = err.response {
+                                let _ = self.socket.send_to(&out[..transmit.size], from);
+                            }
+                        }
+                    }
+                }
+                Some(DatagramEvent::ConnectionEvent(handle, event)) => {
+                    if let Some((conn, _)) = self.conns.get_mut(&handle) {
+                        conn.handle_event(event);
+                    }
+                }
+                Some(DatagramEvent::Response(transmit)) => {
+                    let _ = self.socket.send_to(&out[..transmit.size], from);
+                }
+                None => {}
+            }
+        }
+
+        let handles: Vec<ConnectionHandle> = self.conns.keys().copied().collect();
+        for handle in handles {
+            self.drive(handle);
+        }
+        self.conns.retain(|_, (conn, _)| !conn.is_drained());
+    }
+
+    fn drive(&mut self, handle: ConnectionHandle) {
+        let now = Instant::now();
+        let (conn, streams) = self.conns.get_mut(&handle).unwrap();
+        if conn.poll_timeout().is_some_and(|deadline| deadline <= now) {
+            conn.handle_timeout(now);
+        }
+        while let Some(event) = conn.poll_endpoint_events() {
+            if let Some(event) = self.endpoint.handle_event(handle, event) {
+                conn.handle_event(event);
+            }
+        }
+        while conn.poll().is_some() {}
+
+        // client control stream is never read, it only carries settings
+        while conn.streams().accept(Dir::Uni).is_some() {}
+        while let Some(id) = conn.streams().accept(Dir::Bi) {
+            streams.insert(id, ServerStream::default());
+        }
+
+        for (id, stream) in streams.iter_mut() {
+            if stream.response.is_none() {
+                let mut recv = conn.recv_stream(*id);
+                let mut chunks = match recv.read(true) {
+                    Ok(chunks) => chunks,
+                    Err(_) => continue,
+                };
+                let mut finished = false;
+                loop {
+                    match chunks.next(usize::MAX) {
+                        Ok(Some(chunk)) => stream.request.extend_from_slice(&chunk.bytes),
+                        Ok(None) => {
+                            finished = true;
+                            break;
+                        }
+                        Err(_) => break,
+                    }
+                }
+                let _ = chunks.finalize();
+                if finished {
+                    stream.response = Some((respond(&self.state, &stream.request), 0));
+                }
+            }
+
+            if let Some((response, sent)) = &mut stream.response {
+                let mut send = conn.send_stream(*id);
+                while *sent < response.len() {
+                    match send.write(&response[*sent..]) {
+                        Ok(n) => *sent += n,
+                        Err(_) => break,
+                    }
+                }
+                if *sent == response.len() {
+                    let _ = send.finish();
+                }
+            }
+        }
+        streams.retain(|_, stream| {
+            !matches!(&stream.response, Some((response, sent)) if *sent == response.len())
+        });
+
+        loop {
+            let mut out = Vec::with_capacity(64 * 1024);
+            match conn.poll_transmit(now, 1, &mut out) {
+                Some(transmit) => {
+                    let _ = self
+                        .socket
+                        .send_to(&out[..transmit.size], transmit.destination);
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+/// response (HEADERS and DATA frames) to the request received on a stream
+fn respond(state: &Mutex<State>, request: &[u8]) -> Vec<u8> {
+    let fields = h3::read_frame_head(request)
+        .and_then(|(_, len, head_len)| request.get(head_len..head_len + len as usize))
+        .and_then(|block| h3::decode_field_section(block).ok())
+        .unwrap_or_default();
+    let field = |name: &str| {
+        fields
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, val)| val.clone())
+    };
+    let path = field(":path").unwrap_or_default();
+    let range = field("range").and_then(|range| {
+        let (start, end) = range.strip_prefix("bytes=")?.split_once('-')?;
+        Some((start.parse::<usize>().ok()?, end.parse::<usize>().ok()))
+    });
+
+    let mut state = state.lock().unwrap();
+    state.requests.push(format!(
+        "{} {}",
+        path,
+        field("range").unwrap_or_else(|| "-".to_string())
+    ));
+
+    let (status, mut headers, body) = match (state.files.get(&path), range) {
+        (None, _) => ("404", vec![], b"not found".to_vec()),
+        (Some(file), Some((start, end))) => {
+            let end = end.unwrap_or(file.len() - 1).min(file.len() - 1);
+            let content_range = format!("bytes {}-{}/{}", start, end, file.len());
+            (
+                "206",
+                vec![("content-range", content_range)],
+                file[start..=end].to_vec(),
+            )
+        }
+        (Some(file), None) => ("200", vec![], file.clone()),
+    };
+    headers.push(("content-length", body.len().to_string()));
+    headers.push(("accept-ranges", "bytes".to_string()));
+
+    let mut fields: Vec<(&str, &[u8])> = vec![(":status", status.as_bytes())];
+    fields.extend(headers.iter().map(|(key, val)| (*key, val.as_bytes())));
+    let mut response = h3::encode_headers(&fields);
+    h3::write_frame(&mut response, h3::DATA, &body);
+
+    response
+}
@@ -0,0 +1,332 @@
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::Write,
+    net::IpAddr,
+    path::PathBuf,
+    sync::{Mutex, OnceLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use fget::PError;
+use tempfile::NamedTempFile;
+
+use crate::{urlinfo::UrlInfo, Config};
+
+static STORE: OnceLock<Mutex<HstsStore>> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Policy {
+    include_subdomains: bool,
+    expires: u64, // unix timestamp in seconds
+}
+
+/// Known HSTS hosts (RFC 6797), persisted as one `host include_subdomains expires` per line
+#[derive(Debug, Default)]
+struct HstsStore {
+    path: Option<PathBuf>,
+    hosts: HashMap<String, Policy>,
+    preload: HashMap<String, Policy>, // never saved, hosts take precedence
+    changes: HashMap<String, Option<Policy>>, // recorded by this process, None if removed
+}
+
+/// Load HSTS store (and preload list if any), does nothing if --no-hsts
+pub fn init(cfg: &Config) -> Result<(), PError> {
+    if cfg.no_hsts {
+        return Ok(());
+    }
+
+    let mut store = HstsStore {
+        path: cfg
+            .hsts_file
+            .as_ref()
+            .map(PathBuf::from)
+            .or_else(default_path),
+        ..Default::default()
+    };
+    if let Some(preload) = &cfg.hsts_preload {
+        store.preload = parse_entries(&fs::read_to_string(preload)?, now());
+    }
+    if let Some(content) = store.path.as_ref().and_then(|p| fs::read_to_string(p).ok()) {
+        store.load(&content, now());
+    }
+
+    let _ = STORE.set(Mutex::new(store));
+    Ok(())
+}
+
+/// Upgrade url to https if its host is a known HSTS host, returns true if upgraded
+pub fn upgrade(urlinfo: &mut UrlInfo) -> bool {
+    if urlinfo.is_tls() {
+        return false;
+    }
+
+    let known = STORE
+        .get()
+        .and_then(|store| store.lock().ok())
+        .map(|store| store.is_known(&urlinfo.domain, now()))
+        .unwrap_or_default();
+    if known {
+        urlinfo.scheme = "https".to_string();
+        if urlinfo.port == 80 {
+            urlinfo.port = 443;
+        }
+    }
+
+    known
+}
+
+/// Record Strict-Transport-Security header received from given host over https
+pub fn record(host: &str, header: &str) {
+    if let Some(mut store) = STORE.get().and_then(|store| store.lock().ok()) {
+        store.update(host, header, now());
+    }
+}
+
+/// Write store back to its file if it has been changed
+pub fn save() -> Result<(), PError> {
+    match STORE.get().and_then(|store| store.lock().ok()) {
+        Some(store) => store.save(now()),
+        None => Ok(()),
+    }
+}
+
+impl HstsStore {
+    fn load(&mut self, content: &str, now: u64) {
+        self.hosts.extend(parse_entries(content, now));
+    }
+
+    /// Merge the changes of this process into the file as it is now, other processes
+    /// may have saved theirs since it was loaded, then replace it in one rename
+    fn save(&self, now: u64) -> Result<(), PError> {
+        let path = match &self.path {
+            Some(path) if !self.changes.is_empty() => path,
+            _ => return Ok(()),
+        };
+
+        let on_disk = fs::read_to_string(path).unwrap_or_default();
+        let dir = path.parent().unwrap_or(path);
+        fs::create_dir_all(dir)?;
+        let mut file = NamedTempFile::new_in(dir)?;
+        file.write_all(self.merge(&on_disk, now).as_bytes())?;
+        file.persist(path)?;
+
+        Ok(())
+    }
+
+    fn merge(&self, on_disk: &str, now: u64) -> String {
+        let mut merged = HstsStore::default();
+        merged.load(on_disk, now);
+        for (host, change) in &self.changes {
+            match change {
+                Some(policy) => merged.hosts.insert(host.clone(), *policy),
+                None => merged.hosts.remove(host),
+            };
+        }
+
+        merged.dump(now)
+    }
+
+    fn dump(&self, now: u64) -> String {
+        let mut hosts: Vec<_> = self.hosts.iter().filter(|(_, p)| p.expires > now).collect();
+        hosts.sort_by(|a, b| a.0.cmp(b.0));
+
+        let mut out = String::from("# fget HSTS store: host include_subdomains expires\n");
+        for (host, policy) in hosts {
+            out += &format!(
+                "{} {} {}\n",
+                host, policy.include_subdomains as u8, policy.expires
+            );
+        }
+
+        out
+    }
+
+    fn policy(&self, host: &str) -> Option<&Policy> {
+        self.hosts.get(host).or_else(|| self.preload.get(host))
+    }
+
+    fn is_known(&self, host: &str, now: u64) -> bool {
+        let host = host.to_lowercase();
+        if let Some(policy) = self.policy(&host) {
+            return policy.expires > now;
+        }
+
+        // check superdomains with includeSubDomains
+        let mut domain = host.as_str();
+        while let Some(pos) = domain.find('.') {
+            domain = &domain[pos + 1..];
+            match self.policy(domain) {
+                Some(policy) if policy.include_subdomains && policy.expires > now => return true,
+                _ => {}
+            }
+        }
+
+        false
+    }
+
+    fn update(&mut self, host: &str, header: &str, now: u64) {
+        // hsts does not apply to ip literals
+        if host.parse::<IpAddr>().is_ok() || host.starts_with('[') {
+            return;
+        }
+
+        let (max_age, include_subdomains) = match parse_sts_header(header) {
+            Some(directives) => directives,
+            None => return,
+        };
+
+        let host = host.to_lowercase();
+        if max_age == 0 {
+            if self.hosts.remove(&host).is_some() {
+                self.changes.insert(host, None);
+            }
+            return;
+        }
+
+        let policy = Policy {
+            include_subdomains,
+            expires: now.saturating_add(max_age),
+        };
+        if self.hosts.insert(host.clone(), policy) != Some(policy) {
+            self.changes.insert(host, Some(policy));
+        }
+    }
+}
+
+/// parse entries of a store or preload file, skipping expired ones
+fn parse_entries(content: &str, now: u64) -> HashMap<String, Policy> {
+    let mut hosts = HashMap::new();
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if let [host, sub, expires] = fields[..] {
+            let policy = Policy {
+                include_subdomains: sub == "1" || sub.eq_ignore_ascii_case("true"),
+                expires: expires.parse().unwrap_or_default(),
+            };
+            if policy.expires > now {
+                hosts.insert(host.to_lowercase(), policy);
+            }
+        }
+    }
+
+    hosts
+}
+
+/// parse Strict-Transport-Security header value into (max-age, includeSubDomains),
+/// returns None if header is invalid (max-age directive is required)
+fn parse_sts_header(header: &str) -> Option<(u64, bool)> {
+    let mut max_age = None;
+    let mut include_subdomains = false;
+
+    for directive in header.split(';').map(str::trim) {
+        let (name, val) = match directive.split_once('=') {
+            Some((name, val)) => (name.trim(), Some(val.trim().trim_matches('"'))),
+            None => (directive, None),
+        };
+
+        if name.eq_ignore_ascii_case("max-age") {
+            max_age = Some(val?.parse::<u64>().ok()?);
+        } else if name.eq_ignore_ascii_case("includeSubDomains") {
+            include_subdomains = true;
+        }
+    }
+
+    max_age.map(|max_age| (max_age, include_subdomains))
+}
+
+/// $XDG_DATA_HOME/fget/hsts, defaults to ~/.local/share/fget/hsts (%APPDATA% on windows)
+fn default_path() -> Option<PathBuf> {
+    let dir = env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("APPDATA").map(PathBuf::from))
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share")))?;
+
+    Some(dir.join("fget").join("hsts"))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sts_header() {
+        assert_eq!(
+            Some((31536000, false)),
+            parse_sts_header("max-age=31536000")
+        );
+        assert_eq!(
+            Some((600, true)),
+            parse_sts_header("max-age=\"600\"; includeSubDomains; preload")
+        );
+        assert_eq!(None, parse_sts_header("includeSubDomains"));
+        assert_eq!(None, parse_sts_header("max-age=abc"));
+    }
+
+    #[test]
+    fn test_store() {
+        let mut store = HstsStore::default();
+        store.update("Example.com", "max-age=100; includeSubDomains", 1000);
+        store.update("other.org", "max-age=100", 1000);
+        store.update("127.0.0.1", "max-age=100", 1000);
+
+        assert!(store.is_known("example.com", 1050));
+        assert!(store.is_known("cdn.example.com", 1050));
+        assert!(!store.is_known("example.com", 1100)); // expired
+        assert!(store.is_known("other.org", 1050));
+        assert!(!store.is_known("www.other.org", 1050));
+        assert!(!store.is_known("127.0.0.1", 1050));
+
+        let mut loaded = HstsStore::default();
+        loaded.load(&store.dump(1000), 1000);
+        assert_eq!(store.hosts, loaded.hosts);
+
+        store.update("other.org", "max-age=0", 1010);
+        assert!(!store.is_known("other.org", 1050));
+    }
+
+    #[test]
+    fn test_save() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fget").join("hsts");
+        let new_store = || HstsStore {
+            path: Some(path.clone()),
+            ..Default::default()
+        };
+
+        // preloaded hosts are known but never saved
+        let mut first = new_store();
+        first.preload = parse_entries("preloaded.com 1 2000\n", 1000);
+        assert!(first.is_known("www.preloaded.com", 1000));
+        first.save(1000).unwrap();
+        assert!(!path.exists()); // nothing learned, nothing to save
+        first.update("a.com", "max-age=100", 1000);
+        first.update("b.com", "max-age=100", 1000);
+
+        // a second process loaded the store before the first one saved its hosts
+        let mut second = new_store();
+        second.update("c.com", "max-age=100", 1000);
+        first.save(1000).unwrap();
+        second.load(&fs::read_to_string(&path).unwrap(), 1000);
+        second.update("b.com", "max-age=0", 1010);
+        second.save(1010).unwrap();
+
+        let mut saved = new_store();
+        saved.load(&fs::read_to_string(&path).unwrap(), 1010);
+        let mut hosts: Vec<_> = saved.hosts.keys().map(String::as_str).collect();
+        hosts.sort_unstable();
+        assert_eq!(vec!["a.com", "c.com"], hosts);
+        assert_eq!(1, fs::read_dir(path.parent().unwrap()).unwrap().count());
+    }
+}
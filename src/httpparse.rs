@@ -0,0 +1,800 @@
+use std::io::{self, BufRead, Read};
+
+use http::{StatusCode, Version};
+
+use crate::{make_error, PError};
+
+/// maximum length of the status line or of a single header line (including line folding)
+pub const MAX_LINE_LEN: usize = 8 * 1024;
+/// maximum total size of the response head (status line and all header lines)
+pub const MAX_HEAD_SIZE: usize = 64 * 1024;
+/// maximum number of header fields
+pub const MAX_HEADERS: usize = 128;
+
+/// Status line and headers of an HTTP/1.x response, the body is left in the reader
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResponseHead {
+    pub version: Version,
+    pub status: StatusCode,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl ResponseHead {
+    /// get value of the first header matching given name (case-insensitive)
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, val)| val.as_str())
+    }
+}
+
+/// Parse a complete response head from raw bytes. This is the entry point used
+/// by the fuzz target, it must never panic whatever the input is.
+pub fn parse_response_head(mut data: &[u8]) -> Result<ResponseHead, PError> {
+    read_response_head(&mut data)
+}
+
+/// Read response head from a stream, interim (1xx) responses like
+/// 100 Continue or 103 Early Hints are skipped.
+pub fn read_response_head<R: BufRead>(r: &mut R) -> Result<ResponseHead, PError> {
+    loop {
+        let head = read_single_head(r)?;
+        match head.status.as_u16() {
+            101 => return Err(make_error("unexpected 101 Switching Protocols response")),
+            100..=199 => continue,
+            _ => return Ok(head),
+        }
+    }
+}
+
+/// Parse response head from the beginning of a buffer filled by a non-blocking reader,
+/// returns None if the head is not complete yet, otherwise the head and its length in
+/// bytes. Like `read_response_head`, interim (1xx) responses are skipped.
+pub fn try_parse_response_head(buf: &[u8]) -> Result<Option<(ResponseHead, usize)>, PError> {
+    let mut consumed = 0;
+    loop {
+        let len = match find_head_end(&buf[consumed..])? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+
+        let head = read_single_head(&mut &buf[consumed..consumed + len])?;
+        consumed += len;
+        match head.status.as_u16() {
+            101 => return Err(make_error("unexpected 101 Switching Protocols response")),
+            100..=199 => continue,
+            _ => return Ok(Some((head, consumed))),
+        }
+    }
+}
+
+/// length of the first head (up to and including the empty line) in buf, if complete
+fn find_head_end(buf: &[u8]) -> Result<Option<usize>, PError> {
+    let mut pos = 0;
+    let mut first = true;
+    while let Some(n) = buf[pos..].iter().position(|b| *b == b'\n') {
+        let line = &buf[pos..pos + n + 1];
+        pos += n + 1;
+        if !first && trim_eol(line).is_empty() {
+            return Ok(Some(pos));
+        }
+        first = false;
+    }
+
+    // a complete head may be a bit larger than MAX_HEAD_SIZE because of the status line
+    if buf.len() > MAX_HEAD_SIZE + MAX_LINE_LEN {
+        return Err(make_error("response headers too large"));
+    }
+
+    Ok(None)
+}
+
+fn read_single_head<R: BufRead>(r: &mut R) -> Result<ResponseHead, PError> {
+    let mut line = Vec::new();
+    let mut total = read_line(r, &mut line, MAX_LINE_LEN)?;
+    if total == 0 {
+        return Err(make_error("connection closed before response was received"));
+    }
+
+    let (version, status, reason) = parse_status_line(trim_eol(&line))?;
+    let mut headers: Vec<(String, String)> = vec![];
+
+    loop {
+        line.clear();
+        let n = read_line(r, &mut line, MAX_LINE_LEN)?;
+        if n == 0 {
+            return Err(make_error("unexpected end of response headers"));
+        }
+
+        total += n;
+        if total > MAX_HEAD_SIZE {
+            return Err(make_error("response headers too large"));
+        }
+
+        let content = trim_eol(&line);
+        if content.is_empty() {
+            break; // end of headers
+        }
+
+        // obsolete line folding (RFC 7230 section 3.2.4): continuation of previous value
+        if content[0] == b' ' || content[0] == b'\t' {
+            let (_, val) = headers
+                .last_mut()
+                .ok_or_else(|| make_error("invalid header: line folding without a header"))?;
+            let cont = String::from_utf8_lossy(content);
+            if val.len() + cont.len() > MAX_LINE_LEN {
+                return Err(make_error("header line too long"));
+            }
+            if !val.is_empty() {
+                val.push(' ');
+            }
+            val.push_str(cont.trim());
+            continue;
+        }
+
+        if headers.len() >= MAX_HEADERS {
+            return Err(make_error("too many response headers"));
+        }
+        headers.push(parse_header_line(content)?);
+    }
+
+    Ok(ResponseHead {
+        version,
+        status,
+        reason,
+        headers,
+    })
+}
+
+/// read a line (up to and including '\n') but not more than `max` bytes,
+/// returns 0 if reader reached EOF before anything was read
+fn read_line<R: BufRead>(r: &mut R, buf: &mut Vec<u8>, max: usize) -> Result<usize, PError> {
+    let mut limited = r.take(max as u64 + 1);
+    let n = limited.read_until(b'\n', buf)?;
+
+    if n > max {
+        return Err(make_error("header line too long"));
+    }
+    if n > 0 && buf.last() != Some(&b'\n') {
+        return Err(make_error("unexpected end of response headers"));
+    }
+
+    Ok(n)
+}
+
+/// Decoder of a body sent with `Transfer-Encoding: chunked`, chunk extensions
+/// and trailers are discarded
+pub struct ChunkedReader<R> {
+    r: R,
+    remaining: u64, // remaining bytes of current chunk
+    done: bool,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    pub fn new(r: R) -> Self {
+        Self {
+            r,
+            remaining: 0,
+            done: false,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.r
+    }
+
+    /// chunk = chunk-size [ chunk-ext ] CRLF chunk-data CRLF
+    fn read_chunk_size(&mut self) -> Result<u64, PError> {
+        let mut line = Vec::new();
+        if read_line(&mut self.r, &mut line, MAX_LINE_LEN)? == 0 {
+            return Err(make_error("unexpected end of chunked body"));
+        }
+
+        let size = parse_chunk_size(trim_eol(&line))?;
+        if size == 0 {
+            // last-chunk, skip trailer fields until the empty line
+            loop {
+                line.clear();
+                if read_line(&mut self.r, &mut line, MAX_LINE_LEN)? == 0
+                    || trim_eol(&line).is_empty()
+                {
+                    break;
+                }
+            }
+        }
+
+        Ok(size)
+    }
+
+    fn read_chunk_end(&mut self) -> Result<(), PError> {
+        let mut line = Vec::new();
+        read_line(&mut self.r, &mut line, 2)?;
+        if !trim_eol(&line).is_empty() {
+            return Err(make_error("invalid chunk terminator"));
+        }
+
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let to_io_err = |e: PError| io::Error::new(io::ErrorKind::InvalidData, e.to_string());
+
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            self.remaining = self.read_chunk_size().map_err(to_io_err)?;
+            if self.remaining == 0 {
+                self.done = true;
+                return Ok(0);
+            }
+        }
+
+        let max = buf.len().min(self.remaining as usize);
+        let n = self.r.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "unexpected end of chunked body",
+            ));
+        }
+
+        self.remaining -= n as u64;
+        if self.remaining == 0 {
+            self.read_chunk_end().map_err(to_io_err)?;
+        }
+
+        Ok(n)
+    }
+}
+
+/// Decoder of a chunked body fed with the data received so far, for non-blocking readers
+/// which can not wait for the rest of a line: chunk extensions and trailers are discarded
+#[derive(Debug, Default)]
+pub struct ChunkedDecoder {
+    state: ChunkState,
+    line: Vec<u8>, // beginning of a line received so far
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+enum ChunkState {
+    #[default]
+    Size,
+    Data(u64), // remaining bytes of current chunk
+    DataEnd,   // CRLF after chunk data
+    Trailer,
+    Done,
+}
+
+impl ChunkedDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// whether the last chunk and the trailer fields were received
+    pub fn is_done(&self) -> bool {
+        self.state == ChunkState::Done
+    }
+
+    /// decode data, appending chunk data to out, returns the number of bytes used: what
+    /// follows the end of the body is left
+    pub fn decode(&mut self, mut data: &[u8], out: &mut Vec<u8>) -> Result<usize, PError> {
+        let len = data.len();
+        while !data.is_empty() && !self.is_done() {
+            if let ChunkState::Data(remaining) = self.state {
+                let n = remaining.min(data.len() as u64);
+                out.extend_from_slice(&data[..n as usize]);
+                data = &data[n as usize..];
+                self.state = match remaining - n {
+                    0 => ChunkState::DataEnd,
+                    remaining => ChunkState::Data(remaining),
+                };
+                continue;
+            }
+
+            // other states read a line
+            let eol = data.iter().position(|b| *b == b'\n');
+            let n = eol.map_or(data.len(), |i| i + 1);
+            if self.line.len() + n > MAX_LINE_LEN {
+                return Err(make_error("chunk line too long"));
+            }
+            self.line.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if eol.is_none() {
+                break;
+            }
+
+            let line = std::mem::take(&mut self.line);
+            let line = trim_eol(&line);
+            self.state = match self.state {
+                ChunkState::Size => match parse_chunk_size(line)? {
+                    0 => ChunkState::Trailer,
+                    size => ChunkState::Data(size),
+                },
+                ChunkState::DataEnd if line.is_empty() => ChunkState::Size,
+                ChunkState::DataEnd => return Err(make_error("invalid chunk terminator")),
+                ChunkState::Trailer if line.is_empty() => ChunkState::Done,
+                _ => ChunkState::Trailer,
+            };
+        }
+
+        Ok(len - data.len())
+    }
+}
+
+/// chunk-size [ chunk-ext ], the size is in hexadecimal
+fn parse_chunk_size(line: &[u8]) -> Result<u64, PError> {
+    let size = line.split(|b| *b == b';').next().unwrap_or_default();
+    std::str::from_utf8(size)
+        .ok()
+        .and_then(|size| u64::from_str_radix(size.trim(), 16).ok())
+        .ok_or_else(|| make_error("invalid chunk size"))
+}
+
+/// boundary of a `multipart/byteranges` content type, None if it is another type
+pub fn multipart_boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';');
+    let media_type = params.next()?.trim();
+    if !media_type.eq_ignore_ascii_case("multipart/byteranges") {
+        return None;
+    }
+
+    params
+        .filter_map(|param| param.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("boundary"))
+        .map(|(_, val)| val.trim().trim_matches('"').to_string())
+        .filter(|boundary| !boundary.is_empty())
+}
+
+/// parse `bytes <first>-<last>/<complete length>` content range, the complete length may
+/// be unknown (`*`)
+pub fn parse_content_range(val: &str) -> Option<(u64, u64, Option<u64>)> {
+    let (range, len) = val.trim().strip_prefix("bytes ")?.split_once('/')?;
+    let (first, last) = range.split_once('-')?;
+    let (first, last) = (first.parse::<u64>().ok()?, last.parse::<u64>().ok()?);
+    let len = match len {
+        "*" => None,
+        len => Some(len.parse::<u64>().ok()?),
+    };
+
+    Some((first, last, len)).filter(|_| first <= last && len.is_none_or(|len| last < len))
+}
+
+/// Decoder of a `multipart/byteranges` body (RFC 9110 section 14.6), each part is a range
+/// of the file with its own Content-Range header. Reading returns the data of the
+/// current part, `next_part` moves to the next one.
+pub struct ByteRangesReader<R> {
+    r: R,
+    delimiter: Vec<u8>, // "--" followed by the boundary
+    remaining: u64,     // remaining bytes of current part
+    done: bool,
+}
+
+impl<R: BufRead> ByteRangesReader<R> {
+    pub fn new(r: R, boundary: &str) -> Self {
+        Self {
+            r,
+            delimiter: format!("--{}", boundary).into_bytes(),
+            remaining: 0,
+            done: false,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.r
+    }
+
+    /// skip what is left of the current part and read head of the next one, returns its
+    /// content range or None after the last part
+    pub fn next_part(&mut self) -> Result<Option<(u64, u64, Option<u64>)>, PError> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let unexpected_end = || make_error("unexpected end of multipart body");
+        let skipped = io::copy(&mut (&mut self.r).take(self.remaining), &mut io::sink())?;
+        if skipped < self.remaining {
+            return Err(unexpected_end());
+        }
+        self.remaining = 0;
+
+        // skip the preamble or the line break after part data, up to the delimiter
+        let mut line = Vec::new();
+        let mut total = 0;
+        loop {
+            line.clear();
+            let n = read_line(&mut self.r, &mut line, MAX_LINE_LEN)?;
+            if n == 0 {
+                return Err(unexpected_end());
+            }
+
+            total += n;
+            if total > MAX_HEAD_SIZE {
+                return Err(make_error("multipart delimiter not found"));
+            }
+
+            if let Some(rest) = trim_eol(&line).strip_prefix(self.delimiter.as_slice()) {
+                if rest.starts_with(b"--") {
+                    self.done = true;
+                    return Ok(None);
+                }
+                if rest.iter().all(|b| *b == b' ' || *b == b'\t') {
+                    break;
+                }
+            }
+        }
+
+        let mut content_range = None;
+        total = 0;
+        loop {
+            line.clear();
+            let n = read_line(&mut self.r, &mut line, MAX_LINE_LEN)?;
+            if n == 0 {
+                return Err(unexpected_end());
+            }
+
+            total += n;
+            if total > MAX_HEAD_SIZE {
+                return Err(make_error("multipart headers too large"));
+            }
+
+            let content = trim_eol(&line);
+            if content.is_empty() {
+                break; // end of part headers
+            }
+
+            let (name, val) = parse_header_line(content)?;
+            if name.eq_ignore_ascii_case("content-range") {
+                content_range = Some(val);
+            }
+        }
+
+        let range = content_range
+            .and_then(|val| parse_content_range(&val))
+            .ok_or_else(|| make_error("multipart part without a valid content-range"))?;
+        self.remaining = range.1 - range.0 + 1;
+
+        Ok(Some(range))
+    }
+}
+
+impl<R: BufRead> Read for ByteRangesReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let max = buf.len().min(self.remaining as usize);
+        let n = self.r.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "unexpected end of multipart body",
+            ));
+        }
+
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+fn trim_eol(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+/// status-line = HTTP-version SP status-code SP [ reason-phrase ]
+fn parse_status_line(line: &[u8]) -> Result<(Version, StatusCode, String), PError> {
+    let invalid = || {
+        make_error(
+            format!(
+                "invalid status line: {:?}",
+                String::from_utf8_lossy(&line[..line.len().min(64)])
+            )
+            .as_str(),
+        )
+    };
+
+    let version = match line.get(..8) {
+        Some(b"HTTP/1.0") => Version::HTTP_10,
+        Some(b"HTTP/1.1") => Version::HTTP_11,
+        Some(v) if v.starts_with(b"HTTP/") => {
+            return Err(make_error(
+                format!("unsupported HTTP version: {}", String::from_utf8_lossy(v)).as_str(),
+            ))
+        }
+        _ => return Err(invalid()),
+    };
+
+    // some servers send more than one space between tokens, be lenient with that
+    let rest = &line[8..];
+    if rest.first() != Some(&b' ') {
+        return Err(invalid());
+    }
+    let rest = trim_start_spaces(rest);
+
+    let code = rest.get(..3).ok_or_else(invalid)?;
+    if !code.iter().all(u8::is_ascii_digit) {
+        return Err(invalid());
+    }
+    let status = StatusCode::from_bytes(code).map_err(|_| invalid())?;
+
+    // reason phrase may be empty, even the space before it may be omitted
+    let reason = match &rest[3..] {
+        [] => "",
+        [b' ', reason @ ..] => std::str::from_utf8(reason).unwrap_or_default().trim(),
+        _ => return Err(invalid()),
+    };
+
+    Ok((version, status, reason.to_string()))
+}
+
+/// header-field = field-name ":" OWS field-value OWS
+fn parse_header_line(line: &[u8]) -> Result<(String, String), PError> {
+    let pos = line
+        .iter()
+        .position(|b| *b == b':')
+        .ok_or_else(|| make_error("invalid header: missing colon"))?;
+
+    let name = &line[..pos];
+    if name.is_empty() || !name.iter().all(|b| is_token_char(*b)) {
+        return Err(make_error(
+            format!("invalid header name: {:?}", String::from_utf8_lossy(name)).as_str(),
+        ));
+    }
+
+    let value = String::from_utf8_lossy(&line[pos + 1..]);
+    Ok((
+        String::from_utf8_lossy(name).to_string(),
+        value.trim_matches(|c| c == ' ' || c == '\t').to_string(),
+    ))
+}
+
+fn trim_start_spaces(mut s: &[u8]) -> &[u8] {
+    while let [b' ', rest @ ..] = s {
+        s = rest;
+    }
+    s
+}
+
+/// token characters as defined in RFC 7230 section 3.2.6
+fn is_token_char(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_simple_response() {
+        let data = b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\nAccept-Ranges: bytes\r\n\r\nbody";
+        let head = parse_response_head(data).unwrap();
+
+        assert_eq!(Version::HTTP_11, head.version);
+        assert_eq!(200, head.status.as_u16());
+        assert_eq!("OK", head.reason);
+        assert_eq!(Some("10"), head.header("content-length"));
+        assert_eq!(Some("bytes"), head.header("Accept-Ranges"));
+    }
+
+    #[test]
+    fn test_parse_empty_reason_and_bare_lf() {
+        let head = parse_response_head(b"HTTP/1.0 200\nServer: x\n\n").unwrap();
+        assert_eq!(Version::HTTP_10, head.version);
+        assert_eq!(200, head.status.as_u16());
+        assert_eq!("", head.reason);
+
+        let head = parse_response_head(b"HTTP/1.1 404 \r\n\r\n").unwrap();
+        assert_eq!(404, head.status.as_u16());
+    }
+
+    #[test]
+    fn test_skip_informational_responses() {
+        let data = b"HTTP/1.1 100 Continue\r\n\r\n\
+            HTTP/1.1 103 Early Hints\r\nLink: </style.css>; rel=preload\r\n\r\n\
+            HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 0-9/100\r\n\r\n";
+        let head = parse_response_head(data).unwrap();
+
+        assert_eq!(206, head.status.as_u16());
+        assert_eq!(1, head.headers.len());
+        assert_eq!(Some("bytes 0-9/100"), head.header("content-range"));
+    }
+
+    #[test]
+    fn test_obsolete_line_folding() {
+        let data = b"HTTP/1.1 200 OK\r\nX-Long: first\r\n  second\r\n\tthird\r\n\r\n";
+        let head = parse_response_head(data).unwrap();
+        assert_eq!(Some("first second third"), head.header("x-long"));
+
+        assert!(parse_response_head(b"HTTP/1.1 200 OK\r\n folded\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn test_malformed_responses() {
+        for data in [
+            &b""[..],
+            b"HTTP/1.1 200 OK\r\n",
+            b"HTTP/1.1 200 OK\r\nContent-Length: 1",
+            b"HTTP/2 200\r\n\r\n",
+            b"HTTP/1.1 20 OK\r\n\r\n",
+            b"HTTP/1.1 2000 OK\r\n\r\n",
+            b"HTTP/1.1 abc OK\r\n\r\n",
+            b"HTTP/1.1200 OK\r\n\r\n",
+            b"ICY 200 OK\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nno colon\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nBad Name: x\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\n: x\r\n\r\n",
+            b"HTTP/1.1 101 Switching Protocols\r\n\r\n",
+        ] {
+            assert!(parse_response_head(data).is_err(), "{:?}", data);
+        }
+    }
+
+    #[test]
+    fn test_size_limits() {
+        let long_line = format!("HTTP/1.1 200 OK\r\nX: {}\r\n\r\n", "a".repeat(MAX_LINE_LEN));
+        assert!(parse_response_head(long_line.as_bytes()).is_err());
+
+        let mut many = String::from("HTTP/1.1 200 OK\r\n");
+        for i in 0..=MAX_HEADERS {
+            many += &format!("X-{}: v\r\n", i);
+        }
+        many += "\r\n";
+        assert!(parse_response_head(many.as_bytes()).is_err());
+
+        let mut large = String::from("HTTP/1.1 200 OK\r\n");
+        while large.len() <= MAX_HEAD_SIZE {
+            large += &format!("X: {}\r\n", "a".repeat(1000));
+        }
+        large += "\r\n";
+        assert!(parse_response_head(large.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_chunked_reader() {
+        let data = b"4\r\nWiki\r\n7;ext=1\r\npedia i\r\nB\r\nn \r\nchunks.\r\n0\r\nX-Trailer: 1\r\n\r\nnext";
+        let mut r = ChunkedReader::new(&data[..]);
+        let mut body = String::new();
+        r.read_to_string(&mut body).unwrap();
+
+        assert_eq!("Wikipedia in \r\nchunks.", body);
+        assert_eq!(b"next", r.into_inner());
+
+        let mut body = vec![];
+        assert!(ChunkedReader::new(&b"zz\r\nabc"[..])
+            .read_to_end(&mut body)
+            .is_err());
+        assert!(ChunkedReader::new(&b"5\r\nabc"[..])
+            .read_to_end(&mut body)
+            .is_err());
+    }
+
+    #[test]
+    fn test_chunked_decoder() {
+        let data = b"4\r\nWiki\r\n7;ext=1\r\npedia i\r\nB\r\nn \r\nchunks.\r\n0\r\nX-Trailer: 1\r\n\r\nnext";
+
+        // whatever the data is cut into
+        for size in [1, 3, data.len()] {
+            let mut decoder = ChunkedDecoder::new();
+            let mut body = vec![];
+            let mut used = 0;
+            for chunk in data.chunks(size) {
+                used += decoder.decode(chunk, &mut body).unwrap();
+            }
+            assert!(decoder.is_done());
+            assert_eq!(b"Wikipedia in \r\nchunks.", &body[..]);
+            assert_eq!(data.len() - 4, used);
+        }
+
+        let mut decoder = ChunkedDecoder::new();
+        let mut body = vec![];
+        assert_eq!(6, decoder.decode(b"5\r\nabc", &mut body).unwrap());
+        assert!(!decoder.is_done());
+        assert!(ChunkedDecoder::new().decode(b"zz\r\n", &mut body).is_err());
+        assert!(ChunkedDecoder::new()
+            .decode(b"1\r\nabc\r\n", &mut body)
+            .is_err());
+    }
+
+    #[test]
+    fn test_multipart_boundary() {
+        assert_eq!(
+            Some("3d6b6a416f9b5".to_string()),
+            multipart_boundary("multipart/byteranges; boundary=3d6b6a416f9b5")
+        );
+        assert_eq!(
+            Some("a b".to_string()),
+            multipart_boundary("Multipart/ByteRanges;charset=x; Boundary=\"a b\"")
+        );
+        assert_eq!(None, multipart_boundary("multipart/byteranges"));
+        assert_eq!(None, multipart_boundary("text/plain; boundary=x"));
+    }
+
+    #[test]
+    fn test_parse_content_range() {
+        assert_eq!(Some((0, 0, Some(10))), parse_content_range("bytes 0-0/10"));
+        assert_eq!(Some((5, 9, None)), parse_content_range("bytes 5-9/*"));
+        assert_eq!(None, parse_content_range("bytes 0-10/10"));
+        assert_eq!(None, parse_content_range("bytes 5-4/*"));
+        assert_eq!(None, parse_content_range("bytes */10"));
+    }
+
+    #[test]
+    fn test_byteranges_reader() {
+        let data = b"preamble\r\n--SEP\r\nContent-Type: text/plain\r\n\
+            Content-Range: bytes 0-4/20\r\n\r\nhello\r\n--SEP\r\n\
+            Content-Range: bytes 10-14/*\r\n\r\nwor\r\n\r\n--SEP--\r\nepilogue";
+        let mut r = ByteRangesReader::new(&data[..], "SEP");
+
+        assert_eq!(Some((0, 4, Some(20))), r.next_part().unwrap());
+        let mut body = String::new();
+        r.read_to_string(&mut body).unwrap();
+        assert_eq!("hello", body);
+
+        // part data may contain line breaks
+        assert_eq!(Some((10, 14, None)), r.next_part().unwrap());
+        body.clear();
+        r.read_to_string(&mut body).unwrap();
+        assert_eq!("wor\r\n", body);
+
+        assert_eq!(None, r.next_part().unwrap());
+        assert_eq!(None, r.next_part().unwrap());
+        assert_eq!(b"epilogue", r.into_inner());
+
+        // unread data of a part is skipped
+        let mut r = ByteRangesReader::new(&data[..], "SEP");
+        r.next_part().unwrap();
+        assert_eq!(Some((10, 14, None)), r.next_part().unwrap());
+
+        for data in [
+            &b"--SEP\r\n\r\nhello\r\n--SEP--\r\n"[..],
+            b"--SEP\r\nContent-Range: bytes 0-9/20\r\n\r\nhello",
+            b"--SEP\r\nContent-Range: bytes 0-4/20\r\n\r\nhello\r\n",
+            b"no delimiter\r\n",
+        ] {
+            let mut r = ByteRangesReader::new(data, "SEP");
+            let res = r
+                .next_part()
+                .and_then(|_| Ok(io::copy(&mut r, &mut io::sink())?))
+                .and_then(|_| r.next_part());
+            assert!(res.is_err(), "{:?}", data);
+        }
+    }
+
+    #[test]
+    fn test_try_parse_incomplete_head() {
+        let data = b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 206 Partial Content\r\nA: b\r\n\r\nbody";
+        for len in 0..data.len() - 4 {
+            assert!(
+                try_parse_response_head(&data[..len]).unwrap().is_none(),
+                "{}",
+                len
+            );
+        }
+
+        let (head, len) = try_parse_response_head(data).unwrap().unwrap();
+        assert_eq!(206, head.status.as_u16());
+        assert_eq!(b"body", &data[len..]);
+
+        let (head, len) = try_parse_response_head(b"HTTP/1.0 200 OK\n\nx")
+            .unwrap()
+            .unwrap();
+        assert_eq!(200, head.status.as_u16());
+        assert_eq!(17, len);
+
+        assert!(try_parse_response_head(b"HTTP/1.1 abc\r\n\r\n").is_err());
+        assert!(try_parse_response_head(&vec![b'a'; MAX_HEAD_SIZE + MAX_LINE_LEN + 1]).is_err());
+    }
+
+    #[test]
+    fn test_body_is_left_in_reader() {
+        let mut data: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nbody";
+        read_response_head(&mut data).unwrap();
+        assert_eq!(b"body", data);
+    }
+}
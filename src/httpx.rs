@@ -1,21 +1,32 @@
 use std::{
     collections::HashMap,
-    io::{BufRead, BufReader, Read, Write},
-    net::{SocketAddr, TcpStream, ToSocketAddrs},
-    str::{self, FromStr},
-    time::Duration,
+    error::Error,
+    fmt,
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
 };
 
-use http::{header, request::Builder, Method, Request, Response, StatusCode};
-use native_tls::TlsConnector;
-
-use fget::{hash_map, make_error, PError};
-
-use crate::urlinfo::UrlInfo;
+use http::{header, request::Builder, Method, Request, Response, StatusCode, Version};
+use native_tls::{TlsConnector, TlsConnectorBuilder};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use fget::{
+    hash_map,
+    httpparse::{
+        multipart_boundary, parse_content_range, read_response_head, ByteRangesReader,
+        ChunkedReader, ResponseHead,
+    },
+    make_error, map, statusln, PError,
+};
 
-pub trait ReadWrite: Read + Write {}
+pub use fget::transport::{
+    Conn, ConnAddr, ConnConfig, NbConn, NbStream, ReadWrite, Timings, Transport,
+};
 
-impl<T: Read + Write> ReadWrite for T {}
+use crate::{altsvc, hsts, ratelimit::RateLimit, trace, urlinfo::UrlInfo};
 
 pub struct ToRead(Box<dyn ReadWrite>);
 
@@ -38,15 +49,208 @@ pub type HttpHeaders = HashMap<String, String>;
 
 const DEFAULT_TIMEOUT_MS: u64 = 5 * 1000;
 const DEFAULT_REDIRECT_POLICY: RedirectPolicy = RedirectPolicy::Follow(10);
-const DEFAULT_USER_AGENT: &'static str = "fget/0.1.0";
+const DEFAULT_USER_AGENT: &str = "fget/0.1.0";
+/// maximum number of bytes of an error response body kept in memory
+pub const MAX_ERROR_BODY_LEN: u64 = 1024 * 1024;
+/// maximum number of bytes of an error response body to be displayed
+const MAX_ERROR_EXCERPT_LEN: usize = 1024;
+
+/// Error response (4xx or 5xx) returned by server
+#[derive(Debug, Clone)]
+pub struct HttpError {
+    pub status: StatusCode,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>, // bounded by MAX_ERROR_BODY_LEN
+}
+
+impl HttpError {
+    /// the beginning of response body as (lossy) utf-8 text, for display purposes
+    pub fn excerpt(&self) -> String {
+        let len = self.body.len().min(MAX_ERROR_EXCERPT_LEN);
+        let mut excerpt = String::from_utf8_lossy(&self.body[..len])
+            .trim()
+            .to_string();
+        if len < self.body.len() {
+            excerpt += "...";
+        }
+
+        excerpt
+    }
+
+    /// whether the body kept is only the beginning of the one sent, which was longer than
+    /// MAX_ERROR_BODY_LEN
+    pub fn body_cut(&self) -> bool {
+        let len = self
+            .headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case("content-length"))
+            .and_then(|(_, val)| val.parse::<u64>().ok());
+        self.body.len() as u64 == MAX_ERROR_BODY_LEN && len != Some(MAX_ERROR_BODY_LEN)
+    }
+
+    /// whether server asks client to slow down (429 Too Many Requests or 503 Service
+    /// Unavailable), the request is worth retrying later
+    pub fn is_throttling(&self) -> bool {
+        matches!(
+            self.status,
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+        )
+    }
+
+    /// delay before retrying asked by server in Retry-After header, if any
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case("retry-after"))
+            .and_then(|(_, val)| parse_retry_after(val, SystemTime::now()))
+    }
+}
+
+/// parse Retry-After header value, either a number of seconds or an HTTP-date (a date
+/// in the past means no delay)
+fn parse_retry_after(val: &str, now: SystemTime) -> Option<Duration> {
+    let val = val.trim();
+    if let Ok(secs) = val.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let date = httpdate::parse_http_date(val).ok()?;
+    Some(date.duration_since(now).unwrap_or_default())
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "server response error: {} {}",
+            self.status.as_u16(),
+            self.status.canonical_reason().unwrap_or_default()
+        )?;
+
+        let excerpt = self.excerpt();
+        if !excerpt.is_empty() {
+            write!(f, "\n{}", excerpt)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Error for HttpError {}
 
 /// One-time http client
 pub struct HttpClient {
     host_addr: String,
+    domain: String,
+    tls: bool,
+    local_addr: ConnAddr,
+    remote_addr: ConnAddr,
     rw: Option<Box<dyn ReadWrite>>,
+    timings: Timings,
     cfg: HttpConfig,
 }
 
+/// Connection a response was received on, attached to every response as an extension
+#[derive(Debug, Clone)]
+pub struct ConnInfo {
+    pub host_addr: String,
+    pub local_addr: ConnAddr,
+    pub remote_addr: ConnAddr,
+}
+
+/// Default transport: tcp (bound to configured local address/interface) plus native-tls
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TcpTransport;
+
+impl Transport for TcpTransport {
+    fn connect(
+        &self,
+        host_addr: &str,
+        domain: &str,
+        tls: bool,
+        cfg: &ConnConfig,
+    ) -> Result<Conn, PError> {
+        open_tcp_conn(host_addr, domain, tls, cfg)
+    }
+
+    fn connect_nonblocking(&self, host_addr: &str, cfg: &ConnConfig) -> Result<NbConn, PError> {
+        let mut timings = Timings::default();
+
+        let now = Instant::now();
+        let sock_addr = match cfg.bind_addr {
+            Some(local) => resolve_addr_for(host_addr, &local)?,
+            None => resolve_addr(host_addr)?,
+        };
+        timings.dns = now.elapsed();
+
+        let socket = new_socket(&sock_addr, Type::STREAM, Protocol::TCP, cfg)?;
+        socket.set_nonblocking(true)?;
+        match socket.connect(&sock_addr.into()) {
+            Err(err) if !is_in_progress(&err) => return Err(err.into()),
+            _ => {}
+        }
+        let local_addr = socket
+            .local_addr()?
+            .as_socket()
+            .ok_or_else(|| make_error("invalid local address"))?;
+
+        Ok(NbConn {
+            stream: NbStream::Tcp(mio::net::TcpStream::from_std(socket.into())),
+            local_addr: ConnAddr::Inet(local_addr),
+            remote_addr: ConnAddr::Inet(sock_addr),
+            timings,
+        })
+    }
+}
+
+/// Connects to a unix domain socket whatever the host is, the request still uses
+/// host and path from url
+#[derive(Debug, Clone)]
+pub struct UnixTransport {
+    path: PathBuf,
+}
+
+impl UnixTransport {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+        }
+    }
+}
+
+impl Transport for UnixTransport {
+    fn connect(
+        &self,
+        _host_addr: &str,
+        domain: &str,
+        tls: bool,
+        cfg: &ConnConfig,
+    ) -> Result<Conn, PError> {
+        open_unix_conn(&self.path, domain, tls, cfg)
+    }
+
+    #[cfg(unix)]
+    fn connect_nonblocking(&self, _host_addr: &str, _cfg: &ConnConfig) -> Result<NbConn, PError> {
+        let stream = mio::net::UnixStream::connect(&self.path).map_err(|e| {
+            make_error(
+                format!(
+                    "failed to connect to unix socket {}: {}",
+                    self.path.display(),
+                    e
+                )
+                .as_str(),
+            )
+        })?;
+
+        Ok(NbConn {
+            stream: NbStream::Unix(stream),
+            local_addr: ConnAddr::Unix(self.path.clone()),
+            remote_addr: ConnAddr::Unix(self.path.clone()),
+            timings: Timings::default(),
+        })
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum RedirectPolicy {
     Follow(u8), // maximum number of redirects
@@ -56,8 +260,89 @@ pub enum RedirectPolicy {
 #[derive(Debug, Clone)]
 pub struct HttpConfig {
     redirect_policy: RedirectPolicy,
-    timeout_ms: u64,
+    connect_timeout_ms: u64,
+    read_timeout_ms: u64,
     user_agent: String,
+    bind_addr: Option<IpAddr>,
+    interface: Option<String>,
+    transport: Arc<dyn Transport>,
+    http2: bool,   // offer HTTP/2 (ALPN) on tls connections of the engine
+    http3: bool,   // engine tries HTTP/3 (QUIC) first for https urls
+    label: String, // to identify the connection in debug/trace output
+    rate_limit: Option<Arc<RateLimit>>, // shared by the connections of the engine
+    conn_rate_limit: Option<u64>, // of every connection of the engine, bytes/s
+}
+
+impl HttpConfig {
+    pub fn redirect_policy(&self) -> RedirectPolicy {
+        self.redirect_policy.clone()
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_millis(self.connect_timeout_ms)
+    }
+
+    pub fn read_timeout(&self) -> Duration {
+        Duration::from_millis(self.read_timeout_ms)
+    }
+
+    pub fn user_agent(&self) -> &str {
+        &self.user_agent
+    }
+
+    /// local address connections are bound to, if any
+    pub fn bind_addr(&self) -> Option<IpAddr> {
+        self.bind_addr
+    }
+
+    /// network interface connections are bound to, if any
+    pub fn interface(&self) -> Option<&str> {
+        self.interface.as_deref()
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    pub fn transport(&self) -> Arc<dyn Transport> {
+        self.transport.clone()
+    }
+
+    pub fn http2(&self) -> bool {
+        self.http2
+    }
+
+    pub fn http3(&self) -> bool {
+        self.http3
+    }
+
+    /// buckets limiting the reads of a new connection: the shared one and one of its own
+    pub fn rate_limits(&self) -> Vec<Arc<RateLimit>> {
+        let conn = self
+            .conn_rate_limit
+            .map(|rate| Arc::new(RateLimit::new(rate)));
+        self.rate_limit.iter().cloned().chain(conn).collect()
+    }
+
+    /// settings of the connections opened by the transport
+    pub fn conn_config(&self) -> ConnConfig {
+        ConnConfig {
+            connect_timeout: self.connect_timeout(),
+            read_timeout: self.read_timeout(),
+            bind_addr: self.bind_addr(),
+            interface: self.interface().map(str::to_string),
+        }
+    }
+
+    /// identify configs whose requests to the same host can share a connection
+    pub fn conn_key(&self) -> String {
+        format!(
+            "{:?}/{:?}/{:p}",
+            self.bind_addr,
+            self.interface,
+            Arc::as_ptr(&self.transport)
+        )
+    }
 }
 
 #[allow(dead_code)]
@@ -72,13 +357,32 @@ impl HttpClient {
         tls: bool,
         cfg: &HttpConfig,
     ) -> Result<Self, PError> {
+        let conn = cfg
+            .transport
+            .connect(host_addr, domain, tls, &cfg.conn_config())?;
         Ok(Self {
             host_addr: host_addr.to_string(),
-            rw: Some(open_conn(host_addr, domain, tls, cfg.timeout_ms)?),
+            domain: domain.to_string(),
+            tls,
+            local_addr: conn.local_addr,
+            remote_addr: conn.remote_addr,
+            // trace decrypted data in case of tls
+            rw: Some(trace::wrap(&cfg.label, conn.rw)),
+            timings: conn.timings,
             cfg: cfg.clone(),
         })
     }
 
+    /// local (source) address of the underlying connection
+    pub fn local_addr(&self) -> ConnAddr {
+        self.local_addr.clone()
+    }
+
+    /// remote (server) address of the underlying connection
+    pub fn remote_addr(&self) -> ConnAddr {
+        self.remote_addr.clone()
+    }
+
     /// send a head request, because of one-time so client will be moved out after this method
     pub fn head(self, path: &str) -> Result<HttpResponse, PError> {
         let req = self.make_request(Method::HEAD, path, None).body(vec![])?;
@@ -119,64 +423,133 @@ impl HttpClient {
         self.send(&req)
     }
 
-    fn make_request(&self, method: Method, path: &str, headers: Option<&HttpHeaders>) -> Builder {
-        let mut builder = Request::builder()
-            .method(method)
-            .uri(path)
-            .header(header::HOST, &self.host_addr)
-            .header(header::USER_AGENT, &self.cfg.user_agent);
-
-        let default_headers: HashMap<&str, &str> = hash_map!(
-            "Accept" => "*/*",
-            "Accept-Encoding" => "identity",
-            "Connection" => "Keep-Alive"
-        );
+    /// send a get request for several ranges (first and last byte, inclusive) at once and
+    /// write the received data at its offset in out. Server may answer with a
+    /// multipart/byteranges body, with a single range if it coalesced them, or with the
+    /// whole file (status 200) of which only the requested ranges are written.
+    /// Returns the ranges written, server is allowed to send fewer than requested
+    pub fn get_ranges<W: Write + Seek>(
+        self,
+        path: &str,
+        ranges: &[(u64, u64)],
+        out: &mut W,
+    ) -> Result<Vec<(u64, u64)>, PError> {
+        if ranges.is_empty() {
+            return Err(make_error("no range to request"));
+        }
 
-        if let Some(headers) = headers {
-            for (key, val) in headers.iter() {
-                builder = builder.header(key, val);
+        let headers = map!(header::RANGE.to_string() => format_ranges(ranges));
+        let resp = self.get_with_headers(path, &headers)?;
+        let header = |name: header::HeaderName| {
+            resp.headers()
+                .get(name)
+                .and_then(|val| val.to_str().ok())
+                .map(|val| val.to_string())
+        };
+        let content_type = header(header::CONTENT_TYPE).unwrap_or_default();
+        let content_range = header(header::CONTENT_RANGE);
+        let is_chunked = header(header::TRANSFER_ENCODING)
+            .is_some_and(|te| te.to_ascii_lowercase().contains("chunked"));
+        let len = header(header::CONTENT_LENGTH).and_then(|len| len.parse::<u64>().ok());
+
+        let status = resp.status();
+        let body = resp.into_body();
+        let mut body: Box<dyn BufRead> = match (is_chunked, len) {
+            (true, _) => Box::new(BufReader::new(ChunkedReader::new(body))),
+            (false, Some(len)) => Box::new(body.take(len)),
+            (false, None) => Box::new(body),
+        };
+
+        // a part must not be written elsewhere than requested, but server may coalesce
+        // ranges, filling the gaps between them
+        let requested = |first: u64, last: u64| {
+            let within = |pos: u64| ranges.iter().any(|&(f, l)| f <= pos && pos <= l);
+            if first <= last && within(first) && within(last) {
+                Ok(())
+            } else {
+                Err(make_error(&format!(
+                    "server sent range {}-{} which was not requested",
+                    first, last
+                )))
+            }
+        };
+
+        let mut written = vec![];
+        match (status, multipart_boundary(&content_type)) {
+            (StatusCode::PARTIAL_CONTENT, Some(boundary)) => {
+                let mut parts = ByteRangesReader::new(body, &boundary);
+                while let Some((first, last, _)) = parts.next_part()? {
+                    requested(first, last)?;
+                    out.seek(SeekFrom::Start(first))?;
+                    io::copy(&mut parts, out)?;
+                    written.push((first, last));
+                }
+            }
+            (StatusCode::PARTIAL_CONTENT, None) => {
+                // a single range, server coalesced the requested ones
+                let (first, last, _) = content_range
+                    .and_then(|val| parse_content_range(&val))
+                    .ok_or_else(|| make_error("partial content without valid content-range"))?;
+                requested(first, last)?;
+                out.seek(SeekFrom::Start(first))?;
+                if io::copy(&mut body.take(last - first + 1), out)? < last - first + 1 {
+                    return Err(make_error("unexpected end of partial content"));
+                }
+                written.push((first, last));
+            }
+            (StatusCode::OK, _) => {
+                // the whole file, keep the requested ranges only
+                let mut buf = vec![0; 64 * 1024];
+                let mut pos = 0;
+                loop {
+                    let n = body.read(&mut buf)?;
+                    if n == 0 {
+                        break;
+                    }
+                    for &(first, last) in ranges {
+                        let (start, end) = (first.max(pos), (last + 1).min(pos + n as u64));
+                        if start < end {
+                            out.seek(SeekFrom::Start(start))?;
+                            out.write_all(&buf[(start - pos) as usize..(end - pos) as usize])?;
+                        }
+                    }
+                    pos += n as u64;
+                }
+                written = ranges
+                    .iter()
+                    .filter(|(first, _)| *first < pos)
+                    .map(|&(first, last)| (first, last.min(pos - 1)))
+                    .collect();
+            }
+            (status, _) => {
+                return Err(make_error(
+                    format!("unexpected status {} to a range request", status.as_u16()).as_str(),
+                ))
             }
         }
 
-        for (key, val) in default_headers.iter() {
-            builder = builder.header(*key, *val);
-        }
+        Ok(written)
+    }
 
-        builder
+    fn make_request(&self, method: Method, path: &str, headers: Option<&HttpHeaders>) -> Builder {
+        make_request(method, path, &self.host_addr, &self.cfg, headers)
     }
 
     fn send(mut self, req: &Request<Vec<&u8>>) -> Result<HttpResponse, PError> {
-        if req.method() != Method::GET && req.method() != Method::HEAD {
-            return Err(make_error("unsupported method"));
-        }
-
-        let mut data = format!("{} {} HTTP/1.1\r\n", req.method(), req.uri());
-        for (key, val) in req.headers().iter() {
-            data += &key.to_string();
-            data += ": ";
-            data += &val.to_str()?;
-            data += "\r\n";
-        }
-        // end of headers
-        data += "\r\n";
+        let data = format_request_head(req)?;
+        trace::debug_request(&self.cfg.label, &data);
 
         let mut rw = self.rw.take().unwrap();
         rw.write_all(data.as_bytes())?;
         rw.flush()?;
 
-        Ok(self.make_response(req, BufReader::new(ToRead(rw)))?)
-    }
-
-    fn get_status_code(&self, br: &mut BufReader<ToRead>) -> Result<StatusCode, PError> {
-        let mut buff = String::new();
-        br.read_line(&mut buff)?;
+        // wait for the first byte of response to measure time to first byte
+        let sent = Instant::now();
+        let mut br = BufReader::new(ToRead(rw));
+        br.fill_buf()?;
+        self.timings.ttfb = sent.elapsed();
 
-        let parts: Vec<&str> = buff.split_whitespace().collect();
-        if parts.len() < 3 {
-            return Err(make_error("invalid response"));
-        }
-
-        Ok(StatusCode::from_str(parts[1])?)
+        self.make_response(req, br)
     }
 
     fn make_response<T>(
@@ -184,18 +557,36 @@ impl HttpClient {
         req: &Request<T>,
         mut br: BufReader<ToRead>,
     ) -> Result<HttpResponse, PError> {
-        let status_code = self.get_status_code(&mut br)?;
+        let head = read_response_head(&mut br)?;
+        trace::debug_response(&self.cfg.label, &head);
+        if self.tls {
+            if let Some(sts) = head.header("strict-transport-security") {
+                hsts::record(&self.domain, sts);
+            }
+            if let Some(alt_svc) = head.header("alt-svc") {
+                altsvc::record(&self.host_addr, alt_svc);
+            }
+        }
+        let status_code = head.status;
         if status_code.as_u16() / 100 >= 4 {
-            return Err(make_error(
-                format!("server response error: {}", status_code.as_u16(),).as_str(),
-            ));
+            let body = if req.method() == Method::HEAD {
+                vec![]
+            } else {
+                read_error_body(&head, br)
+            };
+
+            return Err(Box::new(HttpError {
+                status: status_code,
+                headers: head.headers,
+                body,
+            }));
         }
         if status_code.as_u16() / 100 == 3 {
             match self.cfg.redirect_policy {
                 RedirectPolicy::None => return Err(make_error("redirect is not supported")),
                 RedirectPolicy::Follow(max_redirects) => {
                     return if max_redirects > 0 {
-                        self.handle_redirect(req, &status_code, br, max_redirects)
+                        self.handle_redirect(req, &head, max_redirects)
                     } else {
                         Err(make_error("max redirects exceeded"))
                     }
@@ -203,8 +594,16 @@ impl HttpClient {
             }
         }
 
-        let mut builder = Response::builder().status(status_code);
-        for (key, val) in HeaderIterator::from(&mut br) {
+        let mut builder = Response::builder()
+            .status(status_code)
+            .version(head.version)
+            .extension(self.timings)
+            .extension(ConnInfo {
+                host_addr: self.host_addr.clone(),
+                local_addr: self.local_addr.clone(),
+                remote_addr: self.remote_addr.clone(),
+            });
+        for (key, val) in head.headers {
             builder = builder.header(key, val);
         }
 
@@ -214,34 +613,42 @@ impl HttpClient {
     fn handle_redirect<T>(
         self,
         req: &Request<T>,
-        status_code: &StatusCode, // only for logging purposes
-        mut br: BufReader<ToRead>,
+        head: &ResponseHead,
         max_redirects: u8,
     ) -> Result<HttpResponse, PError> {
-        for (key, val) in HeaderIterator::from(&mut br) {
-            let key = key.to_lowercase();
-            if key.trim() == "location" {
-                println!("Redirecting to: {}", val);
-                // build new client with same config from current one
-                let client = HttpClientBuilder::new()
-                    .from_url(&val)?
-                    .with_config(&self.cfg)
-                    .with_timeout_ms(self.cfg.timeout_ms)
-                    .with_redirect_policy(RedirectPolicy::Follow(max_redirects - 1))
-                    .build()?;
-
-                match *req.method() {
-                    Method::GET => return client.get(&val),
-                    Method::HEAD => return client.head(&val),
-                    _ => return Err(make_error("unsupported method")),
-                }
+        if let Some(val) = head.header("location") {
+            statusln!("Redirecting to: {}", val);
+            let mut urlinfo = UrlInfo::parse(val)?;
+            if hsts::upgrade(&mut urlinfo) {
+                statusln!("HSTS: upgrading to https");
             }
+
+            // build new client with same config from current one
+            let client = HttpClientBuilder::new()
+                .from_url_info(&urlinfo)
+                .with_config(&self.cfg)
+                .with_redirect_policy(RedirectPolicy::Follow(max_redirects - 1))
+                .build()?;
+
+            // a ranged request stays one at the new location
+            let headers: HttpHeaders = [header::RANGE, header::IF_RANGE]
+                .iter()
+                .filter_map(|name| {
+                    let val = req.headers().get(name)?.to_str().ok()?;
+                    Some((name.to_string(), val.to_string()))
+                })
+                .collect();
+            return match *req.method() {
+                Method::GET => client.get_with_headers(&urlinfo.path, &headers),
+                Method::HEAD => client.head_with_headers(&urlinfo.path, &headers),
+                _ => Err(make_error("unsupported method")),
+            };
         }
 
         Err(make_error(
             format!(
                 "server return {} but no location header was found",
-                status_code.as_u16()
+                head.status.as_u16()
             )
             .as_str(),
         ))
@@ -255,7 +662,7 @@ pub struct HttpClientBuilder {
     cfg: HttpConfig,
 }
 
-#[allow(dead_code)]
+#[allow(dead_code, clippy::wrong_self_convention)]
 impl HttpClientBuilder {
     pub fn new() -> HttpClientBuilder {
         HttpClientBuilder {
@@ -264,8 +671,17 @@ impl HttpClientBuilder {
             domain: String::new(),
             cfg: HttpConfig {
                 redirect_policy: DEFAULT_REDIRECT_POLICY,
-                timeout_ms: DEFAULT_TIMEOUT_MS,
+                connect_timeout_ms: DEFAULT_TIMEOUT_MS,
+                read_timeout_ms: DEFAULT_TIMEOUT_MS,
                 user_agent: DEFAULT_USER_AGENT.to_string(),
+                bind_addr: None,
+                interface: None,
+                transport: Arc::new(TcpTransport),
+                http2: true,
+                http3: false,
+                label: String::new(),
+                rate_limit: None,
+                conn_rate_limit: None,
             },
         }
     }
@@ -274,7 +690,11 @@ impl HttpClientBuilder {
         Ok(self.from_url_info(&UrlInfo::parse(url)?))
     }
 
+    /// http urls of known HSTS hosts are upgraded to https
     pub fn from_url_info(mut self, urlinfo: &UrlInfo) -> HttpClientBuilder {
+        let mut urlinfo = urlinfo.clone();
+        hsts::upgrade(&mut urlinfo);
+
         self.host_addr = urlinfo.host_addr();
         self.tls = urlinfo.is_tls();
 
@@ -284,13 +704,31 @@ impl HttpClientBuilder {
         self
     }
 
+    /// config of clients to be built, e.g. to run requests on the event loop engine
+    pub fn config(&self) -> HttpConfig {
+        self.cfg.clone()
+    }
+
     pub fn with_config(mut self, cfg: &HttpConfig) -> HttpClientBuilder {
         self.cfg = cfg.clone();
         self
     }
 
+    /// set both connect and read/write timeout
     pub fn with_timeout_ms(mut self, timeout_ms: u64) -> HttpClientBuilder {
-        self.cfg.timeout_ms = timeout_ms;
+        self.cfg.connect_timeout_ms = timeout_ms;
+        self.cfg.read_timeout_ms = timeout_ms;
+        self
+    }
+
+    pub fn with_connect_timeout_ms(mut self, timeout_ms: u64) -> HttpClientBuilder {
+        self.cfg.connect_timeout_ms = timeout_ms;
+        self
+    }
+
+    /// timeout of every single read/write on the connection, not of the whole transfer
+    pub fn with_read_timeout_ms(mut self, timeout_ms: u64) -> HttpClientBuilder {
+        self.cfg.read_timeout_ms = timeout_ms;
         self
     }
 
@@ -306,6 +744,60 @@ impl HttpClientBuilder {
         self
     }
 
+    /// bind the local end of the connection to the given source address
+    pub fn with_bind_addr(mut self, addr: IpAddr) -> HttpClientBuilder {
+        self.cfg.bind_addr = Some(addr);
+        self
+    }
+
+    /// bind the connection to the given network interface (Linux only)
+    pub fn with_interface(mut self, name: &str) -> HttpClientBuilder {
+        self.cfg.interface = Some(name.to_string());
+        self
+    }
+
+    /// open connections using given transport instead of tcp, it is kept on redirects
+    pub fn with_transport(mut self, transport: Arc<dyn Transport>) -> HttpClientBuilder {
+        self.cfg.transport = transport;
+        self
+    }
+
+    /// whether requests run on the event loop engine may use HTTP/2 (negotiated with ALPN,
+    /// tls only), the client itself always speaks HTTP/1.1
+    pub fn with_http2(mut self, enabled: bool) -> HttpClientBuilder {
+        self.cfg.http2 = enabled;
+        self
+    }
+
+    /// whether requests to https urls run on the event loop engine try HTTP/3 first (at
+    /// the alternative advertised by the server with Alt-Svc, if any), falling back to tcp
+    pub fn with_http3(mut self, enabled: bool) -> HttpClientBuilder {
+        self.cfg.http3 = enabled;
+        self
+    }
+
+    /// limit the aggregate read rate of the connections of all the requests sharing given
+    /// bucket, for requests run on the event loop engine
+    pub fn with_rate_limit(mut self, limit: Arc<RateLimit>) -> HttpClientBuilder {
+        self.cfg.rate_limit = Some(limit);
+        self
+    }
+
+    /// limit the read rate of every connection (bytes per second) of requests run on the
+    /// event loop engine
+    pub fn with_conn_rate_limit(mut self, rate: u64) -> HttpClientBuilder {
+        self.cfg.conn_rate_limit = Some(rate);
+        self
+    }
+
+    /// label used to identify connections of this client in debug/trace output
+    pub fn with_label(mut self, label: &str) -> HttpClientBuilder {
+        self.cfg.label.clear();
+        self.cfg.label += label;
+
+        self
+    }
+
     pub fn with_host_addr(mut self, addr: &str) -> HttpClientBuilder {
         self.host_addr.clear();
         self.host_addr += addr;
@@ -333,39 +825,154 @@ impl HttpClientBuilder {
             &self.cfg,
         )
     }
-}
 
-struct HeaderIterator<'a> {
-    br: &'a mut BufReader<ToRead>,
-    buf: String,
+    /// client sending another request on the connection of given response, whose body
+    /// must have been read entirely, if the server keeps it alive
+    pub fn build_on(self, resp: HttpResponse) -> Result<HttpClient, PError> {
+        let info = resp
+            .extensions()
+            .get::<ConnInfo>()
+            .cloned()
+            .ok_or_else(|| make_error("response has no connection"))?;
+        if info.host_addr != self.host_addr {
+            return Err(make_error("response comes from another host"));
+        }
+        let closing = resp.version() != Version::HTTP_11
+            || resp
+                .headers()
+                .get(header::CONNECTION)
+                .is_some_and(|conn| conn.as_bytes().eq_ignore_ascii_case(b"close"));
+        let body = resp.into_body();
+        if closing || !body.buffer().is_empty() {
+            return Err(make_error("connection of response cannot be reused"));
+        }
+
+        Ok(HttpClient {
+            host_addr: self.host_addr,
+            domain: self.domain,
+            tls: self.tls,
+            local_addr: info.local_addr,
+            remote_addr: info.remote_addr,
+            rw: Some(body.into_inner().0),
+            timings: Timings::default(), // connection is set up already
+            cfg: self.cfg,
+        })
+    }
 }
 
-impl HeaderIterator<'_> {
-    fn from(br: &mut BufReader<ToRead>) -> HeaderIterator {
-        HeaderIterator {
-            br,
-            buf: String::new(),
+/// request with Host, User-Agent and default headers, custom headers go first
+fn make_request(
+    method: Method,
+    path: &str,
+    host_addr: &str,
+    cfg: &HttpConfig,
+    headers: Option<&HttpHeaders>,
+) -> Builder {
+    let mut builder = Request::builder()
+        .method(method)
+        .uri(path)
+        .header(header::HOST, host_addr)
+        .header(header::USER_AGENT, cfg.user_agent());
+
+    let default_headers: HashMap<&str, &str> = hash_map!(
+        "Accept" => "*/*",
+        "Accept-Encoding" => "identity",
+        "Connection" => "Keep-Alive"
+    );
+
+    if let Some(headers) = headers {
+        for (key, val) in headers.iter() {
+            builder = builder.header(key, val);
         }
     }
+
+    for (key, val) in default_headers.iter() {
+        builder = builder.header(*key, *val);
+    }
+
+    builder
 }
 
-impl Iterator for HeaderIterator<'_> {
-    type Item = (String, String);
+/// GET request to be sent by the event loop engine
+pub fn get_request(
+    path: &str,
+    host_addr: &str,
+    cfg: &HttpConfig,
+    headers: &HttpHeaders,
+) -> Result<Request<()>, PError> {
+    Ok(make_request(Method::GET, path, host_addr, cfg, Some(headers)).body(())?)
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        self.buf.clear();
+/// format head of a GET request to be sent by the event loop engine
+pub fn get_request_head(
+    path: &str,
+    host_addr: &str,
+    cfg: &HttpConfig,
+    headers: &HttpHeaders,
+) -> Result<String, PError> {
+    format_request_head(&get_request(path, host_addr, cfg, headers)?)
+}
 
-        // read_line may block forever if no endline found
-        if let Ok(n) = self.br.read_line(&mut self.buf) {
-            // len > 2 because read_line always includes \r\n
-            if n > 2 {
-                return parse_header(&self.buf.trim_end())
-                    .map(|(key, val)| (key.to_string(), val.to_string()));
-            }
-        }
+/// value of a Range header requesting given ranges (first and last byte, inclusive)
+pub fn format_ranges(ranges: &[(u64, u64)]) -> String {
+    let ranges: Vec<String> = ranges
+        .iter()
+        .map(|(first, last)| format!("{}-{}", first, last))
+        .collect();
+    format!("bytes={}", ranges.join(","))
+}
 
-        None
+/// request line and headers as sent on the wire (for HTTP/1.1) or as shown in debug output
+pub fn format_request_head<T>(req: &Request<T>) -> Result<String, PError> {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return Err(make_error("unsupported method"));
     }
+
+    let version = match req.version() {
+        Version::HTTP_2 => "HTTP/2",
+        Version::HTTP_3 => "HTTP/3",
+        _ => "HTTP/1.1",
+    };
+    let mut data = format!("{} {} {}\r\n", req.method(), req.uri(), version);
+    for (key, val) in req.headers().iter() {
+        data += key.as_ref();
+        data += ": ";
+        data += val.to_str()?;
+        data += "\r\n";
+    }
+    // end of headers
+    data += "\r\n";
+
+    Ok(data)
+}
+
+/// Read (a bounded part of) body of an error response, best effort: read errors are
+/// ignored and body of unknown length is only read if server is going to close connection
+fn read_error_body(head: &ResponseHead, br: impl BufRead) -> Vec<u8> {
+    let is_chunked = head
+        .header("transfer-encoding")
+        .map(|te| te.to_ascii_lowercase().contains("chunked"))
+        .unwrap_or_default();
+    let content_len = head
+        .header("content-length")
+        .and_then(|len| len.parse::<u64>().ok());
+    let closing = head.version == Version::HTTP_10
+        || head
+            .header("connection")
+            .map(|conn| conn.eq_ignore_ascii_case("close"))
+            .unwrap_or_default();
+
+    let mut body = vec![];
+    let _ = match (is_chunked, content_len) {
+        (true, _) => ChunkedReader::new(br)
+            .take(MAX_ERROR_BODY_LEN)
+            .read_to_end(&mut body),
+        (false, Some(len)) => br.take(len.min(MAX_ERROR_BODY_LEN)).read_to_end(&mut body),
+        (false, None) if closing => br.take(MAX_ERROR_BODY_LEN).read_to_end(&mut body),
+        _ => Ok(0),
+    };
+
+    body
 }
 
 pub fn resolve_addr(addr: &str) -> Result<SocketAddr, PError> {
@@ -375,14 +982,21 @@ pub fn resolve_addr(addr: &str) -> Result<SocketAddr, PError> {
     }
 
     let sock_addr = sock_addrs.next().unwrap();
-    let sock_addr = sock_addrs
-        .filter(|ip| ip.is_ipv4())
-        .next()
-        .unwrap_or_else(|| sock_addr); // try to use ipv4 address if available
+    let sock_addr = sock_addrs.find(|ip| ip.is_ipv4()).unwrap_or(sock_addr); // try to use ipv4 address if available
 
     Ok(sock_addr)
 }
 
+/// resolve address but only keep the ones of the same family as the local address,
+/// since a socket bound to an ipv4 address can not connect to an ipv6 one and vice versa
+pub fn resolve_addr_for(addr: &str, local: &IpAddr) -> Result<SocketAddr, PError> {
+    addr.to_socket_addrs()?
+        .find(|sa| sa.is_ipv4() == local.is_ipv4())
+        .ok_or_else(|| {
+            make_error(format!("no host address found matching local address {}", local).as_str())
+        })
+}
+
 #[allow(dead_code)]
 pub fn head(url: &str) -> Result<HttpResponse, PError> {
     let ui = UrlInfo::parse(url)?;
@@ -401,32 +1015,441 @@ pub fn get(url: &str) -> Result<HttpResponse, PError> {
         .get(&ui.path)
 }
 
-fn open_conn(
+fn open_tcp_conn(
     host_addr: &str,
     domain: &str,
     tls: bool,
-    timeout_ms: u64,
-) -> Result<Box<dyn ReadWrite>, PError> {
-    let dur = Duration::from_millis(timeout_ms);
-    let sock_addr = resolve_addr(host_addr)?;
+    cfg: &ConnConfig,
+) -> Result<Conn, PError> {
+    let mut timings = Timings::default();
+
+    let now = Instant::now();
+    let sock_addr = match &cfg.bind_addr {
+        Some(local) => resolve_addr_for(host_addr, local)?,
+        None => resolve_addr(host_addr)?,
+    };
+    timings.dns = now.elapsed();
+
+    let now = Instant::now();
+    let stream = connect_tcp(&sock_addr, cfg)?;
+    timings.connect = now.elapsed();
+
+    let dur = cfg.read_timeout;
+    stream.set_read_timeout(Some(dur))?;
+    stream.set_write_timeout(Some(dur))?;
+    let local_addr = stream.local_addr()?;
+
+    Ok(Conn {
+        rw: wrap_tls(stream, domain, tls, &mut timings)?,
+        local_addr: ConnAddr::Inet(local_addr),
+        remote_addr: ConnAddr::Inet(sock_addr),
+        timings,
+    })
+}
+
+#[cfg(unix)]
+fn open_unix_conn(path: &Path, domain: &str, tls: bool, cfg: &ConnConfig) -> Result<Conn, PError> {
+    use std::os::unix::net::UnixStream;
 
-    let stream = TcpStream::connect_timeout(&sock_addr, dur)?;
+    let mut timings = Timings::default();
+
+    // there is no connect timeout for unix sockets, but connecting to a local socket
+    // either succeeds or fails immediately
+    let now = Instant::now();
+    let stream = UnixStream::connect(path).map_err(|e| {
+        make_error(format!("failed to connect to unix socket {}: {}", path.display(), e).as_str())
+    })?;
+    timings.connect = now.elapsed();
+
+    let dur = cfg.read_timeout;
     stream.set_read_timeout(Some(dur))?;
     stream.set_write_timeout(Some(dur))?;
 
-    if tls {
-        let tls_conn = TlsConnector::new()?;
-        let stream = tls_conn.connect(domain, stream)?;
-        Ok(Box::new(stream))
-    } else {
-        Ok(Box::new(stream))
+    Ok(Conn {
+        rw: wrap_tls(stream, domain, tls, &mut timings)?,
+        local_addr: ConnAddr::Unix(path.to_path_buf()),
+        remote_addr: ConnAddr::Unix(path.to_path_buf()),
+        timings,
+    })
+}
+
+#[cfg(not(unix))]
+fn open_unix_conn(
+    _path: &Path,
+    _domain: &str,
+    _tls: bool,
+    _cfg: &ConnConfig,
+) -> Result<Conn, PError> {
+    Err(make_error(
+        "unix sockets are not supported on this platform",
+    ))
+}
+
+/// do tls handshake if needed
+fn wrap_tls<S>(
+    stream: S,
+    domain: &str,
+    tls: bool,
+    timings: &mut Timings,
+) -> Result<Box<dyn ReadWrite>, PError>
+where
+    S: Read + Write + fmt::Debug + Send + Sync + 'static,
+{
+    if !tls {
+        return Ok(Box::new(stream));
     }
+
+    let now = Instant::now();
+    let tls_conn = tls_connector().build()?;
+    let stream = tls_conn.connect(domain, stream)?;
+    timings.tls = now.elapsed();
+
+    Ok(Box::new(stream))
+}
+
+/// native-tls connector trusting the system roots, and the certificate of test servers
+/// in test builds
+pub fn tls_connector() -> TlsConnectorBuilder {
+    #[allow(unused_mut)] // only changed in test builds
+    let mut builder = TlsConnector::builder();
+    #[cfg(test)]
+    if let Ok(cert) =
+        native_tls::Certificate::from_pem(crate::h3server::test_cert().cert_pem.as_bytes())
+    {
+        builder.add_root_certificate(cert);
+    }
+
+    builder
+}
+
+/// open a tcp connection, binding the socket to the configured local address and/or
+/// network interface (if any) before connecting
+fn connect_tcp(sock_addr: &SocketAddr, cfg: &ConnConfig) -> Result<TcpStream, PError> {
+    let timeout = cfg.connect_timeout;
+    if cfg.bind_addr.is_none() && cfg.interface.is_none() {
+        return Ok(TcpStream::connect_timeout(sock_addr, timeout)?);
+    }
+
+    let socket = new_socket(sock_addr, Type::STREAM, Protocol::TCP, cfg)?;
+    socket.connect_timeout(&(*sock_addr).into(), timeout)?;
+    Ok(socket.into())
+}
+
+/// open a non-blocking udp socket connected to given host (for QUIC), bound to the
+/// configured local address and/or network interface (if any), returns it with the time
+/// spent resolving host
+pub fn connect_udp(host_addr: &str, cfg: &ConnConfig) -> Result<(UdpSocket, Duration), PError> {
+    let now = Instant::now();
+    let sock_addr = match &cfg.bind_addr {
+        Some(local) => resolve_addr_for(host_addr, local)?,
+        None => resolve_addr(host_addr)?,
+    };
+    let dns = now.elapsed();
+
+    let socket = new_socket(&sock_addr, Type::DGRAM, Protocol::UDP, cfg)?;
+    socket.set_nonblocking(true)?;
+    socket.connect(&sock_addr.into())?;
+
+    Ok((socket.into(), dns))
+}
+
+/// create a socket for given remote address, bound to the configured local address
+/// and/or network interface (if any)
+fn new_socket(
+    sock_addr: &SocketAddr,
+    ty: Type,
+    protocol: Protocol,
+    cfg: &ConnConfig,
+) -> Result<Socket, PError> {
+    let socket = Socket::new(Domain::for_address(*sock_addr), ty, Some(protocol))?;
+    if let Some(name) = cfg.interface.as_deref() {
+        bind_device(&socket, name)?;
+    }
+    if let Some(ip) = cfg.bind_addr {
+        socket
+            .bind(&SocketAddr::new(ip, 0).into())
+            .map_err(|e| make_error(format!("failed to bind to {}: {}", ip, e).as_str()))?;
+    }
+
+    Ok(socket)
+}
+
+/// whether error returned by connect on a non-blocking socket means connecting is in progress
+#[cfg(unix)]
+fn is_in_progress(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::EINPROGRESS)
 }
 
-fn parse_header(header: &str) -> Option<(&str, &str)> {
-    if let Some(pos) = header.find(':') {
-        Some((&header[..pos].trim(), &header[pos + 1..].trim()))
-    } else {
-        None
+#[cfg(not(unix))]
+fn is_in_progress(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::WouldBlock
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_device(socket: &Socket, name: &str) -> Result<(), PError> {
+    socket
+        .bind_device(Some(name.as_bytes()))
+        .map_err(|e| make_error(format!("failed to bind to interface {}: {}", name, e).as_str()))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_device(_socket: &Socket, _name: &str) -> Result<(), PError> {
+    Err(make_error(
+        "binding to a network interface is not supported on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memtransport::{Fault, MemTransport};
+
+    fn client(transport: &MemTransport, url: &str) -> HttpClientBuilder {
+        HttpClient::builder()
+            .from_url(url)
+            .unwrap()
+            .with_transport(Arc::new(transport.clone()))
+    }
+
+    #[test]
+    fn test_config() {
+        let cfg = HttpClient::builder()
+            .with_connect_timeout_ms(2000)
+            .with_read_timeout_ms(3000)
+            .with_user_agent("test/1.0")
+            .with_bind_addr("127.0.0.1".parse().unwrap())
+            .with_interface("lo")
+            .config();
+        assert_eq!(Duration::from_secs(2), cfg.connect_timeout());
+        assert_eq!(Duration::from_secs(3), cfg.read_timeout());
+        assert_eq!("test/1.0", cfg.user_agent());
+        assert_eq!(Some("127.0.0.1".parse().unwrap()), cfg.bind_addr());
+        assert_eq!(Some("lo"), cfg.interface());
+
+        let cfg = HttpClient::builder().config();
+        assert_eq!((None, None), (cfg.bind_addr(), cfg.interface()));
+    }
+
+    #[test]
+    fn test_error_body_cut() {
+        let len = MAX_ERROR_BODY_LEN as usize;
+        let err = |body_len: usize, content_len: Option<usize>| HttpError {
+            status: StatusCode::NOT_FOUND,
+            headers: content_len
+                .map(|len| ("Content-Length".to_string(), len.to_string()))
+                .into_iter()
+                .collect(),
+            body: vec![b'x'; body_len],
+        };
+        assert!(!err(10, Some(10)).body_cut());
+        assert!(!err(len, Some(len)).body_cut());
+        assert!(err(len, Some(len + 1)).body_cut());
+        assert!(err(len, None).body_cut());
+    }
+
+    #[test]
+    fn test_redirect() {
+        let transport = MemTransport::new()
+            .serve("http://b.test/file", b"hello")
+            .redirect("http://a.test/file", "http://b.test:8080/file")
+            .redirect("http://b.test:8080/file", "http://b.test/file");
+
+        let resp = client(&transport, "http://a.test/file")
+            .build()
+            .unwrap()
+            .get("/file")
+            .unwrap();
+        let mut body = String::new();
+        resp.into_body().read_to_string(&mut body).unwrap();
+
+        assert_eq!("hello", body);
+        assert_eq!(
+            vec![
+                "GET a.test:80/file -",
+                "GET b.test:8080/file -",
+                "GET b.test:80/file -"
+            ],
+            transport.requests()
+        );
+
+        let err = client(&transport, "http://a.test/file")
+            .with_redirect_policy(RedirectPolicy::Follow(1))
+            .build()
+            .unwrap()
+            .get("/file")
+            .err()
+            .unwrap();
+        assert_eq!("max redirects exceeded", err.to_string());
+
+        // the range asked for is asked for at the new location too, if still the same
+        // version of the file
+        let transport = MemTransport::new()
+            .serve("http://b.test/file", b"hello")
+            .redirect("http://a.test/file", "http://b.test/file");
+        let get = |headers: &HttpHeaders| {
+            let resp = client(&transport, "http://a.test/file")
+                .build()
+                .unwrap()
+                .get_with_headers("/file", headers)
+                .unwrap();
+            let status = resp.status();
+            let mut body = String::new();
+            resp.into_body().read_to_string(&mut body).unwrap();
+            (status, body)
+        };
+        let mut headers = map!("Range".to_string() => "bytes=1-2".to_string());
+        assert_eq!(
+            (StatusCode::PARTIAL_CONTENT, "el".to_string()),
+            get(&headers)
+        );
+        headers.insert("If-Range".to_string(), "\"v0\"".to_string());
+        assert_eq!((StatusCode::OK, "hello".to_string()), get(&headers));
+        assert_eq!(
+            vec![
+                "GET a.test:80/file 1-2",
+                "GET b.test:80/file 1-2",
+                "GET a.test:80/file 1-2",
+                "GET b.test:80/file 1-2"
+            ],
+            transport.requests()
+        );
+        assert_eq!(
+            vec![
+                None,
+                None,
+                Some("\"v0\"".to_string()),
+                Some("\"v0\"".to_string())
+            ],
+            transport.request_header("if-range")
+        );
+    }
+
+    #[test]
+    fn test_error_response() {
+        let transport = MemTransport::new()
+            .serve("http://a.test/file", b"hello")
+            .fault(None, Fault::Status(503));
+
+        let err = client(&transport, "http://a.test/file")
+            .build()
+            .unwrap()
+            .get("/file")
+            .err()
+            .unwrap();
+        let err = err.downcast_ref::<HttpError>().unwrap();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, err.status);
+        assert_eq!(b"fault", &err.body[..]);
+
+        let err = client(&transport, "http://a.test/missing")
+            .build()
+            .unwrap()
+            .head("/missing")
+            .err()
+            .unwrap();
+        let err = err.downcast_ref::<HttpError>().unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, err.status);
+        assert!(err.body.is_empty());
+        assert!(!err.is_throttling());
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let now = httpdate::parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT").unwrap();
+        assert_eq!(
+            Some(Duration::from_secs(120)),
+            parse_retry_after(" 120", now)
+        );
+        assert_eq!(
+            Some(Duration::from_secs(60)),
+            parse_retry_after("Sun, 06 Nov 1994 08:50:37 GMT", now)
+        );
+        assert_eq!(
+            Some(Duration::ZERO),
+            parse_retry_after("Sun, 06 Nov 1994 08:00:00 GMT", now)
+        );
+        assert_eq!(None, parse_retry_after("soon", now));
+        assert_eq!(None, parse_retry_after("-1", now));
+
+        let transport = MemTransport::new()
+            .serve("http://a.test/file", b"hello")
+            .fault(None, Fault::Throttle(429, 3));
+        let err = client(&transport, "http://a.test/file")
+            .build()
+            .unwrap()
+            .get("/file")
+            .err()
+            .unwrap();
+        let err = err.downcast_ref::<HttpError>().unwrap();
+        assert!(err.is_throttling());
+        assert_eq!(Some(Duration::from_secs(3)), err.retry_after());
+    }
+
+    #[test]
+    fn test_get_ranges() {
+        let data: Vec<u8> = (0..100).collect();
+        let ranges = [(10, 19), (50, 54), (90, 99)];
+        let get_ranges = |transport: &MemTransport| {
+            let mut out = io::Cursor::new(vec![0u8; 100]);
+            let written = client(transport, "http://a.test/file")
+                .build()
+                .unwrap()
+                .get_ranges("/file", &ranges, &mut out)
+                .unwrap();
+            (written, out.into_inner())
+        };
+        let expected = |covered: &[(u64, u64)]| {
+            let mut expected = vec![0u8; 100];
+            for &(first, last) in covered {
+                expected[first as usize..=last as usize]
+                    .copy_from_slice(&data[first as usize..=last as usize]);
+            }
+            expected
+        };
+
+        // multipart/byteranges
+        let transport = MemTransport::new().serve("http://a.test/file", &data);
+        let (written, out) = get_ranges(&transport);
+        assert_eq!(ranges.to_vec(), written);
+        assert_eq!(expected(&ranges), out);
+        assert_eq!(
+            vec!["GET a.test:80/file 10-19,50-54,90-99"],
+            transport.requests()
+        );
+
+        // ranges coalesced into one
+        let transport = transport.fault(None, Fault::CoalesceRanges);
+        let (written, out) = get_ranges(&transport);
+        assert_eq!(vec![(10, 99)], written);
+        assert_eq!(expected(&[(10, 99)]), out);
+
+        // whole file, only the requested ranges are written
+        let transport = transport.fault(None, Fault::IgnoreRange);
+        let (written, out) = get_ranges(&transport);
+        assert_eq!(ranges.to_vec(), written);
+        assert_eq!(expected(&ranges), out);
+
+        let transport = transport.fault(None, Fault::Truncate(300));
+        let err = client(&transport, "http://a.test/file")
+            .build()
+            .unwrap()
+            .get_ranges("/file", &ranges, &mut io::Cursor::new(vec![]))
+            .err()
+            .unwrap();
+        assert_eq!("unexpected end of multipart body", err.to_string());
+
+        // parts are only written where requested
+        let transport = transport.fault(None, Fault::MoveRange(15));
+        let mut out = io::Cursor::new(vec![0u8; 100]);
+        let err = client(&transport, "http://a.test/file")
+            .build()
+            .unwrap()
+            .get_ranges("/file", &ranges, &mut out)
+            .err()
+            .unwrap();
+        assert_eq!(
+            "server sent range 25-34 which was not requested",
+            err.to_string()
+        );
+        assert_eq!(vec![0u8; 100], out.into_inner());
     }
 }
@@ -1,7 +1,16 @@
-use std::{error::Error, fmt};
+use std::{
+    error::Error,
+    fmt,
+    net::IpAddr,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 use clap::Parser;
 
+pub mod httpparse;
+pub mod progress;
+pub mod transport;
+
 #[allow(dead_code)]
 #[macro_export]
 macro_rules! map {
@@ -30,6 +39,41 @@ macro_rules! hash_map_e {
     };
 }
 
+/// print a status message like `print!`, on stderr if stdout carries the downloaded file
+#[macro_export]
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if $crate::status_to_stderr() {
+            eprint!($($arg)*);
+        } else {
+            print!($($arg)*);
+        }
+    };
+}
+
+/// print a status message like `println!`, on stderr if stdout carries the downloaded file
+#[macro_export]
+macro_rules! statusln {
+    ($($arg:tt)*) => {
+        if $crate::status_to_stderr() {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// whether status messages are printed on stderr, set once stdout carries the file (`-o -`)
+static STATUS_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+pub fn status_to_stderr() -> bool {
+    STATUS_TO_STDERR.load(Ordering::Relaxed)
+}
+
+pub fn set_status_to_stderr(val: bool) {
+    STATUS_TO_STDERR.store(val, Ordering::Relaxed);
+}
+
 /// box of error (pointer to actual error object)
 pub type PError = Box<dyn Error>;
 pub type VoidResult = Result<(), PError>;
@@ -49,12 +93,72 @@ pub fn make_error(err: &str) -> PError {
     Box::new(FgetError(err.to_string()))
 }
 
+/// parse a size in bytes with an optional binary unit suffix, e.g. `512K` or `8M`
+pub fn parse_size(val: &str) -> Result<u64, String> {
+    let val = val.trim();
+    let (num, unit) = match val.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((pos, _)) => val.split_at(pos),
+        None => (val, ""),
+    };
+    let mul: u64 = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        _ => return Err(format!("invalid size unit '{}', expected K, M or G", unit)),
+    };
+
+    num.parse::<u64>()
+        .ok()
+        .and_then(|num| num.checked_mul(mul))
+        .ok_or_else(|| format!("invalid size '{}'", val))
+}
+
+/// parse a byte range `<first>-<last>` (inclusive)
+pub fn parse_range(val: &str) -> Result<(u64, u64), String> {
+    val.trim()
+        .split_once('-')
+        .and_then(|(first, last)| Some((first.parse().ok()?, last.parse().ok()?)))
+        .filter(|(first, last)| first <= last)
+        .ok_or_else(|| format!("invalid range '{}', expected FIRST-LAST", val))
+}
+
+/// number of concurrent connections: a fixed one, or tuned from the measured throughput
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NumThreads {
+    Fixed(u16),
+    Auto,
+}
+
+/// upper bound of --num-threads and --max-threads, each connection holds a socket and
+/// a part file open
+const MAX_THREADS: u16 = 256;
+
+/// parse a number of connections, or `auto`
+pub fn parse_num_threads(val: &str) -> Result<NumThreads, String> {
+    if val.eq_ignore_ascii_case("auto") {
+        return Ok(NumThreads::Auto);
+    }
+    val.parse::<u16>().map(NumThreads::Fixed).map_err(|_| {
+        format!(
+            "invalid number of threads '{}', expected a number or auto",
+            val
+        )
+    })
+}
+
 #[derive(Parser, Debug, Clone)]
 #[clap(author, version, about, long_about = None)]
 pub struct Config {
     pub url: String,
 
-    #[clap(short, long, value_parser, value_name = "FILE")]
+    #[clap(
+        short,
+        long,
+        value_parser,
+        value_name = "FILE",
+        help = "Write to FILE, - for stdout (status messages then go to stderr)"
+    )]
     pub output: Option<String>,
 
     #[clap(
@@ -67,12 +171,48 @@ pub struct Config {
 
     #[clap(
         short = 't',
+        long,
+        value_parser = parse_num_threads,
+        value_name = "N|auto",
+        default_value = "4",
+        help = "Number of concurrent downloads (if supported by server) using http-range, auto adds connections while the throughput improves"
+    )]
+    pub num_threads: NumThreads,
+
+    #[clap(
         long,
         value_parser,
-        default_value_t = 4,
-        help = "Number of concurrent downloads (if supported by server) using http-range"
+        value_name = "N",
+        default_value_t = 2,
+        help = "Number of connections --num-threads auto starts with"
+    )]
+    pub min_threads: u16,
+
+    #[clap(
+        long,
+        value_parser,
+        value_name = "N",
+        default_value_t = 16,
+        help = "Maximum number of connections of --num-threads auto"
+    )]
+    pub max_threads: u16,
+
+    #[clap(
+        long,
+        value_parser = parse_size,
+        value_name = "SIZE",
+        help = "Cut the file into parts of about SIZE (e.g. 8M) pulled by --num-threads connections"
+    )]
+    pub split_size: Option<u64>,
+
+    #[clap(
+        long,
+        value_parser = parse_size,
+        value_name = "SIZE",
+        default_value = "1M",
+        help = "Do not cut the file into parts shorter than SIZE, nor split parts for idle connections"
     )]
-    pub num_threads: u8,
+    pub min_split_size: u64,
 
     #[clap(
         short,
@@ -96,17 +236,306 @@ pub struct Config {
         help = "TCP connection/read/write timeout in seconds"
     )]
     pub timeout: u8,
+
+    #[clap(
+        long,
+        value_parser,
+        value_name = "SECS",
+        help = "TCP connection timeout in seconds, overrides --timeout"
+    )]
+    pub connect_timeout: Option<u64>,
+
+    #[clap(
+        long,
+        value_parser,
+        value_name = "SECS",
+        help = "Timeout of a single read/write in seconds, overrides --timeout"
+    )]
+    pub read_timeout: Option<u64>,
+
+    #[clap(
+        short = 'm',
+        long,
+        value_parser,
+        value_name = "SECS",
+        help = "Maximum time in seconds allowed for the whole download"
+    )]
+    pub max_time: Option<u64>,
+
+    #[clap(
+        long,
+        value_parser,
+        value_name = "BYTES",
+        help = "Consider a part stalled if its speed stays below BYTES/s for --speed-time"
+    )]
+    pub speed_limit: Option<u64>,
+
+    #[clap(
+        long,
+        value_parser,
+        value_name = "SECS",
+        default_value_t = 30,
+        help = "Time window in seconds used by --speed-limit"
+    )]
+    pub speed_time: u64,
+
+    #[clap(
+        long,
+        value_parser = parse_size,
+        value_name = "BYTES",
+        help = "When only the last part is left and it is slower than BYTES/s (e.g. 100K), \
+                request its rest on a fresh connection too and keep the first one done"
+    )]
+    pub hedge_speed: Option<u64>,
+
+    #[clap(
+        long,
+        value_parser = parse_range,
+        value_delimiter = ',',
+        value_name = "FIRST-LAST",
+        help = "Only fetch these byte ranges (e.g. 0-99,5000-5999) in one request and write them \
+                at their offsets into the output file, e.g. to fill its holes"
+    )]
+    pub range: Vec<(u64, u64)>,
+
+    #[clap(
+        long,
+        value_parser = parse_size,
+        value_name = "BYTES",
+        help = "Limit the download rate to BYTES/s (e.g. 5M) across all connections, \
+                SIGUSR1 halves it and SIGUSR2 doubles it while downloading"
+    )]
+    pub limit_rate: Option<u64>,
+
+    #[clap(
+        long,
+        value_parser = parse_size,
+        value_name = "BYTES",
+        help = "Limit the download rate of every connection to BYTES/s"
+    )]
+    pub limit_conn_rate: Option<u64>,
+
+    #[clap(
+        long,
+        value_parser,
+        default_value_t = 0,
+        help = "Number of times a failed or stalled part is retried"
+    )]
+    pub retries: u8,
+
+    #[clap(
+        short = 'b',
+        long,
+        value_parser,
+        value_delimiter = ',',
+        value_name = "ADDR",
+        help = "Local address(es) to bind to, parts are spread across multiple addresses"
+    )]
+    pub bind_address: Vec<IpAddr>,
+
+    #[clap(
+        long,
+        value_parser,
+        value_delimiter = ',',
+        value_name = "NAME",
+        help = "Network interface(s) to bind to, parts are spread across multiple interfaces"
+    )]
+    pub interface: Vec<String>,
+
+    #[clap(
+        long,
+        value_parser,
+        value_name = "PATH",
+        help = "Connect through this unix domain socket instead of the url's host"
+    )]
+    pub unix_socket: Option<String>,
+
+    #[clap(
+        long = "http1.1",
+        value_parser,
+        action,
+        help = "Use HTTP/1.1 only, parts are not multiplexed on HTTP/2 connections"
+    )]
+    pub http1_1: bool,
+
+    #[clap(
+        long,
+        value_parser,
+        action,
+        conflicts_with_all = &["http1-1", "limit-rate", "limit-conn-rate"],
+        help = "Try HTTP/3 (QUIC) first for https urls, honoring Alt-Svc, falling back to tcp (not with rate limits)"
+    )]
+    pub http3: bool,
+
+    #[clap(
+        long,
+        value_parser,
+        action,
+        help = "Save the response body to output file even on server error (up to 1 MiB)"
+    )]
+    pub content_on_error: bool,
+
+    #[clap(
+        short = 'd',
+        long,
+        value_parser,
+        action,
+        help = "Print request and response headers of every connection to stderr"
+    )]
+    pub debug: bool,
+
+    #[clap(
+        long,
+        value_parser,
+        value_name = "FILE",
+        conflicts_with = "trace-ascii",
+        help = "Dump all sent and received data (hex) with timestamps to FILE"
+    )]
+    pub trace: Option<String>,
+
+    #[clap(
+        long,
+        value_parser,
+        value_name = "FILE",
+        help = "Like --trace but dump data as plain text"
+    )]
+    pub trace_ascii: Option<String>,
+
+    #[clap(
+        long,
+        value_parser,
+        action,
+        help = "Do not hide authorization and cookie values in debug/trace output"
+    )]
+    pub no_redact: bool,
+
+    #[clap(
+        short = 'w',
+        long,
+        value_parser,
+        value_name = "FORMAT",
+        help = "Print FORMAT (curl style, e.g. '%{label} %{time_total}\\n') for every connection"
+    )]
+    pub write_out: Option<String>,
+
+    #[clap(
+        long,
+        value_parser,
+        action,
+        help = "Print timing breakdown (dns, connect, tls, ttfb, transfer) of every connection"
+    )]
+    pub timing: bool,
+
+    #[clap(
+        long,
+        value_parser,
+        action,
+        help = "Disable HSTS (automatic http to https upgrade of known hosts)"
+    )]
+    pub no_hsts: bool,
+
+    #[clap(
+        long,
+        value_parser,
+        value_name = "FILE",
+        help = "HSTS store file [default: $XDG_DATA_HOME/fget/hsts]"
+    )]
+    pub hsts_file: Option<String>,
+
+    #[clap(
+        long,
+        value_parser,
+        value_name = "FILE",
+        help = "Preload HSTS entries (same format as the store) from FILE"
+    )]
+    pub hsts_preload: Option<String>,
 }
 
 impl Config {
     pub fn build() -> Result<Config, PError> {
         let cfg = Config::parse();
-        if cfg.num_threads <= 0 || cfg.num_threads > 32 {
+        if cfg.num_threads == NumThreads::Fixed(0) || cfg.min_threads == 0 {
+            return Err(make_error(
+                "invalid number of threads, must be greater than 0",
+            ));
+        }
+
+        let too_many = match cfg.num_threads {
+            NumThreads::Fixed(n) => n > MAX_THREADS,
+            NumThreads::Auto => cfg.max_threads > MAX_THREADS,
+        };
+        if too_many {
+            return Err(make_error(&format!(
+                "invalid number of threads, must not be greater than {}",
+                MAX_THREADS
+            )));
+        }
+
+        if cfg.max_threads < cfg.min_threads {
             return Err(make_error(
-                "invalid number of threads, must be between 1 and 32",
+                "invalid maximum number of threads, must not be less than --min-threads",
             ));
         }
 
+        if cfg.speed_time == 0 {
+            return Err(make_error("invalid speed time, must be greater than 0"));
+        }
+
+        if cfg.limit_rate == Some(0) || cfg.limit_conn_rate == Some(0) {
+            return Err(make_error("invalid rate limit, must be greater than 0"));
+        }
+
+        if cfg.split_size == Some(0) || cfg.min_split_size == 0 {
+            return Err(make_error("invalid split size, must be greater than 0"));
+        }
+
+        if !cfg.range.is_empty() && cfg.to_stdout() {
+            return Err(make_error("byte ranges cannot be written to stdout"));
+        }
+
         Ok(cfg)
     }
+
+    /// whether the file is written to stdout (`-o -`) instead of a file
+    pub fn to_stdout(&self) -> bool {
+        self.output.as_deref() == Some("-")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(Ok(1000), parse_size("1000"));
+        assert_eq!(Ok(512 * 1024), parse_size("512K"));
+        assert_eq!(Ok(8 * 1024 * 1024), parse_size("8m"));
+        assert_eq!(Ok(1 << 30), parse_size(" 1GiB "));
+        assert!(parse_size("").is_err());
+        assert!(parse_size("1T").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("1.5M").is_err());
+        assert!(parse_size("99999999999999G").is_err());
+    }
+
+    #[test]
+    fn test_parse_num_threads() {
+        assert_eq!(Ok(NumThreads::Fixed(8)), parse_num_threads("8"));
+        assert_eq!(Ok(NumThreads::Auto), parse_num_threads("auto"));
+        assert_eq!(Ok(NumThreads::Auto), parse_num_threads("AUTO"));
+        assert!(parse_num_threads("").is_err());
+        assert!(parse_num_threads("-1").is_err());
+        assert!(parse_num_threads("many").is_err());
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(Ok((0, 99)), parse_range("0-99"));
+        assert_eq!(Ok((5, 5)), parse_range(" 5-5 "));
+        assert!(parse_range("99-0").is_err());
+        assert!(parse_range("100-").is_err());
+        assert!(parse_range("-100").is_err());
+        assert!(parse_range("a-b").is_err());
+    }
 }
@@ -1,8 +1,22 @@
 use fget::Config;
 
+mod altsvc;
 mod downloader;
+mod engine;
+mod h2;
+mod h3;
+#[cfg(test)]
+mod h3server;
+mod hsts;
 mod httpx;
+#[cfg(test)]
+mod memtransport;
 mod pb;
+mod quic;
+mod ratelimit;
+mod reorder;
+mod timing;
+mod trace;
 mod urlinfo;
 
 fn main() {
@@ -10,8 +24,22 @@ fn main() {
         panic!("Problem parsing arguments: {err}");
     });
 
+    if let Err(err) = trace::init(&cfg) {
+        eprintln!("Problem setting up tracing: {err}");
+        return;
+    }
+
+    if let Err(err) = hsts::init(&cfg) {
+        eprintln!("Problem loading HSTS store: {err}");
+        return;
+    }
+
     let mut pbm = pb::ProgressManager::new();
     if let Err(e) = downloader::run(&cfg, &mut pbm) {
         eprintln!("An error occurred: {}", e)
     }
+
+    if let Err(err) = hsts::save() {
+        eprintln!("Problem saving HSTS store: {err}");
+    }
 }
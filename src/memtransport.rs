@@ -0,0 +1,449 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fmt,
+    hash::{Hash, Hasher},
+    io::{self, Read, Write},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use fget::PError;
+use http::StatusCode;
+
+use crate::{
+    httpx::{Conn, ConnAddr, ConnConfig, NbConn, NbStream, Timings, Transport},
+    urlinfo::UrlInfo,
+};
+
+/// Fault injected into a response of the in-memory server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// wait before sending the response
+    Latency(Duration),
+    /// stop sending after n bytes of body for a while, without blocking: reads would block
+    /// meanwhile
+    Stall(u64, Duration),
+    /// reset the connection after sending n bytes of body
+    ResetAfter(u64),
+    /// reset the connection before sending anything
+    Reset,
+    /// close the connection after sending n bytes of body, the announced length is kept
+    Truncate(u64),
+    /// ignore the range header and send the whole file with status 200
+    IgnoreRange,
+    /// answer with given (error) status
+    Status(u16),
+    /// answer with given status (429 or 503) asking to retry after n seconds
+    Throttle(u16, u64),
+    /// answer a range request with the range starting n bytes later
+    ShiftRange(u64),
+    /// answer a range request with the first range moved n bytes later
+    MoveRange(u64),
+    /// omit Content-Length from the response head (e.g. to a HEAD request)
+    NoLength,
+    /// ignore the range and send the whole file as if it was generated on the fly: with
+    /// Transfer-Encoding: chunked, in chunks of n bytes
+    Chunked(usize),
+    /// ignore the range and send the whole file as if it was generated on the fly: without
+    /// length, until the connection is closed
+    Streamed,
+    /// answer as if the file was replaced: another ETag, which If-Range does not match
+    Modified,
+    /// answer a multi-range request with a single range spanning all requested ones
+    CoalesceRanges,
+}
+
+/// boundary of multipart/byteranges responses
+const BOUNDARY: &str = "MEM_BOUNDARY";
+
+#[derive(Debug, Default)]
+struct State {
+    files: HashMap<String, Vec<u8>>,     // keyed by host:port/path
+    redirects: HashMap<String, String>,  // keyed by host:port/path, value is location
+    faults: Vec<(Option<u64>, Fault)>,   // (range start to match, fault), each used once
+    requests: Vec<String>,               // "METHOD host:port/path range" of every request
+    headers: Vec<Vec<(String, String)>>, // headers of every request
+    connections: usize,                  // number of connections opened
+}
+
+/// In-memory transport serving static files (with range support) and redirects, with
+/// faults injected on demand, to test clients and downloads without network
+#[derive(Debug, Clone, Default)]
+pub struct MemTransport {
+    state: Arc<Mutex<State>>,
+}
+
+impl MemTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// serve body at given url
+    pub fn serve(self, url: &str, body: &[u8]) -> Self {
+        let key = url_key(url);
+        self.state.lock().unwrap().files.insert(key, body.to_vec());
+        self
+    }
+
+    /// redirect requests for given url to location
+    pub fn redirect(self, url: &str, location: &str) -> Self {
+        let key = url_key(url);
+        let location = location.to_string();
+        self.state.lock().unwrap().redirects.insert(key, location);
+        self
+    }
+
+    /// inject fault into the next response to a range request starting at given offset,
+    /// or to the next request whatever it is if None
+    pub fn fault(self, range_start: Option<u64>, fault: Fault) -> Self {
+        self.state.lock().unwrap().faults.push((range_start, fault));
+        self
+    }
+
+    /// all requests received so far
+    pub fn requests(&self) -> Vec<String> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// value of given header (case-insensitive) in every request received so far
+    pub fn request_header(&self, name: &str) -> Vec<Option<String>> {
+        let state = self.state.lock().unwrap();
+        state
+            .headers
+            .iter()
+            .map(|headers| {
+                headers
+                    .iter()
+                    .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                    .map(|(_, val)| val.clone())
+            })
+            .collect()
+    }
+
+    /// number of connections opened so far
+    pub fn connections(&self) -> usize {
+        self.state.lock().unwrap().connections
+    }
+
+    fn open(&self, host_addr: &str) -> MemConn {
+        self.state.lock().unwrap().connections += 1;
+        MemConn {
+            host_addr: host_addr.to_string(),
+            state: self.state.clone(),
+            req: vec![],
+            resp: None,
+        }
+    }
+}
+
+impl Transport for MemTransport {
+    fn connect(
+        &self,
+        host_addr: &str,
+        _domain: &str,
+        _tls: bool, // data is never encrypted in memory
+        _cfg: &ConnConfig,
+    ) -> Result<Conn, PError> {
+        Ok(Conn {
+            rw: Box::new(self.open(host_addr)),
+            local_addr: ConnAddr::Inet("127.0.0.1:40000".parse()?),
+            remote_addr: ConnAddr::Inet("127.0.0.1:80".parse()?),
+            timings: Timings::default(),
+        })
+    }
+
+    fn connect_nonblocking(&self, host_addr: &str, _cfg: &ConnConfig) -> Result<NbConn, PError> {
+        Ok(NbConn {
+            stream: NbStream::Ready(Box::new(self.open(host_addr))),
+            local_addr: ConnAddr::Inet("127.0.0.1:40000".parse()?),
+            remote_addr: ConnAddr::Inet("127.0.0.1:80".parse()?),
+            timings: Timings::default(),
+        })
+    }
+}
+
+/// strong ETag of file, another one if it is to look modified
+fn etag(file: &[u8], fault: Option<Fault>) -> String {
+    let mut hasher = DefaultHasher::new();
+    file.hash(&mut hasher);
+    (fault == Some(Fault::Modified)).hash(&mut hasher);
+    format!("\"{:016x}\"", hasher.finish())
+}
+
+fn url_key(url: &str) -> String {
+    let urlinfo = UrlInfo::parse(url).expect("invalid url");
+    format!("{}{}", urlinfo.host_addr(), urlinfo.path)
+}
+
+/// A response being sent, data is the whole response (head and body)
+struct MemResponse {
+    data: Vec<u8>,
+    pos: usize,
+    limit: Option<(usize, io::ErrorKind)>, // stop there with error, UnexpectedEof to close
+    latency: Option<Duration>,
+    stall: Option<(usize, Instant)>, // stop there until then
+}
+
+struct MemConn {
+    host_addr: String,
+    state: Arc<Mutex<State>>,
+    req: Vec<u8>,
+    resp: Option<MemResponse>,
+}
+
+impl fmt::Debug for MemConn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MemConn({})", self.host_addr)
+    }
+}
+
+impl MemConn {
+    fn respond(&self) -> io::Result<MemResponse> {
+        let req = String::from_utf8_lossy(&self.req);
+        let mut lines = req.lines();
+        let mut parts = lines.next().unwrap_or_default().split(' ');
+        let (method, path) = match (parts.next(), parts.next()) {
+            (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "bad request")),
+        };
+        let headers: Vec<(String, String)> = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(key, val)| (key.trim().to_string(), val.trim().to_string()))
+            .collect();
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, val)| val.as_str())
+        };
+        let ranges: Vec<(u64, Option<u64>)> = header("range")
+            .and_then(|val| val.strip_prefix("bytes="))
+            .map(|val| {
+                val.split(',')
+                    .filter_map(|range| range.trim().split_once('-'))
+                    .filter_map(|(start, end)| Some((start.parse().ok()?, end.parse().ok())))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let key = format!("{}{}", self.host_addr, path);
+        let mut state = self.state.lock().unwrap();
+        let logged: Vec<String> = ranges
+            .iter()
+            .map(|(start, end)| format!("{}-{}", start, end.unwrap_or_default()))
+            .collect();
+        state.requests.push(format!(
+            "{} {} {}",
+            method,
+            key,
+            if logged.is_empty() {
+                "-".to_string()
+            } else {
+                logged.join(",")
+            }
+        ));
+        state.headers.push(headers.clone());
+
+        let range_start = ranges.first().map(|(start, _)| *start);
+        let fault = state
+            .faults
+            .iter()
+            .position(|(start, _)| start.is_none() || *start == range_start)
+            .map(|i| state.faults.remove(i).1);
+
+        let mut resp = MemResponse {
+            data: vec![],
+            pos: 0,
+            limit: None,
+            latency: None,
+            stall: None,
+        };
+        let (status, headers, body) =
+            match (fault, state.redirects.get(&key), state.files.get(&key)) {
+                (Some(Fault::Status(code)), _, _) => (code, vec![], b"fault".to_vec()),
+                (Some(Fault::Throttle(code, secs)), _, _) => {
+                    let retry_after = format!("Retry-After: {}", secs);
+                    (code, vec![retry_after], b"slow down".to_vec())
+                }
+                (_, Some(location), _) => (302, vec![format!("Location: {}", location)], vec![]),
+                (_, _, None) => (404, vec![], b"not found".to_vec()),
+                // e.g. the first byte of an empty file
+                (_, _, Some(file))
+                    if ranges
+                        .first()
+                        .is_some_and(|(start, _)| *start >= file.len() as u64) =>
+                {
+                    let content_range = format!("Content-Range: bytes */{}", file.len());
+                    (416, vec![content_range], vec![])
+                }
+                // If-Range: the range only if the file is still the same version
+                (_, _, Some(file))
+                    if !ranges.is_empty()
+                        && !matches!(
+                            fault,
+                            Some(Fault::IgnoreRange | Fault::Chunked(_) | Fault::Streamed)
+                        )
+                        && header("if-range").is_none_or(|val| val == etag(file, fault)) =>
+                {
+                    let last = (file.len() as u64).saturating_sub(1);
+                    let mut ranges: Vec<(u64, u64)> = ranges
+                        .iter()
+                        .map(|&(start, end)| (start, end.unwrap_or(last).min(last)))
+                        .collect();
+                    match fault {
+                        Some(Fault::ShiftRange(n)) => ranges[0].0 += n,
+                        Some(Fault::MoveRange(n)) => {
+                            ranges[0] = (ranges[0].0 + n, (ranges[0].1 + n).min(last))
+                        }
+                        Some(Fault::CoalesceRanges) => {
+                            let start = ranges.iter().map(|(start, _)| *start).min().unwrap();
+                            let end = ranges.iter().map(|(_, end)| *end).max().unwrap();
+                            ranges = vec![(start, end)];
+                        }
+                        _ => {}
+                    }
+
+                    let etag = format!("ETag: {}", etag(file, fault));
+                    let content_range = |(start, end)| {
+                        format!("Content-Range: bytes {}-{}/{}", start, end, file.len())
+                    };
+                    if let [(start, end)] = ranges[..] {
+                        let body = file[start as usize..=end as usize].to_vec();
+                        (206, vec![content_range((start, end)), etag], body)
+                    } else {
+                        let mut body = vec![];
+                        for (start, end) in ranges {
+                            let part_head = format!(
+                                "\r\n--{}\r\nContent-Type: application/octet-stream\r\n{}\r\n\r\n",
+                                BOUNDARY,
+                                content_range((start, end))
+                            );
+                            body.extend_from_slice(part_head.as_bytes());
+                            body.extend_from_slice(&file[start as usize..=end as usize]);
+                        }
+                        body.extend_from_slice(format!("\r\n--{}--\r\n", BOUNDARY).as_bytes());
+                        let content_type =
+                            format!("Content-Type: multipart/byteranges; boundary={}", BOUNDARY);
+                        (206, vec![content_type, etag], body)
+                    }
+                }
+                (_, _, Some(file)) => (
+                    200,
+                    vec![format!("ETag: {}", etag(file, fault))],
+                    file.clone(),
+                ),
+            };
+
+        let reason = StatusCode::from_u16(status)
+            .ok()
+            .and_then(|s| s.canonical_reason())
+            .unwrap_or("Unknown");
+        let mut head = format!("HTTP/1.1 {} {}\r\n", status, reason);
+        let body = match fault {
+            Some(Fault::Chunked(size)) => {
+                head += "Transfer-Encoding: chunked\r\n";
+                let mut chunked = vec![];
+                for chunk in body.chunks(size) {
+                    chunked.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+                    chunked.extend_from_slice(chunk);
+                    chunked.extend_from_slice(b"\r\n");
+                }
+                chunked.extend_from_slice(b"0\r\n\r\n");
+                chunked
+            }
+            Some(Fault::NoLength | Fault::Streamed) => body,
+            _ => {
+                head += &format!("Content-Length: {}\r\n", body.len());
+                body
+            }
+        };
+        head += "Accept-Ranges: bytes\r\n";
+        for header in headers {
+            head += &format!("{}\r\n", header);
+        }
+        head += "\r\n";
+
+        resp.data.extend_from_slice(head.as_bytes());
+        if method != "HEAD" {
+            resp.data.extend_from_slice(&body);
+        }
+
+        match fault {
+            Some(Fault::Latency(dur)) => resp.latency = Some(dur),
+            Some(Fault::Stall(n, dur)) => {
+                resp.stall = Some((head.len() + n as usize, Instant::now() + dur))
+            }
+            Some(Fault::ResetAfter(n)) => {
+                resp.limit = Some((head.len() + n as usize, io::ErrorKind::ConnectionReset))
+            }
+            Some(Fault::Reset) => resp.limit = Some((0, io::ErrorKind::ConnectionReset)),
+            Some(Fault::Truncate(n)) => {
+                resp.limit = Some((head.len() + n as usize, io::ErrorKind::UnexpectedEof))
+            }
+            _ => {}
+        }
+
+        Ok(resp)
+    }
+}
+
+impl Read for MemConn {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.resp.is_none() {
+            if !self.req.windows(4).any(|w| w == b"\r\n\r\n") {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "incomplete request",
+                ));
+            }
+            self.resp = Some(self.respond()?);
+        }
+
+        let resp = self.resp.as_mut().unwrap();
+        if let Some(latency) = resp.latency.take() {
+            thread::sleep(latency);
+        }
+        let mut end = resp.data.len();
+        if let Some((stall, _)) = resp.stall.filter(|(_, until)| Instant::now() < *until) {
+            if resp.pos >= stall {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "stalled response",
+                ));
+            }
+            end = end.min(stall);
+        }
+        if let Some((limit, kind)) = resp.limit {
+            if resp.pos >= limit {
+                return match kind {
+                    io::ErrorKind::UnexpectedEof => Ok(0),
+                    kind => Err(io::Error::new(kind, "injected fault")),
+                };
+            }
+            end = end.min(limit);
+        }
+
+        let n = buf.len().min(end - resp.pos);
+        buf[..n].copy_from_slice(&resp.data[resp.pos..resp.pos + n]);
+        resp.pos += n;
+
+        Ok(n)
+    }
+}
+
+impl Write for MemConn {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // connection is kept alive: another request once the response was sent
+        if self.resp.as_ref().is_some_and(|r| r.pos == r.data.len()) {
+            self.resp = None;
+            self.req.clear();
+        }
+        self.req.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
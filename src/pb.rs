@@ -1,6 +1,6 @@
 use std::{cmp::min, thread, time::Duration};
 
-use crate::downloader::DownloadObserver;
+use crate::{downloader::DownloadObserver, httpx::ConnAddr};
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 
 pub struct ProgressManager {
@@ -9,25 +9,64 @@ pub struct ProgressManager {
 }
 
 impl DownloadObserver for ProgressManager {
-    fn on_download_start(&mut self, idx: u8, len: u64) {
-        if let Some(pb) = self.pbs.get_mut(idx as usize) {
-            pb.set_length(len);
+    fn on_download_start(&mut self, idx: usize, len: Option<u64>, src: &ConnAddr) {
+        // workers added by --num-threads auto get their bar when they first start
+        while self.pbs.len() <= idx {
+            let pb = self.m.insert(self.pbs.len(), new_progress_bar(0));
+            self.pbs.push(pb);
+        }
+
+        // a worker bar starts over with every part, a spinner if the length is unknown
+        if let Some(pb) = self.pbs.get_mut(idx) {
+            pb.reset();
+            match len {
+                Some(len) => {
+                    pb.set_style(bar_style());
+                    pb.set_length(len);
+                }
+                None => {
+                    pb.set_style(spinner_style());
+                    pb.enable_steady_tick(Duration::from_millis(100));
+                }
+            }
+            pb.set_message(format!("connection {} via {}", idx, src.host()));
         }
     }
 
-    fn on_progress(&mut self, idx: u8, pos: u64) {
-        if let Some(pb) = self.pbs.get_mut(idx as usize) {
+    fn on_progress(&mut self, idx: usize, pos: u64) {
+        if let Some(pb) = self.pbs.get_mut(idx) {
             pb.set_position(pos);
         }
     }
 
-    fn on_download_end(&mut self, idx: u8) {
-        if let Some(pb) = self.pbs.get_mut(idx as usize) {
-            pb.finish_with_message(format!("part {} downloaded", idx));
+    fn on_throttled(&mut self, idx: usize, wait: Duration) {
+        if let Some(pb) = self.pbs.get_mut(idx) {
+            pb.set_message(format!(
+                "connection {} throttled by server, retrying in {}s",
+                idx,
+                wait.as_secs()
+            ));
+        }
+    }
+
+    fn on_split(&mut self, idx: usize, len: u64) {
+        if let Some(pb) = self.pbs.get_mut(idx) {
+            pb.set_length(len);
+        }
+    }
+
+    fn on_download_end(&mut self, idx: usize) {
+        if let Some(pb) = self.pbs.get_mut(idx) {
+            pb.finish_with_message(format!("connection {} done", idx));
         }
     }
 
     fn on_init(&mut self, len: usize) {
+        // a download started over replaces the bars of the previous attempt
+        for pb in self.pbs.drain(..) {
+            pb.finish_and_clear();
+            self.m.remove(&pb);
+        }
         for i in 0..len {
             self.pbs.push(self.m.insert(i, new_progress_bar(0)));
         }
@@ -44,23 +83,38 @@ impl ProgressManager {
 }
 
 fn new_progress_bar(len: u64) -> ProgressBar {
-    let pb = ProgressBar::new(len as u64);
-    pb.set_style(
-        ProgressStyle::with_template(concat!(
-            "{spinner:.green} ",
-            "[{elapsed_precise}] ",
-            "[{wide_bar:.cyan/blue}] ",
-            "{bytes}/{total_bytes} ",
-            "({binary_bytes_per_sec:^12} ",
-            "{eta:>3})"
-        ))
-        .unwrap()
-        .progress_chars("#>-"),
-    );
+    let pb = ProgressBar::new(len);
+    pb.set_style(bar_style());
 
     pb
 }
 
+fn bar_style() -> ProgressStyle {
+    ProgressStyle::with_template(concat!(
+        "{spinner:.green} ",
+        "[{elapsed_precise}] ",
+        "[{wide_bar:.cyan/blue}] ",
+        "{bytes}/{total_bytes} ",
+        "({binary_bytes_per_sec:^12} ",
+        "{eta:>3}) ",
+        "{msg}"
+    ))
+    .unwrap()
+    .progress_chars("#>-")
+}
+
+/// indeterminate progress of a download of unknown length: no bar, no total, no eta
+fn spinner_style() -> ProgressStyle {
+    ProgressStyle::with_template(concat!(
+        "{spinner:.green} ",
+        "[{elapsed_precise}] ",
+        "{bytes} ",
+        "({binary_bytes_per_sec:^12}) ",
+        "{msg}"
+    ))
+    .unwrap()
+}
+
 #[allow(dead_code)]
 pub fn test_show_pb() {
     let len: u64 = 243 * 1024 * 1024;
@@ -88,7 +142,7 @@ pub fn test_show_pb() {
             n -= 1;
         }
     }
-    for i in 0..4 {
-        pbs[i].finish_with_message(format!("part {} downloaded", i));
+    for (i, pb) in pbs.iter().enumerate() {
+        pb.finish_with_message(format!("part {} downloaded", i));
     }
 }
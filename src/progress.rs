@@ -0,0 +1,51 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+/// Number of bytes downloaded by a part, updated by the connection downloading it on
+/// every read without locking nor messaging, and sampled by the observer at its own pace.
+/// Clones share the same counter.
+#[derive(Debug, Clone, Default)]
+pub struct Counter(Arc<AtomicU64>);
+
+impl Counter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, val: u64) {
+        self.0.store(val, Ordering::Relaxed);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_counter() {
+        let counter = Counter::new();
+        let shared = counter.clone();
+        let writers: Vec<_> = (0..4)
+            .map(|_| {
+                let counter = counter.clone();
+                thread::spawn(move || (0..1000).for_each(|_| counter.add(8)))
+            })
+            .collect();
+        writers.into_iter().for_each(|w| w.join().unwrap());
+        assert_eq!(32_000, shared.get());
+
+        counter.set(0);
+        assert_eq!(0, shared.get());
+    }
+}
@@ -0,0 +1,277 @@
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant},
+};
+
+use bytes::{Bytes, BytesMut};
+use fget::{make_error, PError};
+use mio::net::UdpSocket;
+use quinn_proto::{
+    crypto::rustls::QuicClientConfig, ClientConfig, ConnectionHandle, DatagramEvent, Dir, Endpoint,
+    EndpointConfig, Event, ReadError, StreamId, TransportConfig, VarInt,
+};
+
+use crate::httpx::{self, HttpConfig};
+
+/// ALPN protocol id of HTTP/3
+const ALPN_H3: &[u8] = b"h3";
+const MAX_DATAGRAM_SIZE: usize = 64 * 1024;
+/// receive windows, large enough for a fast transfer not to wait for window updates
+const STREAM_WINDOW: u32 = 4 * 1024 * 1024;
+const CONN_WINDOW: u32 = 16 * 1024 * 1024;
+
+/// Data read from a stream
+#[derive(Debug, Default)]
+pub struct StreamData {
+    pub data: Vec<u8>,
+    pub end: bool,          // stream is finished, all data was read
+    pub reset: Option<u64>, // stream was reset by server with given error code
+}
+
+/// Client QUIC connection (RFC 9000) on a non-blocking udp socket. The socket is
+/// registered to an event loop, `drive` is called on its readiness events and once the
+/// deadline of the connection's timers is reached.
+pub struct Connection {
+    socket: UdpSocket,
+    endpoint: Endpoint,
+    handle: ConnectionHandle,
+    conn: quinn_proto::Connection,
+    remote_addr: SocketAddr,
+    out: Option<Vec<u8>>, // datagram which could not be sent yet
+}
+
+impl Connection {
+    /// start connecting to given host, returns the connection and the time spent resolving
+    /// host, the handshake goes on as the connection is driven
+    pub fn connect(
+        host_addr: &str,
+        domain: &str,
+        cfg: &HttpConfig,
+    ) -> Result<(Connection, Duration), PError> {
+        let (socket, dns) = httpx::connect_udp(host_addr, &cfg.conn_config())?;
+        let remote_addr = socket.peer_addr()?;
+
+        let mut transport = TransportConfig::default();
+        transport
+            .stream_receive_window(VarInt::from_u32(STREAM_WINDOW))
+            .receive_window(VarInt::from_u32(CONN_WINDOW))
+            .max_concurrent_bidi_streams(VarInt::from_u32(0))
+            .enable_segmentation_offload(false);
+        let mut client = ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls_config()?)?));
+        client.transport_config(Arc::new(transport));
+
+        let mut endpoint = Endpoint::new(Arc::new(EndpointConfig::default()), None, false, None);
+        let (handle, conn) = endpoint.connect(Instant::now(), client, remote_addr, domain)?;
+
+        let mut conn = Connection {
+            socket: UdpSocket::from_std(socket),
+            endpoint,
+            handle,
+            conn,
+            remote_addr,
+            out: None,
+        };
+        conn.flush()?;
+
+        Ok((conn, dns))
+    }
+
+    pub fn socket(&mut self) -> &mut UdpSocket {
+        &mut self.socket
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    /// when the connection must be driven next if there is no readiness event
+    pub fn deadline(&mut self) -> Option<Instant> {
+        self.conn.poll_timeout()
+    }
+
+    /// receive datagrams until it would block, handle expired timers and send what needs
+    /// to be sent, returns what happened on the connection. An error means the socket is
+    /// unusable (e.g. server port is unreachable).
+    pub fn drive(&mut self) -> Result<Vec<Event>, PError> {
+        let now = Instant::now();
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        loop {
+            let n = match self.socket.recv(&mut buf) {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            let mut response = vec![];
+            let data = BytesMut::from(&buf[..n]);
+            match self
+                .endpoint
+                .handle(now, self.remote_addr, None, None, data, &mut response)
+            {
+                Some(DatagramEvent::ConnectionEvent(_, event)) => self.conn.handle_event(event),
+                Some(DatagramEvent::Response(transmit)) => {
+                    // stateless reset or version negotiation, best effort
+                    let _ = self.socket.send(&response[..transmit.size]);
+                }
+                _ => {}
+            }
+        }
+
+        if self
+            .conn
+            .poll_timeout()
+            .is_some_and(|deadline| deadline <= now)
+        {
+            self.conn.handle_timeout(now);
+        }
+        while let Some(event) = self.conn.poll_endpoint_events() {
+            if let Some(event) = self.endpoint.handle_event(self.handle, event) {
+                self.conn.handle_event(event);
+            }
+        }
+
+        let mut events = vec![];
+        while let Some(event) = self.conn.poll() {
+            events.push(event);
+        }
+        self.flush()?;
+
+        Ok(events)
+    }
+
+    /// send pending datagrams, until it would block
+    pub fn flush(&mut self) -> Result<(), PError> {
+        loop {
+            if let Some(datagram) = &self.out {
+                match self.socket.send(datagram) {
+                    Ok(_) => self.out = None,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+
+            let mut datagram = Vec::with_capacity(MAX_DATAGRAM_SIZE);
+            match self.conn.poll_transmit(Instant::now(), 1, &mut datagram) {
+                Some(transmit) => {
+                    datagram.truncate(transmit.size);
+                    self.out = Some(datagram);
+                }
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// open a bidirectional stream, send data on it and finish it, returns None if no
+    /// stream can be opened now
+    pub fn open_bi(&mut self, data: &[u8]) -> Result<Option<StreamId>, PError> {
+        match self.conn.streams().open(Dir::Bi) {
+            Some(id) => self.send(id, data, true).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// open a unidirectional stream and send data on it, it is never finished
+    pub fn open_uni(&mut self, data: &[u8]) -> Result<StreamId, PError> {
+        let id = self
+            .conn
+            .streams()
+            .open(Dir::Uni)
+            .ok_or_else(|| make_error("no unidirectional stream can be opened"))?;
+        self.send(id, data, false)
+    }
+
+    fn send(&mut self, id: StreamId, data: &[u8], finish: bool) -> Result<StreamId, PError> {
+        let mut stream = self.conn.send_stream(id);
+        // data is small (request head), it always fits in the initial stream window
+        if stream.write(data)? < data.len() {
+            return Err(make_error("request does not fit in quic stream window"));
+        }
+        if finish {
+            stream.finish()?;
+        }
+
+        Ok(id)
+    }
+
+    /// stream opened by server, if any
+    pub fn accept_uni(&mut self) -> Option<StreamId> {
+        self.conn.streams().accept(Dir::Uni)
+    }
+
+    /// read all data available on stream
+    pub fn read(&mut self, id: StreamId) -> StreamData {
+        let mut res = StreamData::default();
+        let mut stream = self.conn.recv_stream(id);
+        let mut chunks = match stream.read(true) {
+            Ok(chunks) => chunks,
+            Err(_) => return res, // stream is closed already
+        };
+        loop {
+            match chunks.next(usize::MAX) {
+                Ok(Some(chunk)) => res.data.extend_from_slice(&chunk.bytes),
+                Ok(None) => {
+                    res.end = true;
+                    break;
+                }
+                Err(ReadError::Blocked) => break,
+                Err(ReadError::Reset(code)) => {
+                    res.reset = Some(code.into());
+                    break;
+                }
+            }
+        }
+        let _ = chunks.finalize(); // flow control credit is sent on next flush
+
+        res
+    }
+
+    /// ask server to stop sending on stream, e.g. once the client got all it wanted
+    pub fn stop(&mut self, id: StreamId, code: u64) {
+        let _ = self
+            .conn
+            .recv_stream(id)
+            .stop(VarInt::from_u64(code).unwrap_or(VarInt::MAX));
+    }
+
+    /// close connection with given application error code, best effort
+    pub fn close(&mut self, code: u64) {
+        let code = VarInt::from_u64(code).unwrap_or(VarInt::MAX);
+        self.conn.close(Instant::now(), code, Bytes::new());
+        let _ = self.flush();
+    }
+}
+
+/// tls configuration of QUIC connections (native root certificates, ALPN h3), loaded once
+fn tls_config() -> Result<Arc<rustls::ClientConfig>, PError> {
+    static CONFIG: OnceLock<Arc<rustls::ClientConfig>> = OnceLock::new();
+    if let Some(config) = CONFIG.get() {
+        return Ok(config.clone());
+    }
+
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in rustls_native_certs::load_native_certs().certs {
+        let _ = roots.add(cert); // skip certificates rustls does not understand
+    }
+    #[cfg(test)]
+    roots.add(crate::h3server::test_cert().cert_der.clone())?;
+    if roots.is_empty() {
+        return Err(make_error("no trusted root certificate found"));
+    }
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![ALPN_H3.to_vec()];
+
+    Ok(CONFIG.get_or_init(|| Arc::new(config)).clone())
+}
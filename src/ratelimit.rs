@@ -0,0 +1,217 @@
+use std::{
+    cmp,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+/// bucket capacity, as the time to fill it at the limited rate: the longest burst
+const BURST: Duration = Duration::from_millis(100);
+/// number of bytes to wait for once a bucket is empty, so that reads are not too small
+const MIN_GRANT: i64 = 4096;
+
+/// Token bucket limiting the read rate (bytes per second) of connections: one is shared
+/// by all connections of a download for --limit-rate, every connection has its own one
+/// for --limit-conn-rate
+#[derive(Debug)]
+pub struct RateLimit {
+    bucket: Mutex<Bucket>,
+    signals: bool, // whether SIGUSR1/SIGUSR2 change the rate
+}
+
+#[derive(Debug)]
+struct Bucket {
+    rate: u64,
+    tokens: i64, // bytes which may be read now, negative once overdrawn
+    refilled: Instant,
+}
+
+impl Bucket {
+    fn capacity(&self) -> i64 {
+        cmp::max(self.rate as u128 * BURST.as_millis() / 1000, 1) as i64
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled);
+        let new = (self.rate as u128 * elapsed.as_nanos() / 1_000_000_000) as i64;
+        if self.tokens + new >= self.capacity() {
+            self.tokens = self.capacity();
+            self.refilled = now;
+        } else if new > 0 {
+            // keep the fraction of a token earned since the last whole one
+            self.tokens += new;
+            self.refilled +=
+                Duration::from_nanos((new as u128 * 1_000_000_000 / self.rate as u128) as u64);
+        }
+    }
+
+    /// change the rate, the tokens available are kept
+    fn set_rate(&mut self, rate: u64) {
+        self.rate = cmp::max(rate, 1);
+        self.tokens = cmp::min(self.tokens, self.capacity());
+    }
+
+    /// when enough tokens are available again for a read
+    fn wakeup(&self) -> Instant {
+        let missing = cmp::min(self.capacity(), MIN_GRANT) - self.tokens;
+        let nanos = missing.max(1) as u128 * 1_000_000_000 / self.rate as u128;
+        self.refilled + Duration::from_nanos(nanos as u64)
+    }
+}
+
+impl RateLimit {
+    /// bucket for given rate (at least one byte per second), full
+    pub fn new(rate: u64) -> Self {
+        let rate = cmp::max(rate, 1);
+        let mut bucket = Bucket {
+            rate,
+            tokens: 0,
+            refilled: Instant::now(),
+        };
+        bucket.tokens = bucket.capacity();
+
+        RateLimit {
+            bucket: Mutex::new(bucket),
+            signals: false,
+        }
+    }
+
+    /// bucket whose rate is halved by SIGUSR1 and doubled by SIGUSR2 while downloading
+    pub fn adjustable(rate: u64) -> Self {
+        signals::install();
+        RateLimit {
+            signals: true,
+            ..Self::new(rate)
+        }
+    }
+
+    /// bucket refilled up to now, with the rate changes asked by signals
+    fn lock(&self, now: Instant) -> MutexGuard<'_, Bucket> {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill(now);
+        if self.signals {
+            let steps = signals::take();
+            let rate = match steps {
+                0 => bucket.rate,
+                steps if steps > 0 => bucket.rate.saturating_mul(1 << steps.min(32)),
+                steps => bucket.rate >> (-steps).min(63),
+            };
+            bucket.set_rate(rate);
+        }
+
+        bucket
+    }
+}
+
+/// take up to n bytes from all given buckets, returns the number of bytes which may be
+/// read now, or when to try again if one of the buckets is empty
+pub fn take(limits: &[Arc<RateLimit>], n: usize, now: Instant) -> Result<usize, Instant> {
+    let mut buckets: Vec<MutexGuard<Bucket>> = limits.iter().map(|l| l.lock(now)).collect();
+    if let Some(wakeup) = buckets
+        .iter()
+        .filter(|b| b.tokens <= 0)
+        .map(|b| b.wakeup())
+        .max()
+    {
+        return Err(wakeup);
+    }
+
+    let granted = buckets.iter().map(|b| b.tokens as usize).fold(n, cmp::min);
+    for bucket in buckets.iter_mut() {
+        bucket.tokens -= granted as i64;
+    }
+
+    Ok(granted)
+}
+
+/// give back bytes taken but not read
+pub fn give_back(limits: &[Arc<RateLimit>], n: usize) {
+    if n == 0 {
+        return;
+    }
+    for limit in limits {
+        let mut bucket = limit.bucket.lock().unwrap();
+        bucket.tokens = cmp::min(bucket.tokens + n as i64, bucket.capacity());
+    }
+}
+
+#[cfg(unix)]
+mod signals {
+    use std::sync::{
+        atomic::{AtomicI32, Ordering},
+        Once,
+    };
+
+    /// rate changes asked since last taken: +1 per SIGUSR2 (double), -1 per SIGUSR1 (halve)
+    static STEPS: AtomicI32 = AtomicI32::new(0);
+    static INSTALL: Once = Once::new();
+
+    extern "C" fn on_signal(sig: libc::c_int) {
+        let step = if sig == libc::SIGUSR2 { 1 } else { -1 };
+        STEPS.fetch_add(step, Ordering::Relaxed);
+    }
+
+    pub fn install() {
+        INSTALL.call_once(|| unsafe {
+            let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            libc::signal(libc::SIGUSR1, handler);
+            libc::signal(libc::SIGUSR2, handler);
+        });
+    }
+
+    pub fn take() -> i32 {
+        STEPS.swap(0, Ordering::Relaxed)
+    }
+}
+
+#[cfg(not(unix))]
+mod signals {
+    pub fn install() {}
+
+    pub fn take() -> i32 {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take() {
+        let limit = Arc::new(RateLimit::new(100_000));
+        let limits = [limit.clone()];
+        let now = limit.bucket.lock().unwrap().refilled;
+
+        // a full bucket holds 100ms worth of bytes
+        assert_eq!(Ok(8000), take(&limits, 8000, now));
+        assert_eq!(Ok(2000), take(&limits, 8000, now));
+        assert_eq!(
+            Err(now + Duration::from_nanos(40_960_000)),
+            take(&limits, 8000, now)
+        );
+
+        // refilled at the limited rate, unread bytes are given back
+        let later = now + Duration::from_millis(50);
+        assert_eq!(Ok(5000), take(&limits, 8000, later));
+        give_back(&limits, 3000);
+        assert_eq!(Ok(3000), take(&limits, 8000, later));
+
+        limit.bucket.lock().unwrap().set_rate(1000);
+        assert_eq!(Ok(100), take(&limits, 8000, later + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_take_shared() {
+        let shared = Arc::new(RateLimit::new(100_000));
+        let first = [shared.clone(), Arc::new(RateLimit::new(30_000))];
+        let second = [shared.clone(), Arc::new(RateLimit::new(30_000))];
+        let now = shared.bucket.lock().unwrap().refilled;
+
+        // capped by the bucket of the connection, then by the shared one
+        assert_eq!(Ok(3000), take(&first, 8000, now));
+        assert_eq!(Ok(3000), take(&second, 8000, now));
+        assert!(take(&first, 8000, now).is_err());
+        assert_eq!(4000, shared.bucket.lock().unwrap().tokens);
+        assert_eq!(Ok(8000), take(&[], 8000, now));
+    }
+}
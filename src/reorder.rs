@@ -0,0 +1,388 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread::{self, JoinHandle},
+};
+
+/// Writes data received at any offset of the file to a stream (stdout) strictly in file
+/// order. Data is kept in memory up to a bound, then spilled to a temporary file, until
+/// a writer thread of its own takes it in turn: parts only claim and keep their data, a
+/// slow stream does not hold them up. Bytes received already (by a retried part or a
+/// hedge) are skipped.
+pub struct ReorderBuffer {
+    shared: Arc<Shared>,
+    writer: Mutex<Option<JoinHandle<()>>>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    ready: Condvar, // data was kept, or the writer is to finish or stop
+}
+
+struct State {
+    pos: u64,                      // number of bytes taken by the writer, offset of the next one
+    received: BTreeMap<u64, u64>,  // ranges received (start to end, exclusive), merged
+    pending: BTreeMap<u64, Chunk>, // data received not taken by the writer yet, by offset
+    in_memory: usize,
+    max_memory: usize,
+    spill_path: String,
+    spill: Option<File>,
+    spill_len: u64,
+    failed: Option<io::ErrorKind>, // the stream failed, data written since would be lost
+    finishing: bool,               // the whole file was received, the writer ends once idle
+    stopped: bool,                 // the writer ends after its current write
+}
+
+/// Data received not written yet
+enum Chunk {
+    Memory(Vec<u8>),
+    Spilled(u64, usize), // (offset in spill file, length)
+}
+
+/// What the writer does next
+enum Next {
+    Write(Vec<u8>),
+    Flush,
+    Stop,
+}
+
+impl ReorderBuffer {
+    pub fn new(w: Box<dyn Write + Send>, max_memory: usize, spill_path: &str) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                pos: 0,
+                received: BTreeMap::new(),
+                pending: BTreeMap::new(),
+                in_memory: 0,
+                max_memory,
+                spill_path: spill_path.to_string(),
+                spill: None,
+                spill_len: 0,
+                failed: None,
+                finishing: false,
+                stopped: false,
+            }),
+            ready: Condvar::new(),
+        });
+        let writer = {
+            let shared = shared.clone();
+            thread::spawn(move || shared.run(w))
+        };
+
+        Self {
+            shared,
+            writer: Mutex::new(Some(writer)),
+        }
+    }
+
+    /// number of bytes taken by the writer so far, they can not be taken back
+    pub fn written(&self) -> u64 {
+        self.shared.lock().pos
+    }
+
+    /// keep data received at given offset of the file until the writer takes it in turn
+    pub fn write(&self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let mut state = self.shared.lock();
+        if let Some(kind) = state.failed {
+            return Err(io::Error::new(kind, "output failed"));
+        }
+
+        for (start, end) in state.claim(offset, offset + data.len() as u64) {
+            state.keep(
+                start,
+                &data[(start - offset) as usize..(end - offset) as usize],
+            )?;
+        }
+        drop(state);
+        self.shared.ready.notify_one();
+
+        Ok(())
+    }
+
+    /// wait for the writer to write and flush all the data once the whole file was
+    /// received, returns the number of bytes written
+    pub fn finish(&self) -> io::Result<u64> {
+        self.shared.lock().finishing = true;
+        self.shared.ready.notify_one();
+        self.join();
+
+        let state = self.shared.lock();
+        if let Some(kind) = state.failed {
+            return Err(io::Error::new(kind, "output failed"));
+        }
+        if !state.pending.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("data missing at offset {}", state.pos),
+            ));
+        }
+
+        Ok(state.pos)
+    }
+
+    fn join(&self) {
+        let writer = self.writer.lock().map(|mut writer| writer.take());
+        if let Ok(Some(writer)) = writer {
+            let _ = writer.join();
+        }
+    }
+}
+
+impl Drop for ReorderBuffer {
+    /// stop the writer, data not written yet is dropped
+    fn drop(&mut self) {
+        self.shared.lock().stopped = true;
+        self.shared.ready.notify_one();
+        self.join();
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// writer thread: write the data at the write position as it comes, the lock is not
+    /// held while writing
+    fn run(&self, mut w: Box<dyn Write + Send>) {
+        loop {
+            let res = match self.next() {
+                Next::Write(data) => w.write_all(&data),
+                Next::Flush => w.flush(),
+                Next::Stop => return,
+            };
+            if let Err(err) = res {
+                self.lock().failed = Some(err.kind());
+                return;
+            }
+        }
+    }
+
+    fn next(&self) -> Next {
+        let mut state = self.lock();
+        loop {
+            if state.stopped || state.failed.is_some() {
+                return Next::Stop;
+            }
+            match state.take() {
+                Ok(Some(data)) => return Next::Write(data),
+                Ok(None) if state.finishing => {
+                    state.stopped = true;
+                    return Next::Flush;
+                }
+                Ok(None) => {
+                    state = self
+                        .ready
+                        .wait(state)
+                        .unwrap_or_else(|err| err.into_inner())
+                }
+                Err(err) => {
+                    state.failed = Some(err.kind());
+                    return Next::Stop;
+                }
+            }
+        }
+    }
+}
+
+impl State {
+    /// ranges of [start, end) not received yet, which are marked as received
+    fn claim(&mut self, start: u64, end: u64) -> Vec<(u64, u64)> {
+        if start >= end {
+            return vec![];
+        }
+
+        let mut free = vec![];
+        let mut cur = start;
+        let from = match self.received.range(..=start).next_back() {
+            Some((&first, _)) => first,
+            None => start,
+        };
+        for (&first, &last) in self.received.range(from..end) {
+            if first > cur {
+                free.push((cur, first));
+            }
+            cur = cur.max(last);
+        }
+        if cur < end {
+            free.push((cur, end));
+        }
+
+        // merge the range with the ones it overlaps or touches
+        let (mut first, mut last) = (start, end);
+        let merged: Vec<u64> = self
+            .received
+            .range(..=end)
+            .rev()
+            .take_while(|(_, &e)| e >= start)
+            .map(|(&s, _)| s)
+            .collect();
+        for s in merged {
+            let e = self.received.remove(&s).unwrap_or(s);
+            first = first.min(s);
+            last = last.max(e);
+        }
+        self.received.insert(first, last);
+
+        free
+    }
+
+    /// keep data received until the writer takes it, spilled to disk if there is too much
+    /// in memory already
+    fn keep(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let chunk = if self.in_memory + data.len() <= self.max_memory {
+            self.in_memory += data.len();
+            Chunk::Memory(data.to_vec())
+        } else {
+            let spill = match self.spill.as_mut() {
+                Some(spill) => spill,
+                None => self.spill.insert(
+                    OpenOptions::new()
+                        .read(true)
+                        .write(true)
+                        .create(true)
+                        .truncate(true)
+                        .open(&self.spill_path)?,
+                ),
+            };
+            spill.seek(SeekFrom::Start(self.spill_len))?;
+            spill.write_all(data)?;
+            let chunk = Chunk::Spilled(self.spill_len, data.len());
+            self.spill_len += data.len() as u64;
+            chunk
+        };
+        self.pending.insert(offset, chunk);
+
+        Ok(())
+    }
+
+    /// data kept at the write position, which is moved past it
+    fn take(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let entry = match self.pending.first_entry() {
+            Some(entry) if *entry.key() == self.pos => entry,
+            _ => return Ok(None),
+        };
+        let data = match entry.remove() {
+            Chunk::Memory(data) => {
+                self.in_memory -= data.len();
+                data
+            }
+            Chunk::Spilled(offset, len) => {
+                let spill = self
+                    .spill
+                    .as_mut()
+                    .ok_or_else(|| io::Error::other("spill file is gone"))?;
+                let mut data = vec![0u8; len];
+                spill.seek(SeekFrom::Start(offset))?;
+                spill.read_exact(&mut data)?;
+                data
+            }
+        };
+        self.pos += data.len() as u64;
+
+        Ok(Some(data))
+    }
+}
+
+impl Drop for State {
+    fn drop(&mut self) {
+        if self.spill.take().is_some() {
+            let _ = fs::remove_file(&self.spill_path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{path::Path, sync::mpsc};
+
+    /// stream whose data stays readable by the test, each write waits for a go if gated
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>, Option<Arc<Mutex<mpsc::Receiver<()>>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if let Some(gate) = &self.1 {
+                let _ = gate.lock().unwrap().recv();
+            }
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn spill_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(name);
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_write_in_order() {
+        let out = Output::default();
+        let buf = ReorderBuffer::new(Box::new(out.clone()), 10, &spill_path("fget.r1"));
+
+        buf.write(4, b"4567").unwrap();
+        buf.write(8, b"89").unwrap();
+        assert_eq!(0, buf.written());
+
+        // the gap is filled: everything goes out, received bytes are not written twice
+        buf.write(0, b"012345").unwrap();
+        buf.write(2, b"23456789ab").unwrap();
+        assert_eq!(12, buf.finish().unwrap());
+        assert_eq!(12, buf.written());
+        assert_eq!(b"0123456789ab", &out.0.lock().unwrap()[..]);
+
+        // data before the end is missing
+        let buf = ReorderBuffer::new(Box::new(out), 10, &spill_path("fget.r3"));
+        buf.write(4, b"4567").unwrap();
+        assert!(buf.finish().is_err());
+    }
+
+    #[test]
+    fn test_spill_to_disk() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        let out = Output::default();
+        let path = spill_path("fget.r2");
+        let buf = ReorderBuffer::new(Box::new(out.clone()), 1000, &path);
+
+        // the end first, most of it does not fit in memory
+        for chunk in (1..10).rev() {
+            let offset = chunk * 1000;
+            buf.write(offset as u64, &data[offset..offset + 1000])
+                .unwrap();
+        }
+        assert_eq!(1000, buf.shared.lock().in_memory);
+        assert_eq!(8000, fs::metadata(&path).unwrap().len());
+
+        buf.write(0, &data[..1000]).unwrap();
+        assert_eq!(10_000, buf.finish().unwrap());
+        assert_eq!(data, *out.0.lock().unwrap());
+        drop(buf);
+        assert!(!Path::new(&path).exists());
+    }
+
+    #[test]
+    fn test_slow_stream() {
+        // parts keep their data while the stream is stuck, past the memory bound too
+        let (go, gate) = mpsc::channel();
+        let out = Output(Arc::default(), Some(Arc::new(Mutex::new(gate))));
+        let path = spill_path("fget.r4");
+        let buf = ReorderBuffer::new(Box::new(out.clone()), 4, &path);
+
+        buf.write(0, b"0123").unwrap();
+        buf.write(4, b"4567").unwrap();
+        buf.write(8, b"89").unwrap();
+        assert!(out.0.lock().unwrap().is_empty());
+        assert!(Path::new(&path).exists());
+
+        drop(go);
+        assert_eq!(10, buf.finish().unwrap());
+        assert_eq!(b"0123456789", &out.0.lock().unwrap()[..]);
+    }
+}
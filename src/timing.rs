@@ -0,0 +1,178 @@
+use std::time::Duration;
+
+use crate::httpx::{ConnAddr, Timings};
+use fget::statusln;
+
+/// Timing and transfer statistics of one connection (the probe request or a part)
+#[derive(Debug, Clone)]
+pub struct ConnStats {
+    pub label: String,
+    pub http_code: u16,
+    pub local_addr: ConnAddr,
+    pub remote_addr: ConnAddr,
+    pub timings: Timings,
+    pub transfer: Duration, // from first byte of response to end of body
+    pub size: u64,          // number of body bytes received
+}
+
+impl ConnStats {
+    pub fn total(&self) -> Duration {
+        let t = &self.timings;
+        t.dns + t.connect + t.tls + t.ttfb + self.transfer
+    }
+
+    /// value of a --write-out variable, names and (cumulative) times follow curl's
+    fn var(&self, name: &str) -> Option<String> {
+        let t = &self.timings;
+        let namelookup = t.dns;
+        let connect = namelookup + t.connect;
+        let appconnect = if t.tls.is_zero() {
+            Duration::ZERO
+        } else {
+            connect + t.tls
+        };
+        let starttransfer = connect + t.tls + t.ttfb;
+        let total = self.total();
+
+        let val = match name {
+            "label" => self.label.clone(),
+            "http_code" | "response_code" => self.http_code.to_string(),
+            "local_ip" => self.local_addr.host(),
+            "local_port" => self.local_addr.port().unwrap_or_default().to_string(),
+            "remote_ip" => self.remote_addr.host(),
+            "remote_port" => self.remote_addr.port().unwrap_or_default().to_string(),
+            "time_namelookup" => format_secs(namelookup),
+            "time_connect" => format_secs(connect),
+            "time_appconnect" => format_secs(appconnect),
+            "time_starttransfer" => format_secs(starttransfer),
+            "time_transfer" => format_secs(self.transfer),
+            "time_total" => format_secs(total),
+            "size_download" => self.size.to_string(),
+            "speed_download" => {
+                let secs = self.transfer.as_secs_f64();
+                let speed = if secs > 0.0 {
+                    self.size as f64 / secs
+                } else {
+                    0.0
+                };
+                format!("{:.0}", speed)
+            }
+            _ => return None,
+        };
+
+        Some(val)
+    }
+}
+
+fn format_secs(dur: Duration) -> String {
+    format!("{:.6}", dur.as_secs_f64())
+}
+
+/// Expand a curl style --write-out template: %{variable} is replaced by its value,
+/// `%%` is a literal `%` and \n, \r, \t are the usual escapes
+pub fn write_out(template: &str, stats: &ConnStats) -> String {
+    let mut out = String::new();
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('%', Some('%')) => {
+                chars.next();
+                out.push('%');
+            }
+            ('%', Some('{')) => {
+                chars.next();
+                let name: String = chars.by_ref().take_while(|c| *c != '}').collect();
+                match stats.var(&name) {
+                    Some(val) => out += &val,
+                    None => out += &format!("%{{{}}}", name), // keep unknown variables as is
+                }
+            }
+            ('\\', Some(&e @ ('n' | 'r' | 't' | '\\'))) => {
+                chars.next();
+                out.push(match e {
+                    'n' => '\n',
+                    'r' => '\r',
+                    't' => '\t',
+                    _ => '\\',
+                });
+            }
+            _ => out.push(c),
+        }
+    }
+
+    out
+}
+
+/// Print timing breakdown of all connections as a table
+pub fn print_summary(stats: &[ConnStats]) {
+    statusln!("Timing breakdown (seconds):");
+    statusln!(
+        "{:<10} {:>9} {:>9} {:>9} {:>9} {:>9} {:>9} {:>12}",
+        "conn",
+        "dns",
+        "connect",
+        "tls",
+        "ttfb",
+        "transfer",
+        "total",
+        "speed (B/s)"
+    );
+    for s in stats {
+        let t = &s.timings;
+        statusln!(
+            "{:<10} {:>9.3} {:>9.3} {:>9.3} {:>9.3} {:>9.3} {:>9.3} {:>12}",
+            s.label,
+            t.dns.as_secs_f64(),
+            t.connect.as_secs_f64(),
+            t.tls.as_secs_f64(),
+            t.ttfb.as_secs_f64(),
+            s.transfer.as_secs_f64(),
+            s.total().as_secs_f64(),
+            s.var("speed_download").unwrap_or_default()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_out() {
+        let stats = ConnStats {
+            label: "part 1".to_string(),
+            http_code: 206,
+            local_addr: ConnAddr::Inet("10.0.0.2:40000".parse().unwrap()),
+            remote_addr: ConnAddr::Inet("1.2.3.4:443".parse().unwrap()),
+            timings: Timings {
+                dns: Duration::from_millis(10),
+                connect: Duration::from_millis(20),
+                tls: Duration::from_millis(30),
+                ttfb: Duration::from_millis(40),
+            },
+            transfer: Duration::from_secs(2),
+            size: 1000,
+        };
+
+        assert_eq!(
+            "part 1 206 10.0.0.2 1.2.3.4:443\n",
+            write_out(
+                "%{label} %{http_code} %{local_ip} %{remote_ip}:%{remote_port}\\n",
+                &stats
+            )
+        );
+        assert_eq!(
+            "0.010000 0.030000 0.060000 0.100000 2.100000",
+            write_out(
+                "%{time_namelookup} %{time_connect} %{time_appconnect} \
+                 %{time_starttransfer} %{time_total}",
+                &stats
+            )
+        );
+        assert_eq!(
+            "500 100% %{unknown}",
+            write_out("%{speed_download} 100%% %{unknown}", &stats)
+        );
+    }
+}
@@ -0,0 +1,388 @@
+use std::{
+    borrow::Cow,
+    fs::File,
+    io::{BufWriter, Read, Write},
+    mem,
+    sync::{Mutex, OnceLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use fget::{httpparse::ResponseHead, PError};
+
+use crate::{httpx::ReadWrite, Config};
+
+/// headers whose values are hidden from debug output and trace files unless --no-redact
+const SENSITIVE_HEADERS: [&str; 4] = [
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+];
+
+static TRACER: OnceLock<Tracer> = OnceLock::new();
+
+/// like eprintln but ignore errors, debug output must never break (or panic) a download
+macro_rules! debug_print {
+    ($($arg:tt)*) => {{
+        let _ = writeln!(std::io::stderr(), $($arg)*);
+    }};
+}
+
+#[derive(Debug, Clone, Copy)]
+enum TraceFormat {
+    Hex,   // like curl --trace
+    Ascii, // like curl --trace-ascii
+}
+
+struct Tracer {
+    debug: bool,
+    redact: bool,
+    file: Option<(Mutex<BufWriter<File>>, TraceFormat)>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Direction {
+    Send,
+    Recv,
+}
+
+/// Setup tracing from config, must be called once before any connection is opened
+pub fn init(cfg: &Config) -> Result<(), PError> {
+    let file = match (&cfg.trace, &cfg.trace_ascii) {
+        (Some(path), _) => Some((path, TraceFormat::Hex)),
+        (None, Some(path)) => Some((path, TraceFormat::Ascii)),
+        (None, None) => None,
+    };
+    let file = match file {
+        Some((path, format)) => Some((Mutex::new(BufWriter::new(File::create(path)?)), format)),
+        None => None,
+    };
+
+    let _ = TRACER.set(Tracer {
+        debug: cfg.debug,
+        redact: !cfg.no_redact,
+        file,
+    });
+
+    Ok(())
+}
+
+/// print request head to stderr if debug is enabled
+pub fn debug_request(label: &str, head: &str) {
+    if let Some(tracer) = TRACER.get().filter(|t| t.debug) {
+        for line in head.lines().filter(|line| !line.is_empty()) {
+            debug_print!("{}> {}", format_label(label), tracer.redact_line(line));
+        }
+    }
+}
+
+/// print response head to stderr if debug is enabled
+pub fn debug_response(label: &str, head: &ResponseHead) {
+    if let Some(tracer) = TRACER.get().filter(|t| t.debug) {
+        let label = format_label(label);
+        debug_print!(
+            "{}< {:?} {} {}",
+            label,
+            head.version,
+            head.status.as_u16(),
+            head.reason
+        );
+        for (key, val) in head.headers.iter() {
+            debug_print!(
+                "{}< {}",
+                label,
+                tracer.redact_line(&format!("{}: {}", key, val))
+            );
+        }
+    }
+}
+
+/// print informational message about a connection to stderr if debug is enabled
+pub fn debug_info(label: &str, msg: &str) {
+    if TRACER.get().filter(|t| t.debug).is_some() {
+        debug_print!("{}* {}", format_label(label), msg);
+    }
+}
+
+/// wrap stream to dump every byte sent and received to the trace file (if any)
+pub fn wrap(label: &str, stream: Box<dyn ReadWrite>) -> Box<dyn ReadWrite> {
+    match TRACER.get() {
+        Some(Tracer { file: Some(_), .. }) => Box::new(TracingStream {
+            inner: stream,
+            label: label.to_string(),
+            sent: Redactor::default(),
+            received: Redactor::default(),
+        }),
+        _ => stream,
+    }
+}
+
+impl Tracer {
+    fn redact_line<'a>(&self, line: &'a str) -> Cow<'a, str> {
+        if self.redact {
+            redact_line(line)
+        } else {
+            Cow::Borrowed(line)
+        }
+    }
+
+    fn record(&self, label: &str, dir: Direction, data: &[u8], redactor: &mut Redactor) {
+        let (file, format) = match &self.file {
+            Some(file) => file,
+            None => return,
+        };
+
+        let len = data.len();
+        let data = if self.redact {
+            Cow::Owned(redactor.redact(data))
+        } else {
+            Cow::Borrowed(data)
+        };
+        let what = match dir {
+            Direction::Send => "=> Send data",
+            Direction::Recv => "<= Recv data",
+        };
+
+        let mut out = format!(
+            "{} {}{}, {} bytes (0x{:x})\n",
+            format_timestamp(SystemTime::now()),
+            format_label(label),
+            what,
+            len,
+            len
+        );
+        match format {
+            TraceFormat::Hex => hex_dump(&data, &mut out),
+            TraceFormat::Ascii => ascii_dump(&data, &mut out),
+        }
+
+        // tracing is best effort, it must never break a download
+        if let Ok(mut w) = file.lock() {
+            let _ = w.write_all(out.as_bytes()).and_then(|_| w.flush());
+        }
+    }
+}
+
+struct TracingStream {
+    inner: Box<dyn ReadWrite>,
+    label: String,
+    sent: Redactor,
+    received: Redactor,
+}
+
+impl Read for TracingStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(tracer) = TRACER.get() {
+            tracer.record(&self.label, Direction::Recv, &buf[..n], &mut self.received);
+        }
+
+        Ok(n)
+    }
+}
+
+impl Write for TracingStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if let Some(tracer) = TRACER.get() {
+            tracer.record(&self.label, Direction::Send, &buf[..n], &mut self.sent);
+        }
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn format_label(label: &str) -> String {
+    if label.is_empty() {
+        String::new()
+    } else {
+        format!("[{}] ", label)
+    }
+}
+
+/// format time of day (UTC) as HH:MM:SS.micros
+fn format_timestamp(time: SystemTime) -> String {
+    let dur = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = dur.as_secs() % 86400;
+    format!(
+        "{:02}:{:02}:{:02}.{:06}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        dur.subsec_micros()
+    )
+}
+
+fn hex_dump(data: &[u8], out: &mut String) {
+    for (i, row) in data.chunks(16).enumerate() {
+        *out += &format!("{:04x}: ", i * 16);
+        for col in 0..16 {
+            match row.get(col) {
+                Some(b) => *out += &format!("{:02x} ", b),
+                None => *out += "   ",
+            }
+        }
+        out.extend(row.iter().map(|b| printable(*b)));
+        out.push('\n');
+    }
+}
+
+fn ascii_dump(data: &[u8], out: &mut String) {
+    let mut offset = 0;
+    for line in data.split_inclusive(|b| *b == b'\n') {
+        *out += &format!("{:04x}: ", offset);
+        out.extend(
+            line.iter()
+                .filter(|b| **b != b'\r' && **b != b'\n')
+                .map(|b| printable(*b)),
+        );
+        out.push('\n');
+        offset += line.len();
+    }
+}
+
+fn printable(b: u8) -> char {
+    if b.is_ascii_graphic() || b == b' ' {
+        b as char
+    } else {
+        '.'
+    }
+}
+
+/// hide value of a sensitive header line, other lines are returned as is
+fn redact_line(line: &str) -> Cow<'_, str> {
+    match line.split_once(':') {
+        Some((key, _)) if SENSITIVE_HEADERS.contains(&key.trim().to_lowercase().as_str()) => {
+            Cow::Owned(format!("{}: <redacted>", key))
+        }
+        _ => Cow::Borrowed(line),
+    }
+}
+
+/// Hides values of sensitive header lines in raw data of a stream, which may be cut
+/// anywhere: the start of a line which could be a sensitive header is held back until
+/// it is known, the rest of a sensitive line is dropped until its end
+#[derive(Default)]
+struct Redactor {
+    partial: Vec<u8>, // start of the current line, held back
+    mid_line: bool,   // data ended in the middle of a line which is not sensitive
+    hiding: bool,     // data ended in the middle of a sensitive line
+    cr: bool,         // last byte hidden was a carriage return
+}
+
+impl Redactor {
+    fn redact(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len());
+        for seg in data.split_inclusive(|b| *b == b'\n') {
+            let complete = seg.ends_with(b"\n");
+            if self.hiding {
+                if complete {
+                    let crlf = seg.ends_with(b"\r\n") || (seg.len() == 1 && self.cr);
+                    out.extend_from_slice(if crlf { b"\r\n" } else { b"\n" });
+                    self.hiding = false;
+                }
+                self.cr = seg.ends_with(b"\r");
+                continue;
+            }
+            if mem::take(&mut self.mid_line) {
+                out.extend_from_slice(seg);
+                self.mid_line = !complete;
+                continue;
+            }
+
+            let mut line = mem::take(&mut self.partial);
+            line.extend_from_slice(seg);
+            match sensitive_name_len(&line) {
+                Some(pos) => {
+                    out.extend_from_slice(&line[..pos]);
+                    out.extend_from_slice(b": <redacted>");
+                    if line.ends_with(b"\r\n") {
+                        out.extend_from_slice(b"\r\n");
+                    } else if complete {
+                        out.push(b'\n');
+                    } else {
+                        self.hiding = true;
+                        self.cr = line.ends_with(b"\r");
+                    }
+                }
+                None if !complete && may_be_sensitive(&line) => self.partial = line,
+                None => {
+                    out.extend_from_slice(&line);
+                    self.mid_line = !complete;
+                }
+            }
+        }
+
+        out
+    }
+}
+
+/// length of the name of a sensitive header the line starts with
+fn sensitive_name_len(line: &[u8]) -> Option<usize> {
+    SENSITIVE_HEADERS
+        .iter()
+        .find(|h| {
+            line.len() > h.len()
+                && line[h.len()] == b':'
+                && line[..h.len()].eq_ignore_ascii_case(h.as_bytes())
+        })
+        .map(|h| h.len())
+}
+
+/// whether the start of a line may be the start of a sensitive header
+fn may_be_sensitive(line: &[u8]) -> bool {
+    SENSITIVE_HEADERS
+        .iter()
+        .any(|h| line.len() <= h.len() && h.as_bytes()[..line.len()].eq_ignore_ascii_case(line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(
+            "Authorization: <redacted>",
+            redact_line("Authorization: Bearer abc")
+        );
+        assert_eq!("set-cookie: <redacted>", redact_line("set-cookie: id=1"));
+        assert_eq!("Accept: */*", redact_line("Accept: */*"));
+
+        let data = b"GET / HTTP/1.1\r\nCookie: a=b\r\nHost: x\r\n\r\n";
+        let expected = b"GET / HTTP/1.1\r\nCookie: <redacted>\r\nHost: x\r\n\r\n";
+        assert_eq!(&expected[..], &Redactor::default().redact(data)[..]);
+
+        // data cut anywhere, even inside a header name or its value
+        for size in 1..data.len() {
+            let mut redactor = Redactor::default();
+            let out: Vec<u8> = data.chunks(size).flat_map(|c| redactor.redact(c)).collect();
+            assert_eq!(&expected[..], &out[..], "chunks of {} bytes", size);
+        }
+
+        // a line is held back only until it cannot be a sensitive header
+        let mut redactor = Redactor::default();
+        assert_eq!(b"A: b\r\n".to_vec(), redactor.redact(b"A: b\r\nAuth"));
+        assert_eq!(b"Author: x\r\nab".to_vec(), redactor.redact(b"or: x\r\nab"));
+        assert_eq!(b"cookie: 1".to_vec(), redactor.redact(b"cookie: 1"));
+    }
+
+    #[test]
+    fn test_dump() {
+        let mut out = String::new();
+        hex_dump(b"GET / HTTP/1.1\r\n\r\n", &mut out);
+        assert_eq!(
+            "0000: 47 45 54 20 2f 20 48 54 54 50 2f 31 2e 31 0d 0a GET / HTTP/1.1..\n\
+             0010: 0d 0a                                           ..\n",
+            out
+        );
+
+        let mut out = String::new();
+        ascii_dump(b"HTTP/1.1 200 OK\r\nA: b\r\n", &mut out);
+        assert_eq!("0000: HTTP/1.1 200 OK\n0011: A: b\n", out);
+    }
+}
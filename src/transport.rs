@@ -0,0 +1,236 @@
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
+
+use crate::{make_error, PError};
+
+pub trait ReadWrite: Read + Write + Send {}
+
+impl<T: Read + Write + Send> ReadWrite for T {}
+
+/// Time spent in each phase of a request, attached to every response as an extension
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timings {
+    pub dns: Duration,
+    pub connect: Duration,
+    pub tls: Duration,  // zero if connection is not tls
+    pub ttfb: Duration, // from request sent to first byte of response received
+}
+
+/// Address of one end of a connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnAddr {
+    Inet(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl ConnAddr {
+    /// ip address, or socket path in case of unix socket
+    pub fn host(&self) -> String {
+        match self {
+            ConnAddr::Inet(addr) => addr.ip().to_string(),
+            ConnAddr::Unix(path) => path.display().to_string(),
+        }
+    }
+
+    pub fn port(&self) -> Option<u16> {
+        match self {
+            ConnAddr::Inet(addr) => Some(addr.port()),
+            ConnAddr::Unix(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for ConnAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConnAddr::Inet(addr) => write!(f, "{}", addr),
+            ConnAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Settings of the connections a transport opens
+#[derive(Debug, Clone, Default)]
+pub struct ConnConfig {
+    pub connect_timeout: Duration,
+    pub read_timeout: Duration,    // of every read and write
+    pub bind_addr: Option<IpAddr>, // local address to bind to
+    pub interface: Option<String>, // network interface to bind to
+}
+
+/// An opened connection
+pub struct Conn {
+    pub rw: Box<dyn ReadWrite>,
+    pub local_addr: ConnAddr,
+    pub remote_addr: ConnAddr,
+    pub timings: Timings, // dns, connect and tls only, ttfb is measured by the client
+}
+
+/// Stream of a non-blocking connection used by the event loop engine
+pub enum NbStream {
+    /// os socket, the engine waits for its readiness
+    Tcp(mio::net::TcpStream),
+    #[cfg(unix)]
+    Unix(mio::net::UnixStream),
+    /// stream that never blocks (e.g. in-memory), driven on every turn of the event loop
+    Ready(Box<dyn ReadWrite + Send>),
+}
+
+impl NbStream {
+    /// event source to register to the event loop, None if the stream is always ready
+    pub fn source(&mut self) -> Option<&mut dyn mio::event::Source> {
+        match self {
+            NbStream::Tcp(stream) => Some(stream),
+            #[cfg(unix)]
+            NbStream::Unix(stream) => Some(stream),
+            NbStream::Ready(_) => None,
+        }
+    }
+
+    /// check whether a connection in progress is established, an error means it failed
+    pub fn is_connected(&self) -> io::Result<bool> {
+        let res = match self {
+            NbStream::Tcp(stream) => match stream.take_error()? {
+                Some(err) => return Err(err),
+                None => stream.peer_addr().map(|_| ()),
+            },
+            #[cfg(unix)]
+            NbStream::Unix(stream) => match stream.take_error()? {
+                Some(err) => return Err(err),
+                None => stream.peer_addr().map(|_| ()),
+            },
+            NbStream::Ready(_) => Ok(()),
+        };
+
+        match res {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == io::ErrorKind::NotConnected => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl Read for NbStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            NbStream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            NbStream::Unix(stream) => stream.read(buf),
+            NbStream::Ready(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for NbStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            NbStream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            NbStream::Unix(stream) => stream.write(buf),
+            NbStream::Ready(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            NbStream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            NbStream::Unix(stream) => stream.flush(),
+            NbStream::Ready(stream) => stream.flush(),
+        }
+    }
+}
+
+impl fmt::Debug for NbStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NbStream::Tcp(stream) => write!(f, "{:?}", stream),
+            #[cfg(unix)]
+            NbStream::Unix(stream) => write!(f, "{:?}", stream),
+            NbStream::Ready(_) => write!(f, "NbStream::Ready"),
+        }
+    }
+}
+
+/// A non-blocking connection, connecting may still be in progress
+pub struct NbConn {
+    pub stream: NbStream,
+    pub local_addr: ConnAddr,
+    pub remote_addr: ConnAddr,
+    pub timings: Timings, // dns only, the rest is measured by the engine
+}
+
+/// Opens connections for http clients, implement it to plug in custom sockets, proxies
+/// or fake servers
+pub trait Transport: fmt::Debug + Send + Sync {
+    /// open a connection to host_addr (host:port), doing the tls handshake for domain if tls
+    fn connect(
+        &self,
+        host_addr: &str,
+        domain: &str,
+        tls: bool,
+        cfg: &ConnConfig,
+    ) -> Result<Conn, PError>;
+
+    /// start opening a non-blocking connection to host_addr for the event loop engine,
+    /// the tls handshake (if any) is left to the engine
+    fn connect_nonblocking(&self, _host_addr: &str, _cfg: &ConnConfig) -> Result<NbConn, PError> {
+        Err(make_error(
+            "transport does not support non-blocking connections",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// transport of a user of the library: connections to an in-memory response
+    #[derive(Debug)]
+    struct Canned(&'static [u8]);
+
+    impl Transport for Canned {
+        fn connect(
+            &self,
+            _host_addr: &str,
+            _domain: &str,
+            _tls: bool,
+            _cfg: &ConnConfig,
+        ) -> Result<Conn, PError> {
+            let addr = ConnAddr::Unix(PathBuf::from("canned"));
+            Ok(Conn {
+                rw: Box::new(Cursor::new(self.0.to_vec())),
+                local_addr: addr.clone(),
+                remote_addr: addr,
+                timings: Timings::default(),
+            })
+        }
+    }
+
+    #[test]
+    fn test_custom_transport() {
+        let transport: Box<dyn Transport> = Box::new(Canned(b"HTTP/1.1 204 No Content\r\n\r\n"));
+        let cfg = ConnConfig::default();
+
+        let mut conn = transport
+            .connect("a.test:80", "a.test", false, &cfg)
+            .unwrap();
+        let mut head = String::new();
+        conn.rw.read_to_string(&mut head).unwrap();
+        assert!(head.starts_with("HTTP/1.1 204"));
+        assert_eq!("unix:canned", conn.remote_addr.to_string());
+        assert_eq!(None, conn.remote_addr.port());
+
+        // not supported unless implemented
+        assert!(transport.connect_nonblocking("a.test:80", &cfg).is_err());
+        let mut stream = NbStream::Ready(Box::new(Cursor::new(vec![])));
+        assert!(stream.source().is_none());
+        assert!(stream.is_connected().unwrap());
+    }
+}
@@ -16,7 +16,7 @@ impl UrlInfo {
             return Err(make_error("Invalid URL"));
         }
 
-        let scheme = parse_and_validate_scheme(&parts[0])?;
+        let scheme = parse_and_validate_scheme(parts[0])?;
         let (host, port) = parse_host_and_port(parts[2], scheme)?;
         let query_idx = parts[0].len() + parts[1].len() + parts[2].len() + 2;
 
@@ -66,6 +66,7 @@ fn parse_and_validate_scheme(scheme: &str) -> Result<&str, PError> {
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
 